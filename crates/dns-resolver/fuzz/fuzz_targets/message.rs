@@ -0,0 +1,16 @@
+//! Feed arbitrary bytes to the message decoder, which must never panic
+#![no_main]
+
+use dns_resolver::dns::{AsBytes, FromBytes, Message};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = Message::from_bytes(data) {
+        // Displaying the message walks all of the decoded data
+        let _ = message.to_string();
+        // Whatever we decoded has to survive being encoded and decoded again
+        let encoded = message.as_bytes();
+        let decoded = Message::from_bytes(&encoded).expect("re-encoded message should decode");
+        assert_eq!(*decoded, *message);
+    }
+});
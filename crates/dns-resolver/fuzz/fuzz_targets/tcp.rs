@@ -0,0 +1,10 @@
+//! Feed arbitrary bytes to the TCP framing and message decoder, which must
+//! never panic
+#![no_main]
+
+use dns_resolver::transport::decode_tcp;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_tcp(data);
+});
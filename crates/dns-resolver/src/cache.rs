@@ -0,0 +1,586 @@
+//! A cache of responses, so that repeated lookups don't go over Tor again
+//!
+//! Responses are kept for the smallest TTL of their answers, and are handed
+//! back with their TTLs counted down. Negative responses, NXDOMAIN or NOERROR
+//! without any answer, are kept for the TTL of the SOA record in their
+//! authority section, capped by its MINIMUM field, as described in RFC 2308
+//! section 5. Negative responses without an SOA record aren't cached.
+//!
+//! Entries are looked up by name, type and class, ignoring the case of the
+//! name, and by the DO and CD bits of the query: a response to a query with
+//! DO may carry DNSSEC records the others didn't ask for, and one to a query
+//! with CD may hold data which failed validation. Once the cache is full,
+//! entries which can no longer be used are dropped first, then the ones
+//! closest to expiring. Entries are indexed by when they expire, so that
+//! finding those doesn't go through the whole cache.
+//!
+//! With serve-stale enabled, as described in RFC 8767, expired entries are
+//! kept for a while longer, and used when no server answers.
+use crate::dns::{Message, Name, RData, Rcode, RecordType};
+use crate::upstream::Answer;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Number of entries kept by default
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Longest a response is kept, whatever its TTL, see RFC 8767 section 4
+pub const MAX_TTL: u32 = 7 * 24 * 60 * 60;
+
+/// How long expired entries are kept by default when serve-stale is enabled,
+/// within the 1 to 3 days RFC 8767 section 5 suggests
+pub const DEFAULT_STALE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// TTL of the records of stale responses, see RFC 8767 section 4
+pub const STALE_TTL: u32 = 30;
+
+/// What an entry is looked up with
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    /// Name asked about, in lower case
+    name: Name,
+    /// Type of the records asked for
+    rtype: RecordType,
+    /// Class of the records asked for
+    class: u16,
+    /// Whether DNSSEC records were asked for, with the DO bit
+    dnssec_ok: bool,
+    /// Whether DNSSEC checking was disabled, with the CD bit
+    checking_disabled: bool,
+}
+
+impl Key {
+    /// The key of `query`, if it asks a single question
+    fn new(query: &Message) -> Option<Self> {
+        match query.questions.as_slice() {
+            [question] => Some(Key {
+                name: question.qname.to_lowercase(),
+                rtype: question.qtype,
+                class: question.qclass,
+                dnssec_ok: query.edns().is_some_and(|edns| edns.dnssec_ok()),
+                checking_disabled: query.flags.cd,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A cached response
+#[derive(Debug)]
+struct Entry {
+    /// The response, along with the server which gave it
+    answer: Answer,
+    /// When the response was received
+    stored: Instant,
+    /// When the response expires
+    expires: Instant,
+    /// Number of the insertion which stored it, which tells apart entries
+    /// expiring at the same time in [Entries::expiry]
+    insertion: u64,
+}
+
+impl Entry {
+    /// The response, as it would be received at `now`, with its TTLs counted
+    /// down, or all set to `stale_ttl` if given
+    fn answer(&self, query: &Message, now: Instant, stale_ttl: Option<u32>) -> Answer {
+        let elapsed = now.saturating_duration_since(self.stored).as_secs();
+        let mut answer = self.answer.clone();
+        answer.rtt = Duration::ZERO;
+        answer.cached = true;
+        let message = &mut answer.message;
+        message.id = query.id;
+        message.questions = query.questions.clone();
+        // The OPT record was meant for whoever asked first, and clients
+        // which didn't send one must not get one back, see RFC 6891 section 7
+        if query.edns().is_none() {
+            message
+                .additional
+                .retain(|record| record.rtype != RecordType::Opt);
+        }
+        let records = message
+            .answers
+            .iter_mut()
+            .chain(message.authority.iter_mut())
+            .chain(message.additional.iter_mut());
+        // The TTL field of OPT records holds flags
+        for record in records.filter(|record| record.rtype != RecordType::Opt) {
+            record.ttl = match stale_ttl {
+                Some(ttl) => ttl,
+                None => record.ttl.saturating_sub(elapsed as u32),
+            };
+        }
+        answer
+    }
+}
+
+/// The cached responses, along with the order they expire in
+#[derive(Debug, Default)]
+struct Entries {
+    /// The responses
+    responses: HashMap<Key, Entry>,
+    /// The key of every response, by when it expires and its insertion
+    expiry: BTreeMap<(Instant, u64), Key>,
+    /// Number of insertions so far
+    insertions: u64,
+}
+
+impl Entries {
+    /// Keep `answer` for `key`, replacing whatever was there
+    fn insert(&mut self, key: Key, answer: Answer, stored: Instant, expires: Instant) {
+        let insertion = self.insertions;
+        self.insertions += 1;
+        self.expiry.insert((expires, insertion), key.clone());
+        let entry = Entry {
+            answer,
+            stored,
+            expires,
+            insertion,
+        };
+        if let Some(old) = self.responses.insert(key, entry) {
+            self.expiry.remove(&(old.expires, old.insertion));
+        }
+    }
+
+    /// When the entry closest to expiring expires
+    fn first_expiry(&self) -> Option<Instant> {
+        self.expiry.keys().next().map(|(expires, _)| *expires)
+    }
+
+    /// Drop the entry closest to expiring
+    fn remove_first(&mut self) -> Option<Key> {
+        let (_, key) = self.expiry.pop_first()?;
+        self.responses.remove(&key);
+        Some(key)
+    }
+}
+
+/// How long `response` can be cached, if at all
+fn ttl(response: &Message) -> Option<u32> {
+    if response.flags.tc {
+        return None;
+    }
+    let ttl = match response.rcode() {
+        Rcode::NoError if !response.answers.is_empty() => {
+            response.answers.iter().map(|record| record.ttl).min()?
+        }
+        Rcode::NoError | Rcode::NxDomain => {
+            response
+                .authority
+                .iter()
+                .find_map(|record| match record.rdata {
+                    RData::Soa { minimum, .. } => Some(record.ttl.min(minimum)),
+                    _ => None,
+                })?
+        }
+        _ => return None,
+    };
+    match ttl {
+        0 => None,
+        ttl => Some(ttl.min(MAX_TTL)),
+    }
+}
+
+/// Hit and miss counts of a [Cache]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Lookups which had to go to a server
+    pub misses: u64,
+    /// Lookups answered with an expired entry, as no server answered
+    pub stale: u64,
+    /// Number of entries in the cache
+    pub entries: usize,
+}
+
+impl fmt::Display for CacheStats {
+    /// Show the counts in a line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} stale, {} entries",
+            self.hits, self.misses, self.stale, self.entries
+        )
+    }
+}
+
+/// A cache of responses, see the [module level documentation](self)
+#[derive(Debug)]
+pub struct Cache {
+    /// The cached responses
+    entries: Mutex<Entries>,
+    /// Largest number of entries
+    capacity: usize,
+    /// How long expired entries are kept for serve-stale, if enabled
+    stale_window: Option<Duration>,
+    /// Lookups answered from the cache
+    hits: AtomicU64,
+    /// Lookups which had to go to a server
+    misses: AtomicU64,
+    /// Lookups answered with an expired entry
+    stale: AtomicU64,
+}
+
+impl Cache {
+    /// Create an empty cache holding up to `capacity` responses
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            capacity,
+            stale_window: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep expired entries for `window`, to answer with them when no server
+    /// answers, or don't if `None`
+    pub fn set_serve_stale(&mut self, window: Option<Duration>) {
+        self.stale_window = window;
+    }
+
+    /// The hit and miss counts so far
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            entries: self.entries.lock().expect("poisoned lock").responses.len(),
+        }
+    }
+
+    /// When an entry expiring at `expires` can no longer be used at all
+    fn deadline(&self, expires: Instant) -> Instant {
+        expires + self.stale_window.unwrap_or_default()
+    }
+
+    /// The cached response to `query`, unless it expired
+    pub fn get(&self, query: &Message) -> Option<Answer> {
+        self.get_at(query, Instant::now())
+    }
+
+    /// The cached response to `query`, as of `now`
+    fn get_at(&self, query: &Message, now: Instant) -> Option<Answer> {
+        let answer = Key::new(query).and_then(|key| {
+            let entries = self.entries.lock().expect("poisoned lock");
+            let entry = entries
+                .responses
+                .get(&key)
+                .filter(|entry| entry.expires > now)?;
+            Some(entry.answer(query, now, None))
+        });
+        let counter = match answer {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        answer
+    }
+
+    /// The cached response to `query`, even if it expired, as long as
+    /// serve-stale is enabled and it is within the window
+    pub fn get_stale(&self, query: &Message) -> Option<Answer> {
+        self.get_stale_at(query, Instant::now())
+    }
+
+    /// The possibly expired response to `query`, as of `now`
+    fn get_stale_at(&self, query: &Message, now: Instant) -> Option<Answer> {
+        self.stale_window?;
+        let key = Key::new(query)?;
+        let entries = self.entries.lock().expect("poisoned lock");
+        let entry = entries
+            .responses
+            .get(&key)
+            .filter(|entry| self.deadline(entry.expires) > now)?;
+        self.stale.fetch_add(1, Ordering::Relaxed);
+        let stale_ttl = (entry.expires <= now).then_some(STALE_TTL);
+        Some(entry.answer(query, now, stale_ttl))
+    }
+
+    /// Keep `answer` to `query`, if it can be cached
+    pub fn insert(&self, query: &Message, answer: &Answer) {
+        self.insert_at(query, answer, Instant::now());
+    }
+
+    /// Keep `answer` to `query`, received at `now`
+    fn insert_at(&self, query: &Message, answer: &Answer, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let (Some(key), Some(ttl)) = (Key::new(query), ttl(&answer.message)) else {
+            return;
+        };
+        let mut entries = self.entries.lock().expect("poisoned lock");
+        if entries.responses.len() >= self.capacity && !entries.responses.contains_key(&key) {
+            // Entries which can no longer be used come first, as they are
+            // the first to expire
+            while entries
+                .first_expiry()
+                .is_some_and(|expires| self.deadline(expires) <= now)
+            {
+                entries.remove_first();
+            }
+            if entries.responses.len() >= self.capacity {
+                if let Some(oldest) = entries.remove_first() {
+                    debug!("Cache is full, dropping {}", oldest.name);
+                }
+            }
+        }
+        let expires = now + Duration::from_secs(ttl.into());
+        entries.insert(key, answer.clone(), now, expires);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, ResourceRecord};
+    use std::net::Ipv4Addr;
+
+    /// A record of `name`, with the TTL and data given
+    fn record(name: &str, ttl: u32, rdata: RData) -> ResourceRecord {
+        let rtype = match rdata {
+            RData::Soa { .. } => RecordType::Soa,
+            _ => RecordType::A,
+        };
+        ResourceRecord {
+            name: name.parse().unwrap(),
+            rtype,
+            class: 1,
+            ttl,
+            rdata,
+        }
+    }
+
+    /// An answer to `query` with `answers`, as sent by `upstream.test`
+    fn answer(query: &Message, answers: Vec<ResourceRecord>) -> Answer {
+        let mut message = query.clone();
+        message.flags.qr = true;
+        message.answers = answers;
+        Answer {
+            server: "upstream.test".parse().unwrap(),
+            message,
+            rtt: Duration::from_millis(100),
+            cached: false,
+        }
+    }
+
+    /// An address record with `ttl`
+    fn address(ttl: u32) -> ResourceRecord {
+        record("example.com", ttl, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+    }
+
+    /// A negative answer to `query`, with an SOA record
+    fn negative(query: &Message, ttl: u32, minimum: u32) -> Answer {
+        let mut answer = answer(query, Vec::new());
+        answer.message.flags.rcode = Rcode::NxDomain;
+        answer.message.authority.push(record(
+            "com",
+            ttl,
+            RData::Soa {
+                mname: "a.gtld-servers.net".parse().unwrap(),
+                rname: "nstld.verisign-grs.com".parse().unwrap(),
+                serial: 1,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum,
+            },
+        ));
+        answer
+    }
+
+    #[test]
+    fn responses_are_kept_for_their_ttl() {
+        let cache = Cache::new(DEFAULT_CAPACITY);
+        let now = Instant::now();
+        let query = build_query("example.com", RecordType::A).unwrap();
+        cache.insert_at(
+            &query,
+            &answer(&query, vec![address(300), address(60)]),
+            now,
+        );
+        let mut other = build_query("EXAMPLE.com", RecordType::A).unwrap();
+        other.id = query.id.wrapping_add(1);
+        let hit = cache.get_at(&other, now + Duration::from_secs(20)).unwrap();
+        assert!(hit.cached);
+        assert_eq!(hit.message.id, other.id);
+        assert_eq!(hit.message.questions, other.questions);
+        let ttls: Vec<_> = hit.message.answers.iter().map(|r| r.ttl).collect();
+        assert_eq!(ttls, [280, 40]);
+        assert!(cache
+            .get_at(&query, now + Duration::from_secs(60))
+            .is_none());
+        let aaaa = build_query("example.com", RecordType::Aaaa).unwrap();
+        assert!(cache.get_at(&aaaa, now).is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                stale: 0,
+                entries: 1
+            }
+        );
+    }
+
+    #[test]
+    fn opt_records_only_go_to_clients_which_sent_one() {
+        let cache = Cache::new(DEFAULT_CAPACITY);
+        let now = Instant::now();
+        let opt = ResourceRecord {
+            name: Name::default(),
+            rtype: RecordType::Opt,
+            class: 1232,
+            ttl: 0,
+            rdata: RData::Opt(Vec::new()),
+        };
+        let mut query = build_query("example.com", RecordType::A).unwrap();
+        query.additional.push(opt.clone());
+        let mut response = answer(&query, vec![address(300)]);
+        response.message.additional = vec![opt.clone()];
+        cache.insert_at(&query, &response, now);
+        let hit = cache.get_at(&query, now).unwrap();
+        assert_eq!(hit.message.additional, [opt]);
+        let plain = build_query("example.com", RecordType::A).unwrap();
+        let hit = cache.get_at(&plain, now).unwrap();
+        assert!(hit.message.additional.is_empty());
+        assert_eq!(hit.message.answers.len(), 1);
+    }
+
+    #[test]
+    fn negative_responses_use_the_soa_minimum() {
+        let cache = Cache::new(DEFAULT_CAPACITY);
+        let now = Instant::now();
+        let query = build_query("missing.com", RecordType::A).unwrap();
+        cache.insert_at(&query, &negative(&query, 900, 300), now);
+        let hit = cache.get_at(&query, now + Duration::from_secs(299));
+        assert_eq!(hit.unwrap().message.rcode(), Rcode::NxDomain);
+        assert!(cache
+            .get_at(&query, now + Duration::from_secs(300))
+            .is_none());
+        // Without an SOA record, there is no telling how long it holds
+        let query = build_query("nosoa.com", RecordType::A).unwrap();
+        let mut response = negative(&query, 900, 300);
+        response.message.authority.clear();
+        cache.insert_at(&query, &response, now);
+        assert!(cache.get_at(&query, now).is_none());
+    }
+
+    #[test]
+    fn failures_are_not_cached() {
+        let cache = Cache::new(DEFAULT_CAPACITY);
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let mut response = answer(&query, vec![address(300)]);
+        response.message.flags.rcode = Rcode::ServFail;
+        cache.insert(&query, &response);
+        let mut response = answer(&query, vec![address(300)]);
+        response.message.flags.tc = true;
+        cache.insert(&query, &response);
+        assert!(cache.get(&query).is_none());
+    }
+
+    #[test]
+    fn full_caches_drop_the_entries_closest_to_expiring() {
+        let cache = Cache::new(2);
+        let now = Instant::now();
+        let queries: Vec<_> = ["a.example", "b.example", "c.example"]
+            .iter()
+            .map(|name| build_query(name, RecordType::A).unwrap())
+            .collect();
+        for (query, ttl) in queries.iter().zip([300, 60, 600]) {
+            cache.insert_at(query, &answer(query, vec![address(ttl)]), now);
+        }
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get_at(&queries[0], now).is_some());
+        assert!(cache.get_at(&queries[1], now).is_none());
+        assert!(cache.get_at(&queries[2], now).is_some());
+    }
+
+    #[test]
+    fn replaced_entries_expire_at_their_new_time() {
+        let cache = Cache::new(2);
+        let now = Instant::now();
+        let queries: Vec<_> = ["a.example", "b.example", "c.example"]
+            .iter()
+            .map(|name| build_query(name, RecordType::A).unwrap())
+            .collect();
+        let insert = |query: &Message, ttl| {
+            cache.insert_at(query, &answer(query, vec![address(ttl)]), now);
+        };
+        insert(&queries[0], 60);
+        insert(&queries[0], 900);
+        insert(&queries[1], 300);
+        insert(&queries[2], 600);
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get_at(&queries[0], now).is_some());
+        assert!(cache.get_at(&queries[1], now).is_none());
+        assert_eq!(cache.entries.lock().expect("poisoned lock").expiry.len(), 2);
+    }
+
+    #[test]
+    fn unusable_entries_are_all_dropped_once_full() {
+        let cache = Cache::new(3);
+        let now = Instant::now();
+        let queries: Vec<_> = ["a.example", "b.example", "c.example", "d.example"]
+            .iter()
+            .map(|name| build_query(name, RecordType::A).unwrap())
+            .collect();
+        for (query, ttl) in queries.iter().zip([10, 20, 600]) {
+            cache.insert_at(query, &answer(query, vec![address(ttl)]), now);
+        }
+        let later = now + Duration::from_secs(30);
+        cache.insert_at(&queries[3], &answer(&queries[3], vec![address(60)]), later);
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.get_at(&queries[2], later).is_some());
+        assert!(cache.get_at(&queries[3], later).is_some());
+    }
+
+    #[test]
+    fn dnssec_queries_are_cached_apart() {
+        let cache = Cache::new(DEFAULT_CAPACITY);
+        let now = Instant::now();
+        let mut dnssec = build_query("example.com", RecordType::A).unwrap();
+        dnssec.additional.push(ResourceRecord {
+            name: Name::default(),
+            rtype: RecordType::Opt,
+            class: 1232,
+            ttl: 0x8000,
+            rdata: RData::Opt(Vec::new()),
+        });
+        cache.insert_at(&dnssec, &answer(&dnssec, vec![address(300)]), now);
+        assert!(cache.get_at(&dnssec, now).is_some());
+        // Neither a plain query nor one without checking gets it
+        let plain = build_query("example.com", RecordType::A).unwrap();
+        assert!(cache.get_at(&plain, now).is_none());
+        let mut unchecked = dnssec.clone();
+        unchecked.flags.cd = true;
+        assert!(cache.get_at(&unchecked, now).is_none());
+        cache.insert_at(&unchecked, &answer(&unchecked, vec![address(300)]), now);
+        assert!(cache.get_at(&unchecked, now).is_some());
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn stale_entries_are_only_served_within_the_window() {
+        let mut cache = Cache::new(DEFAULT_CAPACITY);
+        let now = Instant::now();
+        let query = build_query("example.com", RecordType::A).unwrap();
+        cache.insert_at(&query, &answer(&query, vec![address(60)]), now);
+        let later = now + Duration::from_secs(120);
+        assert!(cache.get_stale_at(&query, later).is_none());
+        cache.set_serve_stale(Some(Duration::from_secs(3600)));
+        let stale = cache.get_stale_at(&query, later).unwrap();
+        assert_eq!(stale.message.answers[0].ttl, STALE_TTL);
+        assert!(cache
+            .get_stale_at(&query, now + Duration::from_secs(3660))
+            .is_none());
+        assert_eq!(cache.stats().stale, 1);
+    }
+}
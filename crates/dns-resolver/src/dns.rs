@@ -8,9 +8,12 @@
 //! protocols over TCP can be tunnelled through Tor. It is not meant for any
 //! real production usage.
 use anyhow::Result;
-use std::fmt::Display;
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 #[derive(Error, Debug)]
 #[error("Failed to parse bytes into struct!")]
@@ -22,18 +25,201 @@ struct FromBytesError;
 /// Error we return if a bad domain name is passed
 pub struct DomainError;
 
-/// Hardcoded DNS server, stored as (&str, u16) detailing host and port
-pub const DNS_SERVER: (&str, u16) = ("1.1.1.1", 53);
+#[derive(Error, Debug)]
+#[error("Unknown record type {0}")]
+/// Error we return if a record type can't be recognized
+pub struct RecordTypeError(String);
+
+/// Error returned when the server answered with an RCODE other than NOERROR
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RcodeError {
+    /// FORMERR
+    #[error("server could not understand the query")]
+    FormErr,
+    /// SERVFAIL
+    #[error("server failure")]
+    ServFail,
+    /// NXDOMAIN
+    #[error("domain does not exist")]
+    NxDomain,
+    /// NOTIMP
+    #[error("server does not support this kind of query")]
+    NotImp,
+    /// REFUSED
+    #[error("server refused to answer")]
+    Refused,
+    /// Any other RCODE
+    #[error("server answered with {0}")]
+    Other(Rcode),
+}
+
+/// Error returned when a response doesn't match the query we sent
+///
+/// This usually means someone is trying to slip us a forged answer, so the
+/// response has to be thrown away
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MismatchError {
+    /// The message isn't a response, or is for another kind of query
+    #[error("message isn't a response to the query")]
+    NotResponse,
+    /// The number of questions isn't the one of the query
+    #[error("response has {got} questions, expected {expected}")]
+    QuestionCount {
+        /// Number of questions in the query
+        expected: usize,
+        /// Number of questions in the response
+        got: usize,
+    },
+    /// The transaction ID is not the one of the query
+    #[error("response has ID {got}, expected {expected}")]
+    Id {
+        /// ID of the query
+        expected: u16,
+        /// ID of the response
+        got: u16,
+    },
+    /// The question isn't echoed back exactly, with the same case
+    #[error("response is for {got}, expected {expected}")]
+    Name {
+        /// Name in the query
+        expected: Name,
+        /// Name in the response
+        got: Name,
+    },
+    /// The type of the question isn't echoed back
+    #[error("response is for type {got}, expected {expected}")]
+    Type {
+        /// Type in the query
+        expected: RecordType,
+        /// Type in the response
+        got: RecordType,
+    },
+    /// The class of the question isn't echoed back
+    #[error("response is for class {got}, expected {expected}")]
+    Class {
+        /// Class in the query
+        expected: u16,
+        /// Class in the response
+        got: u16,
+    },
+}
 
-/// Default value for QTYPE field
-const QTYPE: u16 = 0x0001;
 /// Default value for QCLASS field
 const QCLASS: u16 = 0x0001;
 
+/// Type of a record, as used in the QTYPE and TYPE fields
+///
+/// Only the common types have their own variant, the others are kept as
+/// their number and shown as `TYPE<number>` like RFC 3597 does
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    /// IPv4 address
+    A,
+    /// Authoritative name server
+    Ns,
+    /// Canonical name of an alias
+    Cname,
+    /// Start of a zone of authority
+    Soa,
+    /// Domain name pointer, used for reverse lookups
+    Ptr,
+    /// Mail exchange
+    Mx,
+    /// Text strings
+    Txt,
+    /// IPv6 address
+    Aaaa,
+    /// Location of a service
+    Srv,
+    /// Certification authorities allowed to issue certificates
+    Caa,
+    /// EDNS pseudo-record, see RFC 6891
+    Opt,
+    /// Any other type
+    Other(u16),
+}
+
+impl RecordType {
+    /// Every type with its own variant, along with its number and name
+    const KNOWN: [(RecordType, u16, &'static str); 11] = [
+        (RecordType::A, 1, "A"),
+        (RecordType::Ns, 2, "NS"),
+        (RecordType::Cname, 5, "CNAME"),
+        (RecordType::Soa, 6, "SOA"),
+        (RecordType::Ptr, 12, "PTR"),
+        (RecordType::Mx, 15, "MX"),
+        (RecordType::Txt, 16, "TXT"),
+        (RecordType::Aaaa, 28, "AAAA"),
+        (RecordType::Srv, 33, "SRV"),
+        (RecordType::Opt, 41, "OPT"),
+        (RecordType::Caa, 257, "CAA"),
+    ];
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> Self {
+        RecordType::KNOWN
+            .iter()
+            .find(|(_, number, _)| *number == value)
+            .map(|(rtype, _, _)| *rtype)
+            .unwrap_or(RecordType::Other(value))
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(rtype: RecordType) -> Self {
+        match rtype {
+            RecordType::Other(value) => value,
+            known => RecordType::KNOWN
+                .iter()
+                .find(|(rtype, _, _)| *rtype == known)
+                .map(|(_, number, _)| *number)
+                .expect("every variant is in KNOWN"),
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = RecordTypeError;
+
+    /// Parse a type name like `AAAA`, in any case, or a number written
+    /// as `TYPE<number>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        if let Some((rtype, _, _)) = RecordType::KNOWN.iter().find(|(_, _, name)| *name == upper) {
+            return Ok(*rtype);
+        }
+        upper
+            .strip_prefix("TYPE")
+            .and_then(|number| number.parse::<u16>().ok())
+            .map(RecordType::from)
+            .ok_or_else(|| RecordTypeError(s.to_string()))
+    }
+}
+
+impl Display for RecordType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match RecordType::KNOWN.iter().find(|(rtype, _, _)| rtype == self) {
+            Some((_, _, name)) => write!(f, "{}", name),
+            None => write!(f, "TYPE{}", u16::from(*self)),
+        }
+    }
+}
+
+/// Mnemonic of a class, as shown in presentation format
+fn class_name(class: u16) -> String {
+    match class {
+        1 => "IN".to_string(),
+        3 => "CH".to_string(),
+        4 => "HS".to_string(),
+        other => format!("CLASS{}", other),
+    }
+}
+
 /// Used to convert struct to raw bytes to be sent over the network
 ///
 /// Example:
-/// ```
+/// ```ignore
 /// // We have some struct S that implements this trait
 /// let s = S::new();
 /// // This prints the raw bytes as debug output
@@ -50,100 +236,280 @@ pub trait AsBytes {
 /// Used to convert raw bytes representation into a Rust struct
 ///
 /// Example:
-/// ```
+/// ```ignore
 /// let mut buf: Vec<u8> = Vec::new();
 /// // Read the response from a stream
 /// stream.read_to_end(&mut buf).await.unwrap();
 /// // Interpret the response into a struct S
-/// let resp = S::from_bytes(&buf);
+/// let resp = S::from_bytes(&buf)?;
 /// ```
 ///
-/// In the above code, `resp` is `Option<Box<S>>` type, so you will have to
-/// deal with the `None` value appropriately. This helps denote invalid
-/// situations, ie, parse failures
-///
-/// You will have to interpret each byte and convert it into each field
-/// of your struct yourself when implementing this trait.
+/// `bytes` is a whole message. Implementations read it field by field
+/// through a `Cursor`, which checks every read against the end of the data
+/// and can follow compression pointers back into the message, so that
+/// truncated or malformed input turns into a `FromBytesError` rather than a
+/// panic.
 pub trait FromBytes {
-    /// Convert two u8's into a u16
-    ///
-    /// It is just a thin wrapper over [u16::from_be_bytes()]
-    fn u8_to_u16(upper: u8, lower: u8) -> u16 {
-        let bytes = [upper, lower];
-        u16::from_be_bytes(bytes)
-    }
-    /// Convert four u8's contained in a slice into a u32
-    ///
-    /// It is just a thin wrapper over [u32::from_be_bytes()] but also deals
-    /// with converting &\[u8\] (u8 slice) into [u8; 4] (a fixed size array of u8)
-    fn u8_to_u32(bytes_slice: &[u8]) -> Result<u32> {
-        let bytes: [u8; 4] = bytes_slice.try_into()?;
-        Ok(u32::from_be_bytes(bytes))
-    }
     /// Try converting given bytes into the struct
     ///
-    /// Returns an `Option<Box>` of the struct which implements
-    /// this trait to help denote parsing failures
+    /// Fails with a `FromBytesError` if the bytes don't hold a valid
+    /// message
     fn from_bytes(bytes: &[u8]) -> Result<Box<Self>>;
 }
 
-/// Report length of the struct as in byte stream
+/// Kind of query of a message, see RFC 1035 section 4.1.1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Opcode {
+    /// Standard query
+    #[default]
+    Query,
+    /// Inverse query, obsolete
+    IQuery,
+    /// Server status request
+    Status,
+    /// Zone change notification, see RFC 1996
+    Notify,
+    /// Dynamic update, see RFC 2136
+    Update,
+    /// Any other opcode
+    Other(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            other => Opcode::Other(other),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Other(other) => other,
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opcode::Query => write!(f, "QUERY"),
+            Opcode::IQuery => write!(f, "IQUERY"),
+            Opcode::Status => write!(f, "STATUS"),
+            Opcode::Notify => write!(f, "NOTIFY"),
+            Opcode::Update => write!(f, "UPDATE"),
+            Opcode::Other(other) => write!(f, "OPCODE{}", other),
+        }
+    }
+}
+
+/// Result of a query, as told by the server, see RFC 1035 section 4.1.1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rcode {
+    /// The query went fine
+    #[default]
+    NoError,
+    /// The server couldn't interpret the query
+    FormErr,
+    /// The server had some trouble answering
+    ServFail,
+    /// The domain name doesn't exist
+    NxDomain,
+    /// The server doesn't support this kind of query
+    NotImp,
+    /// The server won't answer, for example because of its policy
+    Refused,
+    /// Any other RCODE
+    Other(u8),
+}
+
+impl Rcode {
+    /// Turn the RCODE into an error, unless it is NOERROR
+    pub fn into_result(self) -> Result<(), RcodeError> {
+        match self {
+            Rcode::NoError => Ok(()),
+            Rcode::FormErr => Err(RcodeError::FormErr),
+            Rcode::ServFail => Err(RcodeError::ServFail),
+            Rcode::NxDomain => Err(RcodeError::NxDomain),
+            Rcode::NotImp => Err(RcodeError::NotImp),
+            Rcode::Refused => Err(RcodeError::Refused),
+            other => Err(RcodeError::Other(other)),
+        }
+    }
+}
+
+impl From<u8> for Rcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NxDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            other => Rcode::Other(other),
+        }
+    }
+}
+
+impl From<Rcode> for u8 {
+    fn from(rcode: Rcode) -> Self {
+        match rcode {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NxDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            Rcode::Other(other) => other,
+        }
+    }
+}
+
+impl Display for Rcode {
+    /// Show the RCODE the way dig does in its status line
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rcode::NoError => write!(f, "NOERROR"),
+            Rcode::FormErr => write!(f, "FORMERR"),
+            Rcode::ServFail => write!(f, "SERVFAIL"),
+            Rcode::NxDomain => write!(f, "NXDOMAIN"),
+            Rcode::NotImp => write!(f, "NOTIMP"),
+            Rcode::Refused => write!(f, "REFUSED"),
+            Rcode::Other(other) => write!(f, "RCODE{}", other),
+        }
+    }
+}
+
+/// Flags of a message, packed on the wire into the second row of the header
 ///
-/// Note that this doesn't mean length of struct
+/// Refer to RFC 1035 section 4.1.1 and RFC 4035 section 3.2 for more info,
+/// but here's a small layout of what is packed into this row:
 ///
-/// It is simply used to denote how long the struct is if it were
-/// sent over the wire
-trait Len {
-    /// Report length of the struct as in byte stream
-    fn len(&self) -> usize;
+///
+///   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
+/// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+/// |QR|   Opcode  |AA|TC|RD|RA|Z |AD|CD|   RCODE   |
+/// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// Whether the message is a response rather than a query
+    pub qr: bool,
+    /// Kind of query
+    pub opcode: Opcode,
+    /// Whether the answer comes from a server authoritative for the domain
+    pub aa: bool,
+    /// Whether the message was truncated
+    pub tc: bool,
+    /// Whether recursion is desired
+    pub rd: bool,
+    /// Whether the server can do recursion
+    pub ra: bool,
+    /// Reserved bit, which should be zero
+    pub z: bool,
+    /// Whether the server verified the answer with DNSSEC
+    pub ad: bool,
+    /// Whether DNSSEC checking is disabled
+    pub cd: bool,
+    /// Result of the query
+    pub rcode: Rcode,
+}
+
+impl From<u16> for Flags {
+    fn from(bits: u16) -> Self {
+        let bit = |n: u16| bits & (1 << n) != 0;
+        Self {
+            qr: bit(15),
+            opcode: Opcode::from(((bits >> 11) & 0xf) as u8),
+            aa: bit(10),
+            tc: bit(9),
+            rd: bit(8),
+            ra: bit(7),
+            z: bit(6),
+            ad: bit(5),
+            cd: bit(4),
+            rcode: Rcode::from((bits & 0xf) as u8),
+        }
+    }
+}
+
+impl From<Flags> for u16 {
+    fn from(flags: Flags) -> Self {
+        let bit = |set: bool, n: u16| if set { 1 << n } else { 0 };
+        bit(flags.qr, 15)
+            | (u16::from(u8::from(flags.opcode)) & 0xf) << 11
+            | bit(flags.aa, 10)
+            | bit(flags.tc, 9)
+            | bit(flags.rd, 8)
+            | bit(flags.ra, 7)
+            | bit(flags.z, 6)
+            | bit(flags.ad, 5)
+            | bit(flags.cd, 4)
+            | (u16::from(u8::from(flags.rcode)) & 0xf)
+    }
 }
 
-/// DNS Header to be used by both Query and Response
+impl Display for Flags {
+    /// List the flags which are set, like dig does
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (self.qr, "qr"),
+            (self.aa, "aa"),
+            (self.tc, "tc"),
+            (self.rd, "rd"),
+            (self.ra, "ra"),
+            (self.z, "z"),
+            (self.ad, "ad"),
+            (self.cd, "cd"),
+        ];
+        let set: Vec<&str> = names
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", set.join(" "))
+    }
+}
+
+/// DNS Header, as found at the start of every [Message]
 ///
-/// The default values chosen are from the perspective of the client
-// TODO: For server we will have to interpret given values
+/// This is only used to encode and decode messages, the counts it holds are
+/// those of the sections of the message
 struct Header {
     /// Random 16 bit number used to identify the DNS request
     identification: u16,
-    /// Set of fields packed together into one 16 bit number
-    ///
-    /// Refer to RFC 1035 for more info, but here's a small
-    /// layout of what is packed into this row:
-    ///
-    ///
-    ///   0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
-    /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    /// |QR|   Opcode  |AA|TC|RD|RA|   Z    |   RCODE   |
-    /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-    ///
-    /// TODO: don't rely on cryptic packed bits
-    packed_second_row: u16, // set to 0x100
+    /// Flags of the message, including the RCODE of responses
+    flags: Flags,
     /// Number of questions we have
-    ///
-    /// Here, we set it to 1 since we only ask about one hostname in a query
-    qdcount: u16, // set to 1 since we have 1 question
+    qdcount: u16,
     /// Number of answers we have
     ///
     /// For a query it will be zero, for a response hopefully it is >= 1
-    ancount: u16, // set to 0 since client doesn't have answers
+    ancount: u16,
     /// Refer to RFC 1035 section 4.1.1, NSCOUNT
-    nscount: u16, // set to 0
+    nscount: u16,
     /// Refer to RFC 1035 section 4.1.1, ARCOUNT
-    arcount: u16, // set to 0
+    arcount: u16,
 }
 
 // Ugly, repetitive code to convert all six 16-bit fields into Vec<u8>
 impl AsBytes for Header {
     fn as_bytes(&self) -> Vec<u8> {
-        let mut v: Vec<u8> = Vec::with_capacity(14);
-        // These 2 bytes store size of the rest of the payload (including header)
-        // Right now it denotes 51 byte size packet, excluding these 2 bytes
-        // We will change this when we know the size of Query
-        v.push(0x00);
-        v.push(0x33);
+        let mut v: Vec<u8> = Vec::with_capacity(12);
         // Just break u16 into [u8, u8] array and copy into vector
         v.extend_from_slice(&u16::to_be_bytes(self.identification));
-        v.extend_from_slice(&u16::to_be_bytes(self.packed_second_row));
+        v.extend_from_slice(&u16::to_be_bytes(self.flags.into()));
         v.extend_from_slice(&u16::to_be_bytes(self.qdcount));
         v.extend_from_slice(&u16::to_be_bytes(self.ancount));
         v.extend_from_slice(&u16::to_be_bytes(self.nscount));
@@ -152,309 +518,1341 @@ impl AsBytes for Header {
     }
 }
 
-impl Display for Header {
+impl Header {
+    /// Decode the header `cursor` is at
+    fn decode(cursor: &mut Cursor<'_>) -> Result<Self> {
+        debug!("Parsing the header");
+        // These offsets were determined by looking at RFC 1035
+        Ok(Header {
+            identification: cursor.read_u16()?,
+            flags: Flags::from(cursor.read_u16()?),
+            qdcount: cursor.read_u16()?,
+            ancount: cursor.read_u16()?,
+            nscount: cursor.read_u16()?,
+            arcount: cursor.read_u16()?,
+        })
+    }
+}
+
+/// A question, asking for the records of some type and class of a name
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Question {
+    /// The domain name we are asking about
+    pub qname: Name,
+    /// Denotes the type of record to get, like A records for IPv4
+    pub qtype: RecordType,
+    /// Denotes the class of the record, 1 for Internet addresses
+    pub qclass: u16,
+}
+
+impl Question {
+    /// Ask for records of type `qtype` of `qname`, in the Internet class
+    pub fn new(qname: Name, qtype: RecordType) -> Self {
+        Self {
+            qname,
+            qtype,
+            qclass: QCLASS,
+        }
+    }
+
+    /// Decode the question `cursor` is at
+    fn decode(cursor: &mut Cursor<'_>) -> Result<Self> {
+        let qname = Name::decode(cursor)?;
+        debug!("Reached end of name, moving on to parse other fields");
+        // These offsets were determined by looking at RFC 1035
+        Ok(Self {
+            qname,
+            qtype: cursor.read_u16()?.into(),
+            qclass: cursor.read_u16()?,
+        })
+    }
+
+    /// Append the question to the message `v`, compressing its name
+    fn compress_into(&self, v: &mut Vec<u8>, compressor: &mut Compressor) {
+        self.qname.compress_into(v, compressor);
+        v.extend_from_slice(&u16::to_be_bytes(self.qtype.into()));
+        v.extend_from_slice(&u16::to_be_bytes(self.qclass));
+    }
+}
+
+impl AsBytes for Question {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut v = self.qname.encode();
+        v.extend_from_slice(&u16::to_be_bytes(self.qtype.into()));
+        v.extend_from_slice(&u16::to_be_bytes(self.qclass));
+        v
+    }
+}
+
+impl Display for Question {
+    /// Show the question the way dig does
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "ID: 0x{:x}", self.identification)?;
-        writeln!(f, "Flags: 0x{:x}", self.packed_second_row)?;
-        writeln!(f, "QDCOUNT: 0x{:x}", self.qdcount)?;
-        writeln!(f, "ANCOUNT: 0x{:x}", self.ancount)?;
-        writeln!(f, "NSCOUNT: 0x{:x}", self.nscount)?;
-        writeln!(f, "ARCOUNT: 0x{:x}", self.arcount)?;
-        Ok(())
+        write!(
+            f,
+            ";{}\t\t{}\t{}",
+            self.qname,
+            class_name(self.qclass),
+            self.qtype
+        )
     }
 }
 
-impl FromBytes for Header {
-    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>> {
-        debug!("Parsing the header");
-        let packed_second_row = Header::u8_to_u16(bytes[2], bytes[3]);
-        // 0x8180 denotes we have a response to a standard query,
-        // that isn't truncated, and has recursion requested to a server
-        // that can do recursion, with some bits reserved for future use
-        // and some that are not relavent for our purposes
-        if packed_second_row == 0x8180 {
-            debug!("Correct flags set in response");
-        } else {
-            error!(
-                "Incorrect flags set in response, we got {}",
-                packed_second_row
+/// Reads the fields of a message one after the other
+///
+/// Every read is checked against the end of the data, so that a truncated or
+/// hostile message turns into an error instead of a panic. Compression
+/// pointers are offsets from the start of the message, so the cursor keeps
+/// the whole message around, along with where it is in it and how far it
+/// may read.
+#[derive(Clone, Copy, Debug)]
+struct Cursor<'a> {
+    /// The whole message
+    message: &'a [u8],
+    /// Offset of the next byte to read
+    position: usize,
+    /// Offset at which reads have to stop
+    end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Start reading at the beginning of `message`
+    fn new(message: &'a [u8]) -> Self {
+        Self::at(message, 0)
+    }
+
+    /// Start reading at `position` in `message`
+    fn at(message: &'a [u8], position: usize) -> Self {
+        Self {
+            message,
+            position,
+            end: message.len(),
+        }
+    }
+
+    /// Offset of the next byte to read
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes left to read
+    fn remaining(&self) -> usize {
+        self.end.saturating_sub(self.position)
+    }
+
+    /// Whether everything has been read
+    fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Read the next `len` bytes
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            debug!(
+                "Truncated message, {} bytes needed at {} but only {} left",
+                len,
+                self.position,
+                self.remaining()
             );
             return Err(FromBytesError.into());
         }
-        // These offsets were determined by looking at RFC 1035
-        Ok(Box::new(Header {
-            identification: Header::u8_to_u16(bytes[0], bytes[1]),
-            packed_second_row,
-            qdcount: Header::u8_to_u16(bytes[4], bytes[5]),
-            ancount: Header::u8_to_u16(bytes[6], bytes[7]),
-            nscount: Header::u8_to_u16(bytes[8], bytes[9]),
-            arcount: Header::u8_to_u16(bytes[10], bytes[11]),
-        }))
+        let bytes = &self.message[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Read a single byte
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Read a big endian u16
+    fn read_u16(&mut self) -> Result<u16> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Read a big endian u32
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Split off a cursor over the next `len` bytes, and skip them
+    ///
+    /// The new cursor can still follow compression pointers to anywhere in
+    /// the message
+    fn take(&mut self, len: usize) -> Result<Cursor<'a>> {
+        let start = self.position;
+        self.read_bytes(len)?;
+        Ok(Self {
+            message: self.message,
+            position: start,
+            end: self.position,
+        })
     }
 }
 
-/// The actual query we will send to a DNS server
+/// Longest a name may be on the wire, see RFC 1035 section 2.3.4
+const MAX_NAME_LEN: usize = 255;
+/// Longest a single label may be
+const MAX_LABEL_LEN: usize = 63;
+
+/// Where the names written so far start in a message, so that later names
+/// ending the same way can point there instead, see RFC 1035 section 4.1.4
+///
+/// Suffixes are matched exactly rather than ignoring case, so that every
+/// name keeps the case it was written with
+#[derive(Debug, Default)]
+struct Compressor {
+    /// Offset of each suffix of the names written so far
+    offsets: HashMap<Vec<Vec<u8>>, u16>,
+}
+
+/// Largest offset a compression pointer can hold
+const MAX_POINTER: usize = 0x3fff;
+
+/// A domain name, stored as its labels
 ///
-/// For now A records are fetched only
-// TODO: add support for different records to be fetched
-pub struct Query {
-    /// Header of the DNS packet, see [Header] for more info
-    header: Header,
-    /// The domain name, stored as a `Vec<u8>`
+/// Labels are kept as they were sent, without changing their case, so that
+/// two names only compare equal if they are written exactly the same way
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Name {
+    /// Labels making up the name, without the empty root label
+    labels: Vec<Vec<u8>>,
+}
+
+impl Name {
+    /// Number of bytes the name takes on the wire, without compression
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| 1 + label.len())
+            .sum::<usize>()
+            + 1
+    }
+
+    /// Decode the name `cursor` is at, and move the cursor past it
     ///
-    /// When we call [Query::from_bytes()], `qname` is automatically
-    /// converted into string stored in a `Vec<u8>` instead of the raw
-    /// byte format used for `qname`
-    qname: Vec<u8>, // domain name
-    /// Denotes the type of record to get.
+    /// This follows RFC 1035 section 4.1.4: a name is a run of labels ending
+    /// either with the empty root label, or with a pointer to the rest of the
+    /// name somewhere else in the message. The cursor ends up right after
+    /// the first pointer if there was one.
     ///
-    /// Here we set to 1 to get an A record, ie, IPv4
-    qtype: u16, // set to 0x0001 for A records
-    /// Denotes the class of the record
+    /// Pointers have to point before the labels leading to them, so that a
+    /// hostile message can't send us around in circles.
+    fn decode(cursor: &mut Cursor<'_>) -> Result<Self> {
+        let mut labels = Vec::new();
+        let mut wire_len = 1;
+        // Where we read labels from, which moves around when following pointers
+        let mut reader = *cursor;
+        // Where the labels we are reading started, pointers have to go before it
+        let mut run_start = reader.position();
+        let mut jumped = false;
+        loop {
+            let len = reader.read_u8()? as usize;
+            match len {
+                0 => break,
+                // The two top bits set denote a pointer to somewhere else in the message
+                0xc0..=0xff => {
+                    let pointer = ((len & 0x3f) << 8) | reader.read_u8()? as usize;
+                    if pointer >= run_start {
+                        error!(
+                            "Compression pointer at {} doesn't point backwards",
+                            reader.position() - 2
+                        );
+                        return Err(FromBytesError.into());
+                    }
+                    if !jumped {
+                        *cursor = reader;
+                        jumped = true;
+                    }
+                    reader = Cursor::at(reader.message, pointer);
+                    run_start = pointer;
+                }
+                // 0x40 and 0x80 are reserved for extended label types, which we don't know
+                0x40..=0xbf => {
+                    error!(
+                        "Unknown label type 0x{:x} at {}",
+                        len & 0xc0,
+                        reader.position() - 1
+                    );
+                    return Err(FromBytesError.into());
+                }
+                _ => {
+                    let label = reader.read_bytes(len)?;
+                    wire_len += 1 + len;
+                    if wire_len > MAX_NAME_LEN {
+                        error!("Name is longer than {} bytes", MAX_NAME_LEN);
+                        return Err(FromBytesError.into());
+                    }
+                    labels.push(label.to_vec());
+                }
+            }
+        }
+        if !jumped {
+            *cursor = reader;
+        }
+        Ok(Self { labels })
+    }
+
+    /// Flip the case of each letter at random, as described in
+    /// draft-vixie-dnsext-dns0x20
     ///
-    /// Here we set to 1 to get an Internet address
-    qclass: u16, // set to 1 for Internet addresses
-}
+    /// Servers copy the question into their response as is, so checking
+    /// the case of the echoed name gives one more bit of entropy per letter
+    /// an attacker has to guess on top of the transaction ID
+    pub fn randomize_case(&mut self) {
+        for byte in self.labels.iter_mut().flatten() {
+            if byte.is_ascii_alphabetic() && rand::random() {
+                *byte ^= 0x20;
+            }
+        }
+    }
 
-impl AsBytes for Query {
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut v: Vec<u8> = Vec::new();
-        let header_bytes = self.header.as_bytes();
-        v.extend(header_bytes);
-        v.extend(&self.qname);
-        v.extend_from_slice(&u16::to_be_bytes(self.qtype));
-        v.extend_from_slice(&u16::to_be_bytes(self.qclass));
-        // Now that the packet is ready, we can calculate size and set that in
-        // first two octets
-        // Subtract 2 since these first 2 bits are never counted when reporting
-        // length like this
-        let len_bits = u16::to_be_bytes((v.len() - 2) as u16);
-        v[0] = len_bits[0];
-        v[1] = len_bits[1];
+    /// Copy of the name with all its letters in lower case, for comparing
+    /// names the way DNS does, see RFC 4343
+    pub fn to_lowercase(&self) -> Name {
+        Name {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Encode the name for the wire, without compression
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.wire_len());
+        for label in self.labels.iter() {
+            v.push(label.len() as u8);
+            v.extend_from_slice(label);
+        }
+        v.push(0x00); // Denote that the name has ended with the root label
         v
     }
+
+    /// Append the name to the message `v`, pointing to the longest suffix of
+    /// it that was written before, and remember where its own suffixes start
+    fn compress_into(&self, v: &mut Vec<u8>, compressor: &mut Compressor) {
+        for (i, label) in self.labels.iter().enumerate() {
+            let suffix = &self.labels[i..];
+            if let Some(offset) = compressor.offsets.get(suffix) {
+                v.extend_from_slice(&(0xc000 | offset).to_be_bytes());
+                return;
+            }
+            if v.len() <= MAX_POINTER {
+                compressor.offsets.insert(suffix.to_vec(), v.len() as u16);
+            }
+            v.push(label.len() as u8);
+            v.extend_from_slice(label);
+        }
+        v.push(0x00);
+    }
 }
 
-impl Len for Query {
-    fn len(&self) -> usize {
-        // extra 1 is for compensating for how we
-        // use one byte more to store length of domain name
-        12 + 1 + self.qname.len() + 2 + 2
+impl FromStr for Name {
+    type Err = DomainError;
+
+    /// Parse a name like `example.com`, with or without the final dot
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_suffix('.').unwrap_or(s);
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut labels = Vec::new();
+        for part in s.split('.') {
+            if part.is_empty() || part.len() > MAX_LABEL_LEN {
+                return Err(DomainError);
+            }
+            labels.push(part.as_bytes().to_vec());
+        }
+        let name = Self { labels };
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(DomainError);
+        }
+        Ok(name)
     }
 }
 
-impl FromBytes for Query {
-    // FIXME: the name struct isn't stored as it was sent over the wire
-    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>> {
-        let header = *Header::from_bytes(&bytes[..12])?;
-        if bytes.len() < 12 {
-            error!("Mismatch between expected number of bytes and given number of bytes!");
+impl Display for Name {
+    /// Show the name in presentation format, ending with a dot, and with
+    /// the same escapes as dig
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.labels.is_empty() {
+            return f.write_char('.');
+        }
+        for label in self.labels.iter() {
+            for byte in label {
+                match byte {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", *byte as char)?
+                    }
+                    0x21..=0x7e => f.write_char(*byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+            f.write_char('.')?;
+        }
+        Ok(())
+    }
+}
+
+/// Write `bytes` as a quoted character-string, escaping what dig escapes
+fn write_character_string(f: &mut std::fmt::Formatter<'_>, bytes: &[u8]) -> std::fmt::Result {
+    f.write_char('"')?;
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+            0x20..=0x7e => f.write_char(*byte as char)?,
+            _ => write!(f, "\\{:03}", byte)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Decoded RDATA of a record
+///
+/// The record types we know about are decoded into their fields, and other
+/// types are kept as raw bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RData {
+    /// IPv4 address
+    A(Ipv4Addr),
+    /// IPv6 address
+    Aaaa(Ipv6Addr),
+    /// Canonical name the owner is an alias for
+    Cname(Name),
+    /// Authoritative name server of the zone
+    Ns(Name),
+    /// Name the owner points to, for reverse lookups
+    Ptr(Name),
+    /// Mail exchange of the domain
+    Mx {
+        /// Lower values are preferred
+        preference: u16,
+        /// Host accepting the mail
+        exchange: Name,
+    },
+    /// One or more character-strings
+    Txt(Vec<Vec<u8>>),
+    /// Start of a zone of authority
+    Soa {
+        /// Primary name server of the zone
+        mname: Name,
+        /// Mailbox of the person responsible for the zone
+        rname: Name,
+        /// Version of the zone
+        serial: u32,
+        /// Seconds before secondary servers should refresh the zone
+        refresh: u32,
+        /// Seconds before a failed refresh is retried
+        retry: u32,
+        /// Seconds after which the zone is no longer authoritative
+        expire: u32,
+        /// TTL of negative answers
+        minimum: u32,
+    },
+    /// Location of a service
+    Srv {
+        /// Lower values are tried first
+        priority: u16,
+        /// Relative weight among records of the same priority
+        weight: u16,
+        /// Port the service listens on
+        port: u16,
+        /// Host providing the service
+        target: Name,
+    },
+    /// Certification authority authorization
+    Caa {
+        /// Flags, the top bit marks the property as critical
+        flags: u8,
+        /// Property, like `issue` or `iodef`
+        tag: String,
+        /// Value of the property
+        value: Vec<u8>,
+    },
+    /// EDNS options, as pairs of option code and data
+    Opt(Vec<(u16, Vec<u8>)>),
+    /// Any other type, as raw bytes
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Decode RDATA of type `rtype`, `rdata` being a cursor over exactly
+    /// the RDATA of the record
+    ///
+    /// Names may point to other parts of the message, but they have to end
+    /// within the RDATA, and all of the RDATA has to be used up
+    fn decode(rtype: RecordType, rdata: &mut Cursor<'_>) -> Result<Self> {
+        let data = match rtype {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.read_bytes(4)?.try_into()?;
+                RData::A(Ipv4Addr::from(octets))
+            }
+            RecordType::Aaaa => {
+                let octets: [u8; 16] = rdata.read_bytes(16)?.try_into()?;
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            RecordType::Cname => RData::Cname(Name::decode(rdata)?),
+            RecordType::Ns => RData::Ns(Name::decode(rdata)?),
+            RecordType::Ptr => RData::Ptr(Name::decode(rdata)?),
+            RecordType::Mx => RData::Mx {
+                preference: rdata.read_u16()?,
+                exchange: Name::decode(rdata)?,
+            },
+            RecordType::Txt => {
+                let mut strings = Vec::new();
+                while !rdata.is_empty() {
+                    let len = rdata.read_u8()? as usize;
+                    strings.push(rdata.read_bytes(len)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            RecordType::Soa => RData::Soa {
+                mname: Name::decode(rdata)?,
+                rname: Name::decode(rdata)?,
+                serial: rdata.read_u32()?,
+                refresh: rdata.read_u32()?,
+                retry: rdata.read_u32()?,
+                expire: rdata.read_u32()?,
+                minimum: rdata.read_u32()?,
+            },
+            RecordType::Srv => RData::Srv {
+                priority: rdata.read_u16()?,
+                weight: rdata.read_u16()?,
+                port: rdata.read_u16()?,
+                target: Name::decode(rdata)?,
+            },
+            RecordType::Caa => {
+                let flags = rdata.read_u8()?;
+                let tag_len = rdata.read_u8()? as usize;
+                let tag = rdata.read_bytes(tag_len)?;
+                // Tags are made of ASCII letters and digits, see RFC 8659
+                if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                    error!("Invalid CAA tag");
+                    return Err(FromBytesError.into());
+                }
+                RData::Caa {
+                    flags,
+                    tag: String::from_utf8_lossy(tag).into_owned(),
+                    value: rdata.read_bytes(rdata.remaining())?.to_vec(),
+                }
+            }
+            RecordType::Opt => {
+                let mut options = Vec::new();
+                while !rdata.is_empty() {
+                    let code = rdata.read_u16()?;
+                    let len = rdata.read_u16()? as usize;
+                    options.push((code, rdata.read_bytes(len)?.to_vec()));
+                }
+                RData::Opt(options)
+            }
+            RecordType::Other(_) => RData::Unknown(rdata.read_bytes(rdata.remaining())?.to_vec()),
+        };
+        if !rdata.is_empty() {
+            error!(
+                "{} bytes left over after {} RDATA",
+                rdata.remaining(),
+                rtype
+            );
             return Err(FromBytesError.into());
         }
-        // Parse name
-        let mut name = String::new();
-        // 12 represents size of Header, which we have already parsed, or errored out of
-        let mut lastnamebyte = 12;
-        loop {
-            // bytes[lastnamebytes] denotes the prefix length, we read that many bytes into name
-            let start = lastnamebyte + 1;
-            let end = start + bytes[lastnamebyte] as usize;
-            name.extend(std::str::from_utf8(&bytes[start..end]));
-            lastnamebyte = end;
-            if lastnamebyte >= bytes.len() || bytes[lastnamebyte] == 0 {
-                // End of domain name, proceed to parse further fields
-                debug!("Reached end of name, moving on to parse other fields");
-                lastnamebyte += 1;
-                break;
+        Ok(data)
+    }
+
+    /// Encode the RDATA for the wire, without compressing names
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        match self {
+            RData::A(address) => v.extend_from_slice(&address.octets()),
+            RData::Aaaa(address) => v.extend_from_slice(&address.octets()),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => v.extend(name.encode()),
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                v.extend_from_slice(&preference.to_be_bytes());
+                v.extend(exchange.encode());
+            }
+            RData::Txt(strings) => {
+                for string in strings {
+                    v.push(string.len() as u8);
+                    v.extend_from_slice(string);
+                }
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                v.extend(mname.encode());
+                v.extend(rname.encode());
+                for number in [serial, refresh, retry, expire, minimum] {
+                    v.extend_from_slice(&number.to_be_bytes());
+                }
             }
-            name.push('.');
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                for number in [priority, weight, port] {
+                    v.extend_from_slice(&number.to_be_bytes());
+                }
+                v.extend(target.encode());
+            }
+            RData::Caa { flags, tag, value } => {
+                v.push(*flags);
+                v.push(tag.len() as u8);
+                v.extend_from_slice(tag.as_bytes());
+                v.extend_from_slice(value);
+            }
+            RData::Opt(options) => {
+                for (code, data) in options {
+                    v.extend_from_slice(&code.to_be_bytes());
+                    v.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                    v.extend_from_slice(data);
+                }
+            }
+            RData::Unknown(bytes) => v.extend_from_slice(bytes),
         }
-        // These offsets were determined by looking at RFC 1035
-        Ok(Box::new(Self {
-            header,
-            qname: name.as_bytes().to_vec(),
-            qtype: Query::u8_to_u16(bytes[lastnamebyte], bytes[lastnamebyte + 1]),
-            qclass: Query::u8_to_u16(bytes[lastnamebyte + 2], bytes[lastnamebyte + 3]),
-        }))
+        v
+    }
+
+    /// Append the RDATA to the message `v`, compressing names only in the
+    /// types RFC 1035 defines, as RFC 3597 section 4 asks
+    fn compress_into(&self, v: &mut Vec<u8>, compressor: &mut Compressor) {
+        match self {
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => {
+                name.compress_into(v, compressor)
+            }
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                v.extend_from_slice(&preference.to_be_bytes());
+                exchange.compress_into(v, compressor);
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                mname.compress_into(v, compressor);
+                rname.compress_into(v, compressor);
+                for number in [serial, refresh, retry, expire, minimum] {
+                    v.extend_from_slice(&number.to_be_bytes());
+                }
+            }
+            _ => v.extend(self.encode()),
+        }
+    }
+}
+
+impl Display for RData {
+    /// Show the RDATA in presentation format, the way dig does
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RData::A(address) => write!(f, "{}", address),
+            RData::Aaaa(address) => write!(f, "{}", address),
+            RData::Cname(name) | RData::Ns(name) | RData::Ptr(name) => write!(f, "{}", name),
+            RData::Mx {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::Txt(strings) => {
+                for (i, string) in strings.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(' ')?;
+                    }
+                    write_character_string(f, string)?;
+                }
+                Ok(())
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::Caa { flags, tag, value } => {
+                write!(f, "{} {} ", flags, tag)?;
+                write_character_string(f, value)
+            }
+            RData::Opt(options) => {
+                for (i, (code, data)) in options.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(' ')?;
+                    }
+                    write!(f, "{}:", code)?;
+                    for byte in data {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
+            // Generic format of RFC 3597
+            RData::Unknown(bytes) => {
+                write!(f, "\\# {}", bytes.len())?;
+                if !bytes.is_empty() {
+                    f.write_char(' ')?;
+                    for byte in bytes {
+                        write!(f, "{:02x}", byte)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// EDNS information carried by an OPT pseudo-record, see RFC 6891
+///
+/// The fields of a normal record are reused for other purposes in an OPT
+/// record: its class holds the largest UDP payload the sender can take, and
+/// its TTL holds the EDNS version and flags
+pub struct Edns {
+    /// Largest UDP payload the server can take
+    udp_size: u16,
+    /// Version of EDNS
+    version: u8,
+    /// Whether the DO bit is set, asking for DNSSEC records
+    dnssec_ok: bool,
+}
+
+impl Edns {
+    /// Largest UDP payload the sender can take
+    pub fn udp_size(&self) -> u16 {
+        self.udp_size
+    }
+
+    /// Whether the sender asks for DNSSEC records, with the DO bit
+    pub fn dnssec_ok(&self) -> bool {
+        self.dnssec_ok
+    }
+}
+
+impl Display for Edns {
+    /// Show the EDNS information the way dig does
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "; EDNS: version: {}, flags:{}; udp: {}",
+            self.version,
+            if self.dnssec_ok { " do" } else { "" },
+            self.udp_size
+        )
     }
 }
 
 /// A struct which represents one RR
-struct ResourceRecord {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceRecord {
+    /// Name of the node the record belongs to
+    pub name: Name,
     /// Denotes the record type
     ///
-    /// It is similar to [Query::qtype]
-    rtype: u16, // same as in Query
+    /// It is similar to [Question::qtype]
+    pub rtype: RecordType,
     /// Denotes the class of the record
     ///
-    /// It is similar to [Query::qclass]
-    class: u16, // same as in Query
+    /// It is similar to [Question::qclass]
+    pub class: u16,
     /// The TTL denotes the amount of time in seconds we can cache the result
     ///
     /// After the TTL expires, we have to make a fresh request since this
     /// answer is not guaranteed to be correct
-    ttl: u32, // number of seconds to cache the result
-    /// Denotes the length of data
-    ///
-    /// For this implementation we only request IPv4 addresses, so its value
-    /// will be 4.
-    rdlength: u16, // Length of RDATA
-    /// The actual answer we need
-    ///
-    /// It is an IPv4 address for us in this case
-    rdata: [u8; 4], // IP address
+    pub ttl: u32,
+    /// The actual answer we need, decoded according to [ResourceRecord::rtype]
+    pub rdata: RData,
 }
 
-impl Len for ResourceRecord {
-    // return number of bytes it consumes
-    fn len(&self) -> usize {
-        let mut size = 0;
-        size += 2; // name, even though we don't store it here
-        size += 2; // rtype
-        size += 2; // class
-        size += 4; // ttl
-        size += 2; // rdlength
-        size += 4; // rdata
-        size
+impl ResourceRecord {
+    /// Decode the record `cursor` is at, and move the cursor past it
+    ///
+    /// Unlike the other structs, a record can't be decoded from its own
+    /// bytes since its names may point to other parts of the message
+    fn decode(cursor: &mut Cursor<'_>) -> Result<Self> {
+        let name = Name::decode(cursor)?;
+        // These offsets were determined by looking at RFC 1035
+        let rtype = RecordType::from(cursor.read_u16()?);
+        let class = cursor.read_u16()?;
+        let ttl = cursor.read_u32()?;
+        let rdlength = cursor.read_u16()?;
+        let mut rdata = cursor.take(rdlength as usize)?;
+        Ok(Self {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata: RData::decode(rtype, &mut rdata)?,
+        })
+    }
+
+    /// Append the record to the message `v`, compressing its names
+    fn compress_into(&self, v: &mut Vec<u8>, compressor: &mut Compressor) {
+        self.name.compress_into(v, compressor);
+        v.extend_from_slice(&u16::to_be_bytes(self.rtype.into()));
+        v.extend_from_slice(&u16::to_be_bytes(self.class));
+        v.extend_from_slice(&u32::to_be_bytes(self.ttl));
+        // The length is only known once the RDATA has been compressed
+        let rdlength = v.len();
+        v.extend_from_slice(&[0, 0]);
+        self.rdata.compress_into(v, compressor);
+        let len = (v.len() - rdlength - 2) as u16;
+        v[rdlength..rdlength + 2].copy_from_slice(&len.to_be_bytes());
     }
 }
 
-impl FromBytes for ResourceRecord {
-    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>> {
-        let lastnamebyte = 1;
-        let mut rdata = [0u8; 4];
-        if bytes.len() < 15 {
-            return Err(FromBytesError.into());
-        }
-        // Copy over IP address into rdata
-        rdata.copy_from_slice(&bytes[lastnamebyte + 10..lastnamebyte + 14]);
-        // These offsets were determined by looking at RFC 1035
-        Ok(Box::new(Self {
-            rtype: ResourceRecord::u8_to_u16(bytes[lastnamebyte], bytes[lastnamebyte + 1]),
-            class: ResourceRecord::u8_to_u16(bytes[lastnamebyte + 2], bytes[lastnamebyte + 3]),
-            ttl: ResourceRecord::u8_to_u32(&bytes[lastnamebyte + 4..lastnamebyte + 8])?,
-            rdlength: Response::u8_to_u16(bytes[lastnamebyte + 8], bytes[lastnamebyte + 9]),
-            rdata,
-        }))
+impl AsBytes for ResourceRecord {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut v = self.name.encode();
+        v.extend_from_slice(&u16::to_be_bytes(self.rtype.into()));
+        v.extend_from_slice(&u16::to_be_bytes(self.class));
+        v.extend_from_slice(&u32::to_be_bytes(self.ttl));
+        let rdata = self.rdata.encode();
+        v.extend_from_slice(&u16::to_be_bytes(rdata.len() as u16));
+        v.extend(rdata);
+        v
     }
 }
 
 impl Display for ResourceRecord {
+    /// Show the record like a line of a zone file
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "RR record type: 0x{:x}", self.rtype)?;
-        writeln!(f, "RR class: 0x{:x}", self.class)?;
-        writeln!(f, "TTL: {}", self.ttl)?;
-        writeln!(f, "RDLENGTH: 0x{:x}", self.rdlength)?;
-        writeln!(
+        write!(
             f,
-            "IP address: {}.{}.{}.{}",
-            self.rdata[0], self.rdata[1], self.rdata[2], self.rdata[3]
-        )?;
-        Ok(())
+            "{}\t{}\t{}\t{}\t{}",
+            self.name,
+            self.ttl,
+            class_name(self.class),
+            self.rtype,
+            self.rdata
+        )
     }
 }
 
-/// Stores the response in easy to interpret manner
+/// A DNS message, see RFC 1035 section 4.1
 ///
-/// A Response is made up of the query given to the server and a bunch of
-/// Resource Records (RR). Each RR will include the resource type, class, and
-/// name. For the A records we're requesting, we will get an A record, of Internet class,
-/// ie an IPv4 address
-pub struct Response {
-    /// The Query part of the response we obtain from the server
-    query: Query,
-    /// A collection of resource records all parsed neatly and kept separately
-    /// for easy iteration
-    rr: Vec<ResourceRecord>,
-}
-
-impl FromBytes for Response {
-    // Try to construct Response from raw byte data from network
-    // We will also try to check if a valid DNS response has been sent back to us
-    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>> {
-        debug!("Parsing response into struct");
-        // Check message length
-        let l = bytes.len();
-        let messagelen = Response::u8_to_u16(bytes[0], bytes[1]);
-        if messagelen == (l - 2) as u16 {
-            debug!("Appear to have gotten good message from server");
-        } else {
-            error!(
-                "Expected and observed message length don't match: {} and {} respectively",
-                l - 2,
-                messagelen
-            );
+/// Queries and responses are laid out in the same way: a header followed by
+/// four sections. The counts of the header aren't kept around, they are
+/// those of the sections when encoding the message.
+///
+/// Messages are encoded and decoded on their own, without the length prefix
+/// used over TCP, so that the same types can be used whatever the transport.
+/// See [crate::transport] for the framing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Random 16 bit number used to match responses with queries
+    pub id: u16,
+    /// Flags of the message, including the RCODE of responses
+    pub flags: Flags,
+    /// Questions being asked, usually a single one
+    pub questions: Vec<Question>,
+    /// Records answering the question
+    pub answers: Vec<ResourceRecord>,
+    /// Records pointing to the authority of the domain, like the NS records
+    /// of a referral or the SOA record of a negative answer
+    pub authority: Vec<ResourceRecord>,
+    /// Records which may help, like the addresses of name servers (glue) or
+    /// the OPT pseudo-record
+    pub additional: Vec<ResourceRecord>,
+}
+
+/// Decode the `count` records `cursor` is at, and move the cursor past them
+fn decode_section(cursor: &mut Cursor<'_>, count: u16) -> Result<Vec<ResourceRecord>> {
+    // The count comes from the network, so don't trust it to size the vector
+    let mut records = Vec::new();
+    for _ in 0..count {
+        records.push(ResourceRecord::decode(cursor)?);
+    }
+    Ok(records)
+}
+
+impl Message {
+    /// Create a query asking `question`, with recursion desired and a random ID
+    pub fn query(question: Question) -> Self {
+        Self {
+            // Random so that it can't be guessed by someone trying to forge a response
+            id: rand::random(),
+            flags: Flags {
+                rd: true,
+                ..Default::default()
+            },
+            questions: vec![question],
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
         }
-        // Start index at 2 to skip over message length bytes
-        let mut index = 2;
-        let query = *Query::from_bytes(&bytes[index..])?;
-        index += query.len() + 2; // TODO: needs explanation why it works
-        let mut rrvec: Vec<ResourceRecord> = Vec::new();
-        while index < l {
-            match ResourceRecord::from_bytes(&bytes[index..]) {
-                Ok(rr) => {
-                    index += rr.len();
-                    rrvec.push(*rr);
-                }
-                Err(_) => break,
+    }
+
+    /// The first question of the message, which is the only one in practice
+    pub fn question(&self) -> Option<&Question> {
+        self.questions.first()
+    }
+
+    /// Result of the query, as told by the server
+    pub fn rcode(&self) -> Rcode {
+        self.flags.rcode
+    }
+
+    /// EDNS information of the message, from its OPT pseudo-record
+    pub fn edns(&self) -> Option<Edns> {
+        self.additional
+            .iter()
+            .find(|record| record.rtype == RecordType::Opt)
+            .map(|record| Edns {
+                udp_size: record.class,
+                version: (record.ttl >> 16) as u8,
+                dnssec_ok: record.ttl & 0x8000 != 0,
+            })
+    }
+
+    /// Randomize the case of the names we ask about, see [Name::randomize_case()]
+    pub fn randomize_case(&mut self) {
+        for question in self.questions.iter_mut() {
+            question.qname.randomize_case();
+        }
+    }
+
+    /// Check that this is a response to `query`
+    ///
+    /// The ID has to be the same, and the questions have to be echoed back
+    /// exactly, down to the case of the names
+    pub fn check_matches(&self, query: &Message) -> Result<(), MismatchError> {
+        if !self.flags.qr || self.flags.opcode != query.flags.opcode {
+            return Err(MismatchError::NotResponse);
+        }
+        if query.id != self.id {
+            return Err(MismatchError::Id {
+                expected: query.id,
+                got: self.id,
+            });
+        }
+        if query.questions.len() != self.questions.len() {
+            return Err(MismatchError::QuestionCount {
+                expected: query.questions.len(),
+                got: self.questions.len(),
+            });
+        }
+        for (expected, got) in query.questions.iter().zip(self.questions.iter()) {
+            if expected.qname != got.qname {
+                return Err(MismatchError::Name {
+                    expected: expected.qname.clone(),
+                    got: got.qname.clone(),
+                });
             }
+            if expected.qtype != got.qtype {
+                return Err(MismatchError::Type {
+                    expected: expected.qtype,
+                    got: got.qtype,
+                });
+            }
+            if expected.qclass != got.qclass {
+                return Err(MismatchError::Class {
+                    expected: expected.qclass,
+                    got: got.qclass,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the message `cursor` is at
+    fn decode(cursor: &mut Cursor<'_>) -> Result<Self> {
+        let header = Header::decode(cursor)?;
+        // The sections follow the header in order, each holding as many
+        // entries as the header says
+        let mut questions = Vec::new();
+        for _ in 0..header.qdcount {
+            questions.push(Question::decode(cursor)?);
         }
-        Ok(Box::new(Response { query, rr: rrvec }))
+        let answers = decode_section(cursor, header.ancount)?;
+        let authority = decode_section(cursor, header.nscount)?;
+        let additional = decode_section(cursor, header.arcount)?;
+        if !cursor.is_empty() {
+            warn!(
+                "Ignoring {} bytes after the last record",
+                cursor.remaining()
+            );
+        }
+        Ok(Self {
+            id: header.identification,
+            flags: header.flags,
+            questions,
+            answers,
+            authority,
+            additional,
+        })
     }
 }
 
-impl Display for Response {
+impl AsBytes for Message {
+    fn as_bytes(&self) -> Vec<u8> {
+        let header = Header {
+            identification: self.id,
+            flags: self.flags,
+            qdcount: self.questions.len() as u16,
+            ancount: self.answers.len() as u16,
+            nscount: self.authority.len() as u16,
+            arcount: self.additional.len() as u16,
+        };
+        let mut v = header.as_bytes();
+        let mut compressor = Compressor::default();
+        for question in self.questions.iter() {
+            question.compress_into(&mut v, &mut compressor);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter())
+        {
+            record.compress_into(&mut v, &mut compressor);
+        }
+        v
+    }
+}
+
+impl FromBytes for Message {
+    fn from_bytes(bytes: &[u8]) -> Result<Box<Self>> {
+        debug!("Parsing message into struct");
+        Ok(Box::new(Message::decode(&mut Cursor::new(bytes))?))
+    }
+}
+
+impl Display for Message {
+    /// Show the message the way dig does
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{}", self.query.header)?;
         writeln!(
             f,
-            "Name: {}",
-            String::from_utf8(self.query.qname.to_owned()).unwrap()
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            self.flags.opcode, self.flags.rcode, self.id
         )?;
-        writeln!(f, "Res type: 0x{:x}", self.query.qtype)?;
-        writeln!(f, "Class: 0x{:x}", self.query.qclass)?;
-        for record in self.rr.iter() {
+        writeln!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.flags,
+            self.questions.len(),
+            self.answers.len(),
+            self.authority.len(),
+            self.additional.len()
+        )?;
+        writeln!(f)?;
+        // The OPT pseudo-record isn't a real record, dig shows it on its own
+        if let Some(opt) = self.edns() {
+            writeln!(f, ";; OPT PSEUDOSECTION:")?;
+            writeln!(f, "{}", opt)?;
+        }
+        writeln!(f, ";; QUESTION SECTION:")?;
+        for question in self.questions.iter() {
+            writeln!(f, "{}", question)?;
+        }
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authority),
+            ("ADDITIONAL", &self.additional),
+        ];
+        for (title, records) in sections {
+            let mut records = records
+                .iter()
+                .filter(|record| record.rtype != RecordType::Opt)
+                .peekable();
+            if records.peek().is_none() {
+                continue;
+            }
             writeln!(f)?;
-            writeln!(f, "{}", record)?;
+            writeln!(f, ";; {} SECTION:", title)?;
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
         }
         Ok(())
     }
 }
 
-/// Craft the actual query for a particular domain and returns a Query object
+/// Craft the actual query for a particular domain
 ///
-/// The query is made for an A record of type Internet, ie, a normal IPv4 address
-/// should be returned from the DNS server.
+/// The query is made for records of type `qtype` in the Internet class, for
+/// example [RecordType::A] to get a normal IPv4 address back from the DNS server.
 ///
-/// Convert this Query into bytes to be sent over the network by calling [Query::as_bytes()]
-pub fn build_query(domain: &str) -> Result<Query, DomainError> {
-    // TODO: generate identification randomly
-    let header = Header {
-        identification: 0x304e, // chosen by random dice roll, secure
-        packed_second_row: 0x0100,
-        qdcount: 0x0001,
-        ancount: 0x0000,
-        nscount: 0x0000,
-        arcount: 0x0000,
-    };
-    let mut qname: Vec<u8> = Vec::new();
-    let split_domain: Vec<&str> = domain.split('.').collect();
-    for part in split_domain {
-        if part.is_empty() {
-            return Err(DomainError);
+/// Convert the query into bytes to be sent over the network by calling
+/// [Message::as_bytes()], or [crate::transport::encode_tcp()] for TCP
+pub fn build_query(domain: &str, qtype: RecordType) -> Result<Message, DomainError> {
+    let qname = Name::from_str(domain)?;
+    debug!("Crafted query successfully!");
+    Ok(Message::query(Question::new(qname, qtype)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Any name which fits on the wire
+    fn name() -> impl Strategy<Value = Name> {
+        prop::collection::vec(prop::collection::vec(any::<u8>(), 1..=MAX_LABEL_LEN), 0..5)
+            .prop_map(|labels| Name { labels })
+            .prop_filter("name too long", |name| name.wire_len() <= MAX_NAME_LEN)
+    }
+
+    /// A hostname which can be typed on the command line
+    fn hostname() -> impl Strategy<Value = String> {
+        prop::collection::vec("[a-zA-Z0-9-]{1,20}", 1..5).prop_map(|labels| labels.join("."))
+    }
+
+    /// RDATA of any of the types we decode, along with its type
+    fn rdata() -> impl Strategy<Value = (RecordType, RData)> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(|a| (RecordType::A, RData::A(a.into()))),
+            any::<[u8; 16]>().prop_map(|a| (RecordType::Aaaa, RData::Aaaa(a.into()))),
+            name().prop_map(|n| (RecordType::Cname, RData::Cname(n))),
+            name().prop_map(|n| (RecordType::Ns, RData::Ns(n))),
+            name().prop_map(|n| (RecordType::Ptr, RData::Ptr(n))),
+            (any::<u16>(), name()).prop_map(|(preference, exchange)| (
+                RecordType::Mx,
+                RData::Mx {
+                    preference,
+                    exchange
+                }
+            )),
+            prop::collection::vec(prop::collection::vec(any::<u8>(), 0..=255), 0..4)
+                .prop_map(|strings| (RecordType::Txt, RData::Txt(strings))),
+            (name(), name(), any::<[u32; 5]>()).prop_map(|(mname, rname, numbers)| (
+                RecordType::Soa,
+                RData::Soa {
+                    mname,
+                    rname,
+                    serial: numbers[0],
+                    refresh: numbers[1],
+                    retry: numbers[2],
+                    expire: numbers[3],
+                    minimum: numbers[4],
+                }
+            )),
+            (any::<[u16; 3]>(), name()).prop_map(|(numbers, target)| (
+                RecordType::Srv,
+                RData::Srv {
+                    priority: numbers[0],
+                    weight: numbers[1],
+                    port: numbers[2],
+                    target,
+                }
+            )),
+            (
+                any::<u8>(),
+                "[a-z0-9]{1,15}",
+                prop::collection::vec(any::<u8>(), 0..64)
+            )
+                .prop_map(|(flags, tag, value)| (
+                    RecordType::Caa,
+                    RData::Caa { flags, tag, value }
+                )),
+            prop::collection::vec(
+                (any::<u16>(), prop::collection::vec(any::<u8>(), 0..32)),
+                0..4
+            )
+            .prop_map(|options| (RecordType::Opt, RData::Opt(options))),
+            (300u16.., prop::collection::vec(any::<u8>(), 0..64))
+                .prop_map(|(rtype, bytes)| (RecordType::Other(rtype), RData::Unknown(bytes))),
+        ]
+    }
+
+    /// A record of any of the types we decode
+    fn record() -> impl Strategy<Value = ResourceRecord> {
+        (name(), rdata(), any::<u16>(), any::<u32>()).prop_map(
+            |(name, (rtype, rdata), class, ttl)| ResourceRecord {
+                name,
+                rtype,
+                class,
+                ttl,
+                rdata,
+            },
+        )
+    }
+
+    /// Any question
+    fn question() -> impl Strategy<Value = Question> {
+        (name(), any::<u16>(), any::<u16>()).prop_map(|(qname, qtype, qclass)| Question {
+            qname,
+            qtype: qtype.into(),
+            qclass,
+        })
+    }
+
+    /// Any message made of the types we decode
+    fn message() -> impl Strategy<Value = Message> {
+        (
+            any::<u16>(),
+            any::<u16>(),
+            prop::collection::vec(question(), 0..3),
+            prop::collection::vec(record(), 0..3),
+            prop::collection::vec(record(), 0..3),
+            prop::collection::vec(record(), 0..3),
+        )
+            .prop_map(
+                |(id, flags, questions, answers, authority, additional)| Message {
+                    id,
+                    flags: flags.into(),
+                    questions,
+                    answers,
+                    authority,
+                    additional,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn name_round_trip(name in name()) {
+            let encoded = name.encode();
+            prop_assert_eq!(encoded.len(), name.wire_len());
+            let mut cursor = Cursor::new(&encoded);
+            prop_assert_eq!(Name::decode(&mut cursor).unwrap(), name);
+            prop_assert!(cursor.is_empty());
         }
-        let l = part.len() as u8;
-        if l != 0 {
-            qname.push(l);
-            qname.extend_from_slice(part.as_bytes());
+
+        #[test]
+        fn hostname_round_trip(hostname in hostname()) {
+            let name = Name::from_str(&hostname).unwrap();
+            prop_assert_eq!(name.to_string(), format!("{}.", hostname));
+        }
+
+        #[test]
+        fn rdata_round_trip((rtype, rdata) in rdata()) {
+            let encoded = rdata.encode();
+            let mut cursor = Cursor::new(&encoded);
+            prop_assert_eq!(RData::decode(rtype, &mut cursor).unwrap(), rdata);
+        }
+
+        #[test]
+        fn query_round_trip(hostname in hostname(), qtype in any::<u16>()) {
+            let query = build_query(&hostname, qtype.into()).unwrap();
+            let decoded = Message::from_bytes(&query.as_bytes()).unwrap();
+            prop_assert_eq!(*decoded, query);
+        }
+
+        #[test]
+        fn message_round_trip(message in message()) {
+            let decoded = Message::from_bytes(&message.as_bytes()).unwrap();
+            prop_assert_eq!(*decoded, message);
+        }
+
+        #[test]
+        fn flags_round_trip(bits in any::<u16>()) {
+            prop_assert_eq!(u16::from(Flags::from(bits)), bits);
+        }
+
+        #[test]
+        fn garbage_does_not_panic(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Message::from_bytes(&bytes);
+        }
+
+        #[test]
+        fn damaged_message_does_not_panic(
+            message in message(),
+            damage in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            truncate in any::<prop::sample::Index>(),
+        ) {
+            let mut encoded = message.as_bytes();
+            for (index, byte) in damage {
+                let index = index.index(encoded.len());
+                encoded[index] = byte;
+            }
+            encoded.truncate(truncate.index(encoded.len() + 1));
+            let _ = Message::from_bytes(&encoded);
         }
     }
-    qname.push(0x00); // Denote that hostname has ended by pushing 0x00
-    debug!("Crafted query successfully!");
-    Ok(Query {
-        header,
-        qname,
-        qtype: QTYPE,
-        qclass: QCLASS,
-    })
+
+    #[test]
+    fn pointer_loops_are_rejected() {
+        // A name made of a label followed by a pointer back to that label
+        let message = [3, b'f', b'o', b'o', 0xc0, 0x00];
+        assert!(Name::decode(&mut Cursor::new(&message)).is_err());
+        // A pointer to itself
+        let message = [0xc0, 0x00];
+        assert!(Name::decode(&mut Cursor::new(&message)).is_err());
+    }
+
+    #[test]
+    fn names_inside_rdata_stay_inside_rdata() {
+        // A CNAME whose RDATA is cut in the middle of its name
+        let message = [3, b'f', b'o', b'o', 0];
+        let mut rdata = Cursor::new(&message).take(2).unwrap();
+        assert!(RData::decode(RecordType::Cname, &mut rdata).is_err());
+    }
+
+    #[test]
+    fn repeated_names_are_compressed() {
+        let name = |name: &str| Name::from_str(name).unwrap();
+        let record = |owner: &str, rtype, rdata| ResourceRecord {
+            name: name(owner),
+            rtype,
+            class: QCLASS,
+            ttl: 300,
+            rdata,
+        };
+        let srv = RData::Srv {
+            priority: 0,
+            weight: 0,
+            port: 443,
+            target: name("www.example.com"),
+        };
+        let message = Message {
+            id: 1,
+            flags: Flags::default(),
+            questions: vec![Question::new(name("wWw.ExAmple.com"), RecordType::A)],
+            answers: vec![
+                record(
+                    "wWw.ExAmple.com",
+                    RecordType::Cname,
+                    RData::Cname(name("www.example.com")),
+                ),
+                record(
+                    "www.example.com",
+                    RecordType::A,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                ),
+                record("_https._tcp.example.com", RecordType::Srv, srv.clone()),
+            ],
+            authority: vec![],
+            additional: vec![],
+        };
+        let encoded = message.as_bytes();
+        assert_eq!(*Message::from_bytes(&encoded).unwrap(), message);
+        let uncompressed = 12
+            + message.questions[0].as_bytes().len()
+            + message
+                .answers
+                .iter()
+                .map(|record| record.as_bytes().len())
+                .sum::<usize>();
+        assert!(encoded.len() < uncompressed);
+        // Names in SRV records are never compressed
+        assert!(encoded.ends_with(&srv.encode()));
+    }
 }
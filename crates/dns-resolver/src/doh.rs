@@ -0,0 +1,330 @@
+//! DNS over HTTPS, as described in RFC 8484
+//!
+//! Some resolvers can only be reached, or only answer truthfully, over HTTPS.
+//! Queries are sent to them in wire format, either base64url encoded in the
+//! `dns` parameter of a GET request, or as the body of a POST request with
+//! the `application/dns-message` content type. GET requests are the default,
+//! as their responses can be cached by HTTP caches along the way.
+//!
+//! The streams to these servers are set up like for DNS over TLS, and the
+//! same [HttpConnection] is then used for all the queries to a server, for as
+//! long as the server keeps it open. HTTP/1.1 is used, so queries to a server
+//! are sent one after the other rather than all at once.
+use crate::dns::{AsBytes, FromBytes, Message};
+use crate::upstream::Server;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::header::{ACCEPT, CONTENT_TYPE, HOST};
+use hyper::{Body, Request, StatusCode};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+/// Port DNS over HTTPS servers listen on
+pub const DOH_PORT: u16 = 443;
+
+/// Path of the queries, for servers given without one
+pub const DEFAULT_PATH: &str = "/dns-query";
+
+/// Media type of DNS messages
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest response accepted, the same as over TCP
+const MAX_RESPONSE_LEN: usize = u16::MAX as usize;
+
+/// Error returned when a query over HTTPS fails
+#[derive(Error, Debug)]
+pub enum DohError {
+    /// The request method isn't one RFC 8484 allows
+    #[error("unknown method {0:?}, expected GET or POST")]
+    UnknownMethod(String),
+    /// The server didn't answer with a 2xx status
+    #[error("server answered with HTTP status {0}")]
+    Status(StatusCode),
+    /// The server answered with something else than a DNS message
+    #[error("server answered with content type {0:?} instead of {DNS_MESSAGE}")]
+    ContentType(String),
+    /// The response is larger than any DNS message can be
+    #[error("response is larger than {MAX_RESPONSE_LEN} bytes")]
+    TooLarge,
+    /// The HTTP connection failed
+    #[error("HTTP connection failed: {0}")]
+    Http(#[from] hyper::Error),
+}
+
+/// How queries are sent to DNS over HTTPS servers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Method {
+    /// In the `dns` parameter of a GET request, base64url encoded
+    #[default]
+    Get,
+    /// As the body of a POST request
+    Post,
+}
+
+impl FromStr for Method {
+    type Err = DohError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Ok(Method::Get),
+            "POST" => Ok(Method::Post),
+            _ => Err(DohError::UnknownMethod(s.to_string())),
+        }
+    }
+}
+
+/// Build the HTTP request carrying `query` to `server`
+///
+/// The `Host` header names the authentication name of the server when it has
+/// one, as that is the name the server knows itself by and the one its
+/// certificate was checked against, rather than the address we connect to
+fn build_request(
+    server: &Server,
+    query: &Message,
+    method: Method,
+) -> anyhow::Result<Request<Body>> {
+    let path = server.path.as_deref().unwrap_or(DEFAULT_PATH);
+    let name = server.auth_name.as_deref().unwrap_or(&server.host);
+    let host = if name.contains(':') {
+        format!("[{}]", name)
+    } else {
+        name.to_string()
+    };
+    let authority = if server.port == DOH_PORT {
+        host
+    } else {
+        format!("{}:{}", host, server.port)
+    };
+    let builder = Request::builder()
+        .header(HOST, authority)
+        .header(ACCEPT, DNS_MESSAGE);
+    let request = match method {
+        Method::Get => {
+            let dns = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(query.as_bytes());
+            let separator = if path.contains('?') { '&' } else { '?' };
+            builder
+                .method(hyper::Method::GET)
+                .uri(format!("{}{}dns={}", path, separator, dns))
+                .body(Body::empty())?
+        }
+        Method::Post => builder
+            .method(hyper::Method::POST)
+            .uri(path)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Body::from(query.as_bytes()))?,
+    };
+    Ok(request)
+}
+
+/// An HTTP connection to a DNS over HTTPS server
+pub struct HttpConnection {
+    /// Sends the requests, one at a time
+    sender: tokio::sync::Mutex<SendRequest<Body>>,
+    /// Set once the connection was closed
+    closed: Arc<AtomicBool>,
+}
+
+impl HttpConnection {
+    /// Start speaking HTTP over `stream`
+    pub async fn new<S>(stream: S) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, connection) = http1::handshake(stream).await?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let done = closed.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("HTTP connection failed: {}", e);
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        Ok(Self {
+            sender: tokio::sync::Mutex::new(sender),
+            closed,
+        })
+    }
+
+    /// Whether the connection was closed, in which case a new one is needed
+    /// for further queries
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Send `query` to `server` with `method`, and wait for its response
+    pub async fn query(
+        &self,
+        server: &Server,
+        query: &Message,
+        method: Method,
+    ) -> anyhow::Result<Message> {
+        // The response is tied to the request by HTTP, so the ID is set to 0
+        // to let HTTP caches reuse responses, see RFC 8484 section 4.1
+        let mut query = query.clone();
+        query.id = 0;
+        let request = build_request(server, &query, method)?;
+        let response = {
+            let mut sender = self.sender.lock().await;
+            sender.ready().await.map_err(DohError::from)?;
+            sender.send_request(request)
+        };
+        let response = response.await.map_err(DohError::from)?;
+        if !response.status().is_success() {
+            return Err(DohError::Status(response.status()).into());
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .unwrap_or_default();
+        // Parameters like a charset don't mean anything for binary messages
+        if content_type.split(';').next().map(str::trim) != Some(DNS_MESSAGE) {
+            return Err(DohError::ContentType(content_type).into());
+        }
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(DohError::from)?;
+            if bytes.len() + chunk.len() > MAX_RESPONSE_LEN {
+                return Err(DohError::TooLarge.into());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let message = *Message::from_bytes(&bytes)?;
+        message.check_matches(&query)?;
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, RecordType};
+    use crate::transport::testing::response_to;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use tokio::io::DuplexStream;
+
+    /// Answer a request like a DNS over HTTPS server at [DEFAULT_PATH] would,
+    /// with an empty response
+    async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let status = |status: StatusCode| {
+            Ok(Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap())
+        };
+        match request.uri().path() {
+            DEFAULT_PATH => (),
+            "/text" => return Ok(Response::new(Body::from("not dns"))),
+            _ => return status(StatusCode::NOT_FOUND),
+        }
+        let query = match *request.method() {
+            hyper::Method::GET => {
+                let query = request.uri().query().unwrap_or_default();
+                let Some(dns) = query.strip_prefix("dns=") else {
+                    return status(StatusCode::BAD_REQUEST);
+                };
+                base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(dns)
+                    .unwrap()
+            }
+            hyper::Method::POST => {
+                if request.headers().get(CONTENT_TYPE).unwrap() != DNS_MESSAGE {
+                    return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+                hyper::body::to_bytes(request.into_body())
+                    .await
+                    .unwrap()
+                    .to_vec()
+            }
+            _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+        let response = response_to(&Message::from_bytes(&query).unwrap());
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Body::from(response.as_bytes()))
+            .unwrap())
+    }
+
+    /// Run a DNS over HTTPS server, without the TLS, on the other end of the
+    /// returned stream
+    fn serve() -> DuplexStream {
+        let (client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(
+            hyper::server::conn::Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service_fn(handle)),
+        );
+        client
+    }
+
+    /// Query the stand-in server through `url`
+    async fn query(url: &str, method: Method) -> anyhow::Result<Message> {
+        let connection = HttpConnection::new(serve()).await?;
+        let query = build_query("example.com", RecordType::A)?;
+        connection.query(&url.parse()?, &query, method).await
+    }
+
+    #[test]
+    fn requests_are_sent_to_the_authentication_name() {
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let host = |server: &str| {
+            let request = build_request(&server.parse().unwrap(), &query, Method::Get).unwrap();
+            request.headers()[HOST].to_str().unwrap().to_string()
+        };
+        assert_eq!(host("https://dns.test"), "dns.test");
+        assert_eq!(host("https://dns.test:8443"), "dns.test:8443");
+        assert_eq!(host("https://[2606:4700::1111]"), "[2606:4700::1111]");
+        assert_eq!(host("https://9.9.9.9#dns.quad9.net"), "dns.quad9.net");
+        assert_eq!(host("https://[::1]:8443#dns.test"), "dns.test:8443");
+    }
+
+    #[tokio::test]
+    async fn queries_are_sent_with_get_and_post() {
+        for method in [Method::Get, Method::Post] {
+            let response = query("https://dns.test/dns-query", method).await.unwrap();
+            assert!(response.flags.qr);
+            assert_eq!(response.id, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let connection = HttpConnection::new(serve()).await.unwrap();
+        let server = "https://dns.test".parse().unwrap();
+        for name in ["a.example", "b.example", "c.example"] {
+            let query = build_query(name, RecordType::A).unwrap();
+            let response = connection
+                .query(&server, &query, Method::Get)
+                .await
+                .unwrap();
+            assert_eq!(response.question(), query.question());
+        }
+        assert!(!connection.is_closed());
+    }
+
+    #[tokio::test]
+    async fn http_errors_are_reported() {
+        let error = query("https://dns.test/missing", Method::Get)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DohError>(),
+            Some(DohError::Status(StatusCode::NOT_FOUND))
+        ));
+        let error = query("https://dns.test/text", Method::Post)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DohError>(),
+            Some(DohError::ContentType(_))
+        ));
+    }
+}
@@ -0,0 +1,16 @@
+#![warn(clippy::missing_docs_in_private_items)]
+//! # dns-resolver
+//! The toy DNS implementation used by the `dns-resolver` program
+//!
+//! It lives in a library so that the parser can be fuzzed, the fuzz targets
+//! are in the `fuzz` directory and can be run with
+//! [cargo-fuzz](https://github.com/rust-fuzz/cargo-fuzz):
+//!
+//! `cargo +nightly fuzz run message`
+pub mod cache;
+pub mod dns;
+pub mod doh;
+pub mod server;
+pub mod tls;
+pub mod transport;
+pub mod upstream;
@@ -12,10 +12,98 @@
 //! `cargo run <hostname-to-look-up>`
 //!
 //! The program will then attempt to create a new Tor connection, craft the DNS
-//! query, and send it to a DNS server (right now, Cloudflare's 1.1.1.1)
+//! query, and send it to a DNS server (Cloudflare's 1.1.1.1 by default)
 //!
 //! The response is then decoded into a struct and pretty printed to the user
 //!
+//! ### Record types
+//! A records are looked up by default, some other type can be asked for with
+//! `--type`, for example:
+//!
+//! `cargo run -- --type MX torproject.org`
+//!
+//! A, AAAA, CNAME, MX, NS, TXT, SOA, SRV, PTR and CAA records are decoded and
+//! shown in the same format as dig. Other types can be asked for by number,
+//! like `--type TYPE65`, and are shown as raw bytes.
+//!
+//! ### Several lookups at once
+//! Several hostnames and types can be given, for example to get both the IPv4
+//! and IPv6 addresses of two hosts:
+//!
+//! `cargo run -- --type A --type AAAA torproject.org example.com`
+//!
+//! All the queries are sent right away over a single Tor stream, without
+//! waiting for the responses of the previous ones, as described in RFC 7766.
+//! The server may answer them in any order, each response is matched with its
+//! query by ID. The stream is closed once no query has been in flight for
+//! `--idle-timeout` seconds.
+//!
+//! ### Servers
+//! Other servers can be used with `--server`, given as `host:port` or just
+//! `host` for port 53. It can be repeated, for example:
+//!
+//! `cargo run -- --server 9.9.9.9 --server 1.1.1.1 torproject.org`
+//!
+//! Each attempt at a query has `--timeout` seconds to complete, and is
+//! retried `--retries` times before moving on to the next server. By default
+//! the servers are tried one after the other. With `--race 2`, each query is
+//! sent to the first two servers at once instead, and whichever answers first
+//! wins. Servers which failed several times in a row are only tried after
+//! the others. The server which answered is shown after each response.
+//!
+//! ### DNS over TLS
+//! Over plain DNS, the exit relay sees every query and response. Servers
+//! prefixed with `tls://` are queried over TLS instead, on port 853 by
+//! default, so that only the server can read them. The name the certificate
+//! has to be valid for goes after a `#`, and is needed for servers given by
+//! IP address:
+//!
+//! `cargo run -- --server tls://1.1.1.1#one.one.one.one torproject.org`
+//!
+//! That name is sent as SNI, unless `--no-sni` is given. Extra CA
+//! certificates can be trusted with `--ca-cert <file.pem>`, and the key of a
+//! server can be pinned with `--pin <name>=sha256/<base64 hash>`, see
+//! [TlsConfig::pin()] for how to compute the hash.
+//!
+//! ### DNS over HTTPS
+//! Servers given as an `https://` URL are queried over HTTPS, as in RFC 8484,
+//! which also gets through networks which only let web traffic out:
+//!
+//! `cargo run -- --server https://dns.quad9.net/dns-query torproject.org`
+//!
+//! The path defaults to `/dns-query`. Queries are sent in GET requests unless
+//! `--doh-method POST` is given. The TLS options above apply to these
+//! servers too.
+//!
+//! ### Forged responses
+//! Each query gets a random ID, and a response is only accepted if it has the
+//! same ID and repeats the question exactly. With `--randomize-case`, the
+//! letters of the hostname are also made upper or lower case at random, as
+//! in draft-vixie-dnsext-dns0x20, and the server has to echo them back
+//! unchanged. This makes forging a response much harder, but a few servers
+//! don't preserve the case, and their responses get rejected.
+//!
+//! ### Cache
+//! Responses are cached for as long as their TTL allows, and negative
+//! responses for as long as the SOA record that comes with them allows, so
+//! asking again doesn't go over Tor. The cache keeps up to `--cache-size`
+//! responses, 0 turns it off. With `--serve-stale`, expired responses are
+//! kept for a day, or the number of seconds given, and used when no server
+//! answers, as in RFC 8767.
+//!
+//! ### Stub resolver
+//! With `--listen`, no hostname is looked up. Instead, a stub resolver listens
+//! on 127.0.0.1:5353 over UDP and TCP, or on the address given, and forwards
+//! the queries of other programs over Tor to the servers above:
+//!
+//! `cargo run -- --listen --server tls://1.1.1.1#one.one.one.one`
+//!
+//! `dig @127.0.0.1 -p 5353 torproject.org`
+//!
+//! Responses too large for UDP come back truncated, and the client is then
+//! expected to ask again over TCP. The cache statistics are logged every
+//! minute.
+//!
 //! ### Note on DNS
 //! The DNS implementation showcased is not really meant for production. It is just
 //! a quick series of hacks to show you how, if you do have a very custom protocol
@@ -25,47 +113,204 @@
 //!
 //! For more information on DNS, you can read [RFC 1035](https://datatracker.ietf.org/doc/html/rfc1035)
 //! or [this educational guide](https://mislove.org/teaching/cs4700/spring11/handouts/project1-primer.pdf)
-use crate::dns::{AsBytes, FromBytes, Response};
 use arti_client::{TorClient, TorClientConfig};
-use std::env;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::debug;
+use clap::Parser;
+use dns_resolver::cache::{self, Cache};
+use dns_resolver::dns::{self, RecordType};
+use dns_resolver::doh;
+use dns_resolver::server::{self, StubServer};
+use dns_resolver::tls::TlsConfig;
+use dns_resolver::transport;
+use dns_resolver::upstream::{self, Server, Strategy, Upstream};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How often the cache statistics are logged by the stub resolver
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
 
-mod dns;
+/// Look up DNS records over Tor
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Hostnames to look up
+    #[arg(required_unless_present = "listen")]
+    hostnames: Vec<String>,
+    /// Type of record to look up, like A, AAAA, MX or TXT, can be repeated
+    #[arg(short, long = "type", default_value = "A")]
+    record_types: Vec<RecordType>,
+    /// Randomize the case of the hostname, and check the server echoes it back unchanged
+    #[arg(long)]
+    randomize_case: bool,
+    /// Server to send the queries to, as host:port, can be repeated
+    #[arg(short, long = "server", default_value = upstream::DEFAULT_SERVER)]
+    servers: Vec<Server>,
+    /// Seconds to wait for a response before retrying or moving on to the next server
+    #[arg(long, default_value_t = upstream::QUERY_TIMEOUT.as_secs())]
+    timeout: u64,
+    /// How many times a query is retried on a server before moving on
+    #[arg(long, default_value_t = upstream::RETRIES)]
+    retries: usize,
+    /// Send each query to this many servers at once, and use the first response
+    #[arg(long)]
+    race: Option<usize>,
+    /// Seconds after which the stream to a server is closed if no query is in flight
+    #[arg(long, default_value_t = transport::IDLE_TIMEOUT.as_secs())]
+    idle_timeout: u64,
+    /// PEM file of CA certificates to trust for DNS over TLS and HTTPS, can be repeated
+    #[arg(long)]
+    ca_cert: Vec<PathBuf>,
+    /// Only accept a key for a DNS over TLS or HTTPS server, as `<name>=sha256/<base64 SPKI hash>`, can be repeated
+    #[arg(long)]
+    pin: Vec<String>,
+    /// Don't send the name of DNS over TLS and HTTPS servers as SNI
+    #[arg(long)]
+    no_sni: bool,
+    /// HTTP method used for DNS over HTTPS, GET or POST
+    #[arg(long, default_value = "GET")]
+    doh_method: doh::Method,
+    /// Run a stub resolver on this address instead of looking up hostnames
+    #[arg(long, num_args = 0..=1, default_missing_value = server::DEFAULT_LISTEN)]
+    listen: Option<SocketAddr>,
+    /// Number of responses to cache, 0 to disable the cache
+    #[arg(long, default_value_t = cache::DEFAULT_CAPACITY)]
+    cache_size: usize,
+    /// Answer with expired responses up to this many seconds old when no server answers
+    #[arg(long)]
+    serve_stale: Option<Option<u64>>,
+}
+
+/// Build the TLS settings out of the `--ca-cert`, `--pin` and `--no-sni`
+/// options
+fn tls_config(args: &Args) -> anyhow::Result<TlsConfig> {
+    let mut tls = TlsConfig::new();
+    for path in args.ca_cert.iter() {
+        tls.add_ca_certificates(path)?;
+    }
+    for pin in args.pin.iter() {
+        let (name, hash) = pin
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Pin should look like <name>=sha256/<hash>"))?;
+        tls.pin(name, hash)?;
+    }
+    tls.set_sni(!args.no_sni);
+    Ok(tls)
+}
 
 #[tokio::main]
 async fn main() {
     // Start logging messages
     tracing_subscriber::fmt::init();
     // Get and check CLI arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: dns-resolver <hostname-to-lookup>");
-        return;
-    }
+    let args = Args::parse();
+    let tls = match tls_config(&args).and_then(|tls| tls.build_connector()) {
+        Ok(tls) => tls,
+        Err(e) => {
+            tracing::error!("Invalid TLS settings: {}", e);
+            return;
+        }
+    };
     // Create the default TorClientConfig and create a TorClient
     let config = TorClientConfig::default();
     let tor_client = TorClient::create_bootstrapped(config).await.unwrap();
-    debug!("Connecting to 1.1.1.1 port 53 for DNS over TCP lookup");
-    let mut stream = tor_client.connect(crate::dns::DNS_SERVER).await.unwrap();
-    // We now have a TcpStream analogue to use
-    match crate::dns::build_query(args[1].as_str()) {
-        Ok(query) => {
-            let req = query.as_bytes(); // Get raw bytes representation
-            stream.write_all(req.as_slice()).await.unwrap();
-            // Flushing ensures we actually send data over network right then instead
-            // of waiting for buffer to fill up
-            stream.flush().await.unwrap();
-            debug!("Awaiting response...");
-            let mut buf: Vec<u8> = Vec::new();
-            // Read the response
-            stream.read_to_end(&mut buf).await.unwrap();
-            // Interpret the response
-            match Response::from_bytes(&buf) {
-                Ok(resp) => println!("{}", resp),
-                Err(_) => eprintln!("No valid response!"),
-            };
+    // Streams to the servers are opened as they are needed, each one being a
+    // TcpStream analogue over which all the queries to that server go, with
+    // TLS on top for DNS over TLS servers
+    let connector = move |server: Server| {
+        let tor_client = tor_client.clone();
+        let tls = tls.clone();
+        async move {
+            debug!("Connecting to {}", server);
+            let stream = tor_client
+                .connect((server.host.as_str(), server.port))
+                .await?;
+            tls.connect(&server, stream).await
         }
-        Err(_) => tracing::error!("Invalid domain name entered!"),
     };
+    let mut upstream = Upstream::new(args.servers, connector);
+    upstream.set_timeout(Duration::from_secs(args.timeout));
+    upstream.set_retries(args.retries);
+    upstream.set_idle_timeout(Duration::from_secs(args.idle_timeout));
+    upstream.set_doh_method(args.doh_method);
+    if let Some(count) = args.race {
+        upstream.set_strategy(Strategy::Race(count));
+    }
+    if args.cache_size > 0 {
+        let mut cache = Cache::new(args.cache_size);
+        cache.set_serve_stale(
+            args.serve_stale
+                .map(|window| window.map_or(cache::DEFAULT_STALE_WINDOW, Duration::from_secs)),
+        );
+        upstream.set_cache(cache);
+    }
+    let upstream = Arc::new(upstream);
+    if let Some(address) = args.listen {
+        let stats = upstream.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STATS_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Some(stats) = stats.cache_stats() {
+                    info!("Cache: {}", stats);
+                }
+            }
+        });
+        let mut stub = StubServer::new(upstream);
+        stub.set_randomize_case(args.randomize_case);
+        if let Err(e) = Arc::new(stub).run(address).await {
+            tracing::error!("Stub resolver failed: {:#}", e);
+        }
+        return;
+    }
+    // Send every query right away, the responses are matched with them as
+    // they come back
+    let mut lookups = Vec::new();
+    for hostname in args.hostnames.iter() {
+        for record_type in args.record_types.iter() {
+            let mut query = match dns::build_query(hostname, *record_type) {
+                Ok(query) => query,
+                Err(_) => {
+                    tracing::error!("Invalid domain name entered: {}", hostname);
+                    continue;
+                }
+            };
+            if args.randomize_case {
+                query.randomize_case();
+            }
+            let upstream = upstream.clone();
+            let lookup = tokio::spawn(async move { upstream.query(&query).await });
+            lookups.push((hostname, lookup));
+        }
+    }
+    debug!("Awaiting responses...");
+    for (hostname, lookup) in lookups {
+        match lookup.await.expect("lookup task panicked") {
+            Ok(answer) => {
+                let resp = answer.message;
+                if resp.flags.tc {
+                    warn!("Response was truncated");
+                }
+                println!("{}", resp);
+                println!(";; Query time: {} msec", answer.rtt.as_millis());
+                if answer.cached {
+                    println!(";; SERVER: {} (cached)\n", answer.server);
+                } else {
+                    println!(";; SERVER: {}\n", answer.server);
+                }
+                if let Err(e) = resp.rcode().into_result() {
+                    eprintln!("Lookup of {} failed: {}", hostname, e);
+                }
+            }
+            Err(e) => eprintln!("No valid response for {}: {:#}", hostname, e),
+        }
+    }
+    for (server, health) in upstream.health() {
+        debug!("{}: {}", server, health);
+    }
+    if let Some(stats) = upstream.cache_stats() {
+        debug!("Cache: {}", stats);
+    }
 }
@@ -0,0 +1,452 @@
+//! A stub resolver, forwarding the queries of local programs over Tor
+//!
+//! Applications, or the system resolver, can be pointed at the address a
+//! [StubServer] listens on, over UDP or TCP. Each query is decoded and sent to
+//! the [Upstream] servers, over Tor, and the response is relayed back to the
+//! client. Queries go upstream with a new random ID, as two clients could
+//! well use the same one, and their responses are given the ID of the query
+//! of the client back.
+//!
+//! Over UDP, responses are limited to 512 bytes, or to the payload size given
+//! in the OPT record of the query. Larger responses have their records
+//! dropped and the TC flag set, so that the client asks again over TCP, see
+//! RFC 1035 section 4.2.1 and RFC 6891 section 7.
+//!
+//! Each query is handled in its own task, so that a slow upstream doesn't hold
+//! up the other clients, with at most [MAX_IN_FLIGHT] queries at once. Each
+//! TCP connection has a task of its own too, with at most
+//! [MAX_TCP_CONNECTIONS] of them at once: further clients wait for one of
+//! them to close, or to be closed for being idle.
+use crate::dns::{AsBytes, Flags, FromBytes, Message, Opcode, Rcode, RecordType};
+use crate::transport::{read_message, write_message, IDLE_TIMEOUT};
+use crate::upstream::{Connector, Upstream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+/// Address the stub resolver listens on by default
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:5353";
+
+/// Largest response sent over UDP to clients which don't use EDNS
+pub const UDP_PAYLOAD_LEN: usize = 512;
+
+/// Largest response sent over UDP, whatever the client says it can take, as
+/// larger datagrams tend to get fragmented
+pub const MAX_UDP_PAYLOAD_LEN: usize = 4096;
+
+/// Largest number of queries handled at once, over UDP and TCP together
+pub const MAX_IN_FLIGHT: usize = 1024;
+
+/// Largest number of TCP connections served at once by default
+pub const MAX_TCP_CONNECTIONS: usize = 256;
+
+/// Build a response to `query` without any record, carrying `rcode`
+fn error_response(query: &Message, rcode: Rcode) -> Message {
+    Message {
+        id: query.id,
+        flags: Flags {
+            qr: true,
+            opcode: query.flags.opcode,
+            rd: query.flags.rd,
+            ra: true,
+            rcode,
+            ..Default::default()
+        },
+        questions: query.questions.clone(),
+        answers: Vec::new(),
+        authority: Vec::new(),
+        additional: Vec::new(),
+    }
+}
+
+/// Build a FORMERR response to a query which couldn't be decoded, if enough
+/// of its header is there to tell it is a query and what its ID is
+fn format_error(bytes: &[u8]) -> Option<Message> {
+    let id = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+    // Answering something which may be a response could start a loop
+    if bytes.get(2)? & 0x80 != 0 {
+        return None;
+    }
+    Some(Message {
+        id,
+        flags: Flags {
+            qr: true,
+            rcode: Rcode::FormErr,
+            ..Default::default()
+        },
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authority: Vec::new(),
+        additional: Vec::new(),
+    })
+}
+
+/// Encode `response` to be sent over UDP to a client which can take up to
+/// `limit` bytes, dropping its records if it doesn't fit
+fn encode_udp(mut response: Message, limit: usize) -> Vec<u8> {
+    let bytes = response.as_bytes();
+    if bytes.len() <= limit {
+        return bytes;
+    }
+    debug!(
+        "Truncating response of {} bytes to fit in {}",
+        bytes.len(),
+        limit
+    );
+    response.flags.tc = true;
+    response.answers.clear();
+    response.authority.clear();
+    response
+        .additional
+        .retain(|record| record.rtype == RecordType::Opt);
+    response.as_bytes()
+}
+
+/// Largest response the client sending `query` over UDP can take
+fn udp_limit(query: &Message) -> usize {
+    query
+        .edns()
+        .map_or(UDP_PAYLOAD_LEN, |edns| edns.udp_size() as usize)
+        .clamp(UDP_PAYLOAD_LEN, MAX_UDP_PAYLOAD_LEN)
+}
+
+/// A stub resolver, see the [module level documentation](self)
+pub struct StubServer<C: Connector> {
+    /// Where the queries are forwarded to
+    upstream: Arc<Upstream<C>>,
+    /// Limits the number of queries handled at once
+    in_flight: Arc<Semaphore>,
+    /// Limits the number of TCP connections served at once
+    tcp_connections: Arc<Semaphore>,
+    /// Whether to randomize the case of the names sent upstream
+    randomize_case: bool,
+}
+
+impl<C: Connector> StubServer<C> {
+    /// Create a stub resolver forwarding queries to `upstream`
+    pub fn new(upstream: Arc<Upstream<C>>) -> Self {
+        Self {
+            upstream,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            tcp_connections: Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS)),
+            randomize_case: false,
+        }
+    }
+
+    /// Randomize the case of the names sent upstream, see
+    /// [Message::randomize_case()]
+    pub fn set_randomize_case(&mut self, enabled: bool) {
+        self.randomize_case = enabled;
+    }
+
+    /// Serve at most `limit` TCP connections at once, instead of
+    /// [MAX_TCP_CONNECTIONS]
+    pub fn set_max_tcp_connections(&mut self, limit: usize) {
+        self.tcp_connections = Arc::new(Semaphore::new(limit.max(1)));
+    }
+
+    /// Listen on `address` over both UDP and TCP, until either fails
+    pub async fn run(self: Arc<Self>, address: SocketAddr) -> anyhow::Result<()> {
+        let udp = UdpSocket::bind(address).await?;
+        let tcp = TcpListener::bind(address).await?;
+        info!("Listening on {} over UDP and TCP", address);
+        tokio::try_join!(self.clone().serve_udp(udp), self.serve_tcp(tcp))?;
+        Ok(())
+    }
+
+    /// Work out the response to `query`, asking upstream
+    async fn resolve(&self, query: &Message) -> Message {
+        if query.flags.opcode != Opcode::Query {
+            return error_response(query, Rcode::NotImp);
+        }
+        let Some(question) = query.question().filter(|_| query.questions.len() == 1) else {
+            return error_response(query, Rcode::FormErr);
+        };
+        let mut forwarded = query.clone();
+        forwarded.id = rand::random();
+        if self.randomize_case {
+            forwarded.randomize_case();
+        }
+        match self.upstream.query(&forwarded).await {
+            Ok(answer) => {
+                debug!("{} answered {}", answer.server, question.qname);
+                let mut response = answer.message;
+                response.id = query.id;
+                // The question was checked against the one sent, give the
+                // client back its own, with the case it used
+                response.questions = query.questions.clone();
+                response
+            }
+            Err(e) => {
+                warn!("No answer for {}: {:#}", question.qname, e);
+                error_response(query, Rcode::ServFail)
+            }
+        }
+    }
+
+    /// Answer the queries coming to `socket`
+    pub async fn serve_udp(self: Arc<Self>, socket: UdpSocket) -> anyhow::Result<()> {
+        let socket = Arc::new(socket);
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let (len, client) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                // Errors like an ICMP port unreachable from an earlier
+                // response are about a single client, keep on serving
+                Err(e) => {
+                    debug!("Unable to receive over UDP: {}", e);
+                    continue;
+                }
+            };
+            let bytes = buffer[..len].to_vec();
+            let permit = self.in_flight.clone().acquire_owned().await?;
+            let server = self.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let response = match Message::from_bytes(&bytes) {
+                    Ok(query) if query.flags.qr => return,
+                    Ok(query) => encode_udp(server.resolve(&query).await, udp_limit(&query)),
+                    Err(e) => {
+                        debug!("Invalid query from {}: {}", client, e);
+                        match format_error(&bytes) {
+                            Some(response) => response.as_bytes(),
+                            None => return,
+                        }
+                    }
+                };
+                if let Err(e) = socket.send_to(&response, client).await {
+                    debug!("Unable to answer {}: {}", client, e);
+                }
+            });
+        }
+    }
+
+    /// Answer the queries of the clients connecting to `listener`
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            // Clients past the limit are left waiting in the backlog of the
+            // listener until a connection closes
+            let permit = self.tcp_connections.clone().acquire_owned().await?;
+            let (stream, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Unable to accept a TCP connection: {}", e);
+                    continue;
+                }
+            };
+            debug!("TCP connection from {}", client);
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                server.serve_tcp_client(stream).await;
+            });
+        }
+    }
+
+    /// Answer the queries of a client connected over TCP
+    ///
+    /// Clients may send several queries without waiting for the responses,
+    /// which are sent back as they come, see RFC 7766 section 6.2.1.1
+    async fn serve_tcp_client(self: Arc<Self>, stream: TcpStream) {
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        loop {
+            let query = match tokio::time::timeout(IDLE_TIMEOUT, read_message(&mut reader)).await {
+                Ok(Ok(query)) => query,
+                Ok(Err(e)) => {
+                    debug!("TCP connection closed: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    debug!("Closing idle TCP connection");
+                    break;
+                }
+            };
+            if query.flags.qr {
+                continue;
+            }
+            let Ok(permit) = self.in_flight.clone().acquire_owned().await else {
+                break;
+            };
+            let server = self.clone();
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let response = server.resolve(&query).await;
+                if let Err(e) = write_message(&mut *writer.lock().await, &response).await {
+                    debug!("Unable to answer over TCP: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, Name, RData, ResourceRecord};
+    use crate::transport::testing::{response_to, serve};
+    use crate::upstream::Server;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+
+    /// Connect to a fake upstream server, which answers `big.example` with
+    /// more TXT records than fit in 512 bytes, and anything else with an
+    /// address
+    async fn connector(_server: Server) -> anyhow::Result<DuplexStream> {
+        let (client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve(stream, |query| async move {
+            let mut response = response_to(&query);
+            let name = query.questions[0].qname.clone();
+            let big = name == "big.example".parse::<Name>().unwrap();
+            let rdata = |i: usize| match big {
+                true => RData::Txt(vec![vec![b'x'; 200]]),
+                false => RData::A(Ipv4Addr::new(192, 0, 2, i as u8)),
+            };
+            let count = if big { 10 } else { 1 };
+            response.answers = (0..count)
+                .map(|i| ResourceRecord {
+                    name: name.clone(),
+                    rtype: if big { RecordType::Txt } else { RecordType::A },
+                    class: 1,
+                    ttl: 300,
+                    rdata: rdata(i),
+                })
+                .collect();
+            Some(response)
+        }));
+        Ok(client)
+    }
+
+    /// Start a stub resolver forwarding to the fake upstream, and return the
+    /// address it listens on
+    async fn start() -> SocketAddr {
+        start_with(MAX_TCP_CONNECTIONS).await
+    }
+
+    /// Like [start()], serving at most `max_tcp_connections` at once
+    async fn start_with(max_tcp_connections: usize) -> SocketAddr {
+        let upstream = Upstream::new(vec!["upstream.test".parse().unwrap()], connector);
+        let mut server = StubServer::new(Arc::new(upstream));
+        server.set_max_tcp_connections(max_tcp_connections);
+        let server = Arc::new(server);
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(address).await.unwrap();
+        tokio::spawn(server.clone().serve_udp(udp));
+        tokio::spawn(server.serve_tcp(tcp));
+        address
+    }
+
+    /// Send `query` over UDP to `address`, and wait for the response
+    async fn ask_udp(address: SocketAddr, query: &[u8]) -> Message {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(query, address).await.unwrap();
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        *Message::from_bytes(&buffer[..len]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn udp_queries_are_forwarded() {
+        let address = start().await;
+        let mut query = build_query("example.com", RecordType::A).unwrap();
+        query.id = 0x1234;
+        let response = ask_udp(address, &query.as_bytes()).await;
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn large_udp_responses_are_truncated() {
+        let address = start().await;
+        let mut query = build_query("big.example", RecordType::Txt).unwrap();
+        let response = ask_udp(address, &query.as_bytes()).await;
+        assert!(response.flags.tc);
+        assert!(response.answers.is_empty());
+        // A client which can take larger responses over EDNS gets it all
+        query.additional.push(ResourceRecord {
+            name: Name::default(),
+            rtype: RecordType::Opt,
+            class: 4096,
+            ttl: 0,
+            rdata: RData::Opt(Vec::new()),
+        });
+        let response = ask_udp(address, &query.as_bytes()).await;
+        assert!(!response.flags.tc);
+        assert_eq!(response.answers.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn invalid_queries_get_a_format_error() {
+        let address = start().await;
+        let response = ask_udp(address, &[0xab, 0xcd, 0x01, 0x00, 0xff]).await;
+        assert_eq!(response.id, 0xabcd);
+        assert_eq!(response.rcode(), Rcode::FormErr);
+    }
+
+    #[tokio::test]
+    async fn tcp_queries_are_answered_in_full() {
+        let address = start().await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let queries = [
+            build_query("big.example", RecordType::Txt).unwrap(),
+            build_query("example.com", RecordType::A).unwrap(),
+        ];
+        for query in queries.iter() {
+            write_message(&mut stream, query).await.unwrap();
+        }
+        for _ in queries.iter() {
+            let response = read_message(&mut stream).await.unwrap();
+            let query = queries.iter().find(|q| q.id == response.id).unwrap();
+            assert_eq!(response.questions, query.questions);
+            assert!(!response.flags.tc);
+            assert!(!response.answers.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn many_clients_are_served_at_once() {
+        let address = start().await;
+        let clients = (0..100u16).map(|id| {
+            tokio::spawn(async move {
+                let mut query = build_query("example.com", RecordType::A).unwrap();
+                query.id = id;
+                ask_udp(address, &query.as_bytes()).await.id
+            })
+        });
+        for (id, client) in (0..100u16).zip(clients.collect::<Vec<_>>()) {
+            assert_eq!(client.await.unwrap(), id);
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_clients_past_the_limit_wait_their_turn() {
+        let address = start_with(1).await;
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let mut first = TcpStream::connect(address).await.unwrap();
+        write_message(&mut first, &query).await.unwrap();
+        read_message(&mut first).await.unwrap();
+        // The connection is made, but nobody reads from it yet
+        let mut second = TcpStream::connect(address).await.unwrap();
+        write_message(&mut second, &query).await.unwrap();
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(200), read_message(&mut second)).await;
+        assert!(waiting.is_err());
+        // Its turn comes once the first client leaves
+        drop(first);
+        let response = tokio::time::timeout(Duration::from_secs(5), read_message(&mut second))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.id, query.id);
+    }
+}
@@ -0,0 +1,320 @@
+//! DNS over TLS, as described in RFC 7858
+//!
+//! Over a plain Tor stream, the exit relay sees every query and response. With
+//! DNS over TLS, the stream to the server is wrapped in TLS, so the exit relay
+//! only learns which server we talk to. Messages are framed exactly like over
+//! TCP, so the same [Connection](crate::transport::Connection) is used on top
+//! of the TLS stream.
+//!
+//! The certificate of the server has to be valid for its authentication name,
+//! which is given after a `#` in the address of the server, like
+//! `tls://1.1.1.1#one.one.one.one`, and defaults to its host otherwise. The
+//! authentication name is also sent as SNI, unless that is turned off, in
+//! which case the exit relay can't tell the name we expect either.
+//!
+//! On top of the Mozilla roots from `webpki-roots`, more CA certificates can be
+//! trusted, for example to test against a local server with a self-signed
+//! certificate. The public key of a server can also be pinned, as in RFC 7858
+//! section 4.2, in which case its certificate is only accepted if it is valid
+//! *and* its key matches one of the pins. The pins are checked by the
+//! [tls_pinning] crate, which `download-manager` uses too.
+//!
+//! The same settings apply to DNS over HTTPS servers, see the
+//! [doh](crate::doh) module.
+use crate::upstream::{Protocol, Server};
+use anyhow::{anyhow, bail};
+use rustls::{ClientConfig, ServerName};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tls_pinning::{PinningVerifier, Pins};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+/// Port DNS over TLS servers listen on
+pub const DOT_PORT: u16 = 853;
+
+/// Extra CA certificates, pinned keys and SNI settings used when connecting
+/// to DNS over TLS and HTTPS servers
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// DER encoded certificates trusted on top of the default roots
+    ca_certificates: Vec<Vec<u8>>,
+    /// Accepted public keys, by authentication name
+    pins: Pins,
+    /// Whether to send the authentication name as SNI
+    sni: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_certificates: Vec::new(),
+            pins: Pins::new(),
+            sni: true,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Create a config which only uses the default roots
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust every certificate found in the PEM file at `path`
+    pub fn add_ca_certificates(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let certificates = rustls_pemfile::certs(&mut reader)?;
+        if certificates.is_empty() {
+            bail!("No certificate found in {}", path.display());
+        }
+        debug!(
+            "Trusting {} certificates from {}",
+            certificates.len(),
+            path.display()
+        );
+        self.ca_certificates.extend(certificates);
+        Ok(())
+    }
+
+    /// Trust a DER encoded certificate
+    pub fn add_ca_certificate(&mut self, der: Vec<u8>) {
+        self.ca_certificates.push(der);
+    }
+
+    /// Only accept certificates of the server authenticated as `name` whose
+    /// public key matches `pin`
+    ///
+    /// See [Pins::add()] for what `pin` looks like and how to compute it
+    pub fn pin(&mut self, name: &str, pin: &str) -> anyhow::Result<()> {
+        self.pins.add(name, pin)
+    }
+
+    /// Send the authentication name of servers as SNI or not
+    pub fn set_sni(&mut self, enabled: bool) {
+        self.sni = enabled;
+    }
+
+    /// Create a connector using these settings
+    pub fn build_connector(&self) -> anyhow::Result<TlsConnector> {
+        let verifier = PinningVerifier::new(&self.ca_certificates, self.pins.clone())?;
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        config.enable_sni = self.sni;
+        Ok(TlsConnector {
+            inner: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        })
+    }
+}
+
+/// Sets up TLS on the streams to DNS over TLS servers
+#[derive(Clone)]
+pub struct TlsConnector {
+    /// The rustls connector, with our verifier
+    inner: tokio_rustls::TlsConnector,
+}
+
+impl TlsConnector {
+    /// Set up TLS on `stream` if `server` is a DNS over TLS or HTTPS
+    /// server, or hand it back as is otherwise
+    pub async fn connect<S>(&self, server: &Server, stream: S) -> anyhow::Result<MaybeTls<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if server.protocol == Protocol::Tcp {
+            return Ok(MaybeTls::Plain(stream));
+        }
+        let name = server.auth_name.as_deref().unwrap_or(&server.host);
+        if name.parse::<IpAddr>().is_ok() {
+            bail!(
+                "{} needs an authentication name, like {}#dns.example",
+                server,
+                server
+            );
+        }
+        let server_name = ServerName::try_from(name)
+            .map_err(|_| anyhow!("Invalid authentication name {:?}", name))?;
+        let stream = self.inner.connect(server_name, stream).await?;
+        debug!("Set up TLS with {}, authenticated as {}", server, name);
+        Ok(MaybeTls::Tls(Box::new(stream)))
+    }
+}
+
+/// A stream to a server, with TLS unless it is a DNS over TCP server
+pub enum MaybeTls<S> {
+    /// A plain stream, for DNS over TCP
+    Plain(S),
+    /// A TLS stream, for DNS over TLS and HTTPS
+    Tls(Box<TlsStream<S>>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTls<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTls<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, Message, RecordType};
+    use crate::transport::testing;
+    use crate::transport::{Connection, IDLE_TIMEOUT};
+    use base64::Engine;
+    use rustls::{Certificate, PrivateKey, ServerConfig};
+    use sha2::{Digest, Sha256};
+    use tokio::io::DuplexStream;
+
+    /// A self-signed certificate for `dns.test`
+    struct Identity {
+        /// The DER encoded certificate
+        certificate: Vec<u8>,
+        /// Its DER encoded private key
+        key: Vec<u8>,
+        /// Its pin, as given to [TlsConfig::pin()]
+        ///
+        /// This is computed from the key pair by rcgen, rather than by
+        /// looking into the certificate like [Pins] does
+        pin: String,
+    }
+
+    /// Generate a new [Identity]
+    fn generate_certificate() -> Identity {
+        let certificate = rcgen::generate_simple_self_signed(vec!["dns.test".into()]).unwrap();
+        let spki = certificate.get_key_pair().public_key_der();
+        Identity {
+            certificate: certificate.serialize_der().unwrap(),
+            key: certificate.serialize_private_key_der(),
+            pin: base64::engine::general_purpose::STANDARD.encode(Sha256::digest(spki)),
+        }
+    }
+
+    /// Run a DNS over TLS server using `certificate` on the other end of the
+    /// returned stream, answering every query with an empty response
+    fn serve(certificate: &[u8], key: &[u8]) -> DuplexStream {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certificate.to_vec())],
+                PrivateKey(key.to_vec()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let (client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            if let Ok(stream) = acceptor.accept(stream).await {
+                testing::serve(stream, testing::echo).await;
+            }
+        });
+        client
+    }
+
+    /// Query a stand-in server using `certificate`, as `server` and with
+    /// `config`
+    async fn query(
+        config: &TlsConfig,
+        server: &str,
+        identity: &Identity,
+    ) -> anyhow::Result<Message> {
+        let server: Server = server.parse()?;
+        let stream = config
+            .build_connector()?
+            .connect(&server, serve(&identity.certificate, &identity.key))
+            .await?;
+        assert!(matches!(stream, MaybeTls::Tls(_)));
+        let connection = Connection::new(stream, IDLE_TIMEOUT);
+        connection
+            .query(build_query("example.com", RecordType::A)?)
+            .await
+    }
+
+    #[tokio::test]
+    async fn queries_go_over_tls() {
+        let certificate = generate_certificate();
+        let mut config = TlsConfig::new();
+        config.add_ca_certificate(certificate.certificate.clone());
+        config.pin("dns.test", &certificate.pin).unwrap();
+        let response = query(&config, "tls://127.0.0.1#dns.test", &certificate)
+            .await
+            .unwrap();
+        assert!(response.flags.qr);
+        // The SNI is only a hint, leaving it out changes nothing
+        config.set_sni(false);
+        query(&config, "tls://127.0.0.1#dns.test", &certificate)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificates_are_rejected() {
+        let certificate = generate_certificate();
+        let result = query(&TlsConfig::new(), "tls://127.0.0.1#dns.test", &certificate).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn certificates_have_to_match_the_authentication_name() {
+        let certificate = generate_certificate();
+        let mut config = TlsConfig::new();
+        config.add_ca_certificate(certificate.certificate.clone());
+        let result = query(&config, "tls://127.0.0.1#other.test", &certificate).await;
+        assert!(result.is_err());
+        // An IP address can't be used as authentication name
+        let result = query(&config, "tls://127.0.0.1", &certificate).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn certificates_have_to_match_the_pins() {
+        let certificate = generate_certificate();
+        let other = generate_certificate();
+        let mut config = TlsConfig::new();
+        config.add_ca_certificate(certificate.certificate.clone());
+        config.pin("dns.test", &other.pin).unwrap();
+        let result = query(&config, "tls://127.0.0.1#dns.test", &certificate).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! Framing of DNS messages for the transports they are sent over
+//!
+//! The [dns](crate::dns) module only deals with messages on their own. Over
+//! TCP, and so over Tor streams, each message is preceded by its length as a
+//! 2 byte big endian number, see RFC 1035 section 4.2.2. That prefix is added
+//! and checked here, so that the same message types can be used for other
+//! transports which frame messages in their own way.
+//!
+//! [Connection] sends queries over a single stream, without waiting for the
+//! response of one query before sending the next one, as allowed by RFC 7766.
+use crate::dns::{AsBytes, FromBytes, Message};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// How long a connection is kept open without any query in flight
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest message which can be sent over TCP, as its length has to fit in
+/// the 2 byte prefix
+pub const MAX_TCP_MESSAGE_LEN: usize = u16::MAX as usize;
+
+/// Error returned when a message can't be framed or unframed
+#[derive(Error, Debug)]
+pub enum FramingError {
+    /// The message doesn't fit in a TCP frame
+    #[error("message of {0} bytes is too large to be sent over TCP")]
+    TooLarge(usize),
+    /// Fewer bytes were received than the length prefix announced
+    #[error("expected a message of {expected} bytes, only got {got}")]
+    Truncated {
+        /// Length announced by the prefix
+        expected: usize,
+        /// Number of bytes received after the prefix
+        got: usize,
+    },
+}
+
+/// Error returned by a [Connection]
+#[derive(Error, Debug)]
+pub enum ConnectionError {
+    /// The connection was closed, by us or the server, before a response came
+    #[error("connection closed before a response was received")]
+    Closed,
+}
+
+/// Add the TCP length prefix to an encoded message
+pub fn frame_tcp(message: &[u8]) -> Result<Vec<u8>, FramingError> {
+    let len = u16::try_from(message.len()).map_err(|_| FramingError::TooLarge(message.len()))?;
+    let mut v = Vec::with_capacity(2 + message.len());
+    v.extend_from_slice(&len.to_be_bytes());
+    v.extend_from_slice(message);
+    Ok(v)
+}
+
+/// Encode a message to be sent over TCP, with its length prefix
+pub fn encode_tcp(message: &Message) -> Result<Vec<u8>, FramingError> {
+    frame_tcp(&message.as_bytes())
+}
+
+/// Decode a message received over TCP, along with its length prefix
+///
+/// Anything following the message is ignored
+pub fn decode_tcp(bytes: &[u8]) -> anyhow::Result<Message> {
+    let (prefix, rest) = bytes.split_at(bytes.len().min(2));
+    let prefix: [u8; 2] = prefix.try_into().map_err(|_| FramingError::Truncated {
+        expected: 2,
+        got: prefix.len(),
+    })?;
+    let len = u16::from_be_bytes(prefix) as usize;
+    let message = rest.get(..len).ok_or(FramingError::Truncated {
+        expected: len,
+        got: rest.len(),
+    })?;
+    if rest.len() > len {
+        warn!("Ignoring {} bytes after the message", rest.len() - len);
+    }
+    Ok(*Message::from_bytes(message)?)
+}
+
+/// Write a message to `stream`, along with its length prefix
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    message: &Message,
+) -> anyhow::Result<()> {
+    stream.write_all(&encode_tcp(message)?).await?;
+    // Flushing ensures we actually send data over network right then instead
+    // of waiting for buffer to fill up
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read the next message from `stream`
+///
+/// Exactly the length prefix and the message it announces are read, so that
+/// the stream can be used for more messages afterwards
+pub async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Message> {
+    let mut prefix = [0u8; 2];
+    stream.read_exact(&mut prefix).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(prefix) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(*Message::from_bytes(&message)?)
+}
+
+/// A query waiting for its response
+struct Pending {
+    /// The query as it was sent, to check the response against it
+    query: Message,
+    /// Where to send the response
+    response: oneshot::Sender<anyhow::Result<Message>>,
+}
+
+/// State shared between a [Connection] and the task reading its responses
+#[derive(Default)]
+struct Shared {
+    /// Queries waiting for a response, by ID
+    pending: HashMap<u16, Pending>,
+    /// Whether the connection was closed
+    closed: bool,
+}
+
+/// A stream over which several queries can be in flight at once
+///
+/// Each query is given an ID no other query in flight uses, and a task reads
+/// the responses as they come, in whatever order, and hands each of them to
+/// the query with the same ID. Responses which don't match the question of
+/// their query are rejected.
+///
+/// The connection is closed once it has been idle for a while, as asked by
+/// RFC 7766, after which queries fail with [ConnectionError::Closed].
+pub struct Connection<S> {
+    /// Sending half of the stream, shut down by the reading task once it is
+    /// done
+    writer: Arc<tokio::sync::Mutex<WriteHalf<S>>>,
+    /// State shared with the reading task
+    shared: Arc<Mutex<Shared>>,
+    /// When the last query was sent or response received, to tell when the
+    /// connection is idle
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+impl<S> Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// Start using `stream` for queries, closing it once no query has been in
+    /// flight for `idle_timeout`
+    pub fn new(stream: S, idle_timeout: Duration) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        tokio::spawn(read_responses(
+            reader,
+            writer.clone(),
+            shared.clone(),
+            last_activity.clone(),
+            idle_timeout,
+        ));
+        Self {
+            writer,
+            shared,
+            last_activity,
+        }
+    }
+
+    /// Send `query` and wait for its response
+    ///
+    /// The ID of the query is replaced by one which isn't in use on this
+    /// connection, the response comes back with that ID
+    pub async fn query(&self, mut query: Message) -> anyhow::Result<Message> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut shared = self.shared.lock().expect("poisoned lock");
+            if shared.closed {
+                return Err(ConnectionError::Closed.into());
+            }
+            while shared.pending.contains_key(&query.id) {
+                query.id = rand::random();
+            }
+            shared.pending.insert(
+                query.id,
+                Pending {
+                    query: query.clone(),
+                    response: sender,
+                },
+            );
+        }
+        *self.last_activity.lock().expect("poisoned lock") = Instant::now();
+        let mut waiting = Waiting {
+            shared: &self.shared,
+            id: query.id,
+            receiver,
+        };
+        write_message(&mut *self.writer.lock().await, &query).await?;
+        debug!("Sent query {}", query.id);
+        (&mut waiting.receiver)
+            .await
+            .map_err(|_| ConnectionError::Closed)?
+    }
+
+    /// Whether the connection was closed, in which case a new one is needed
+    /// for further queries
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().expect("poisoned lock").closed
+    }
+}
+
+/// A query waiting for its response
+///
+/// If the query is given up on, for example because it timed out, dropping
+/// this removes it from the pending queries. Otherwise it would keep the
+/// connection from ever being idle.
+struct Waiting<'a> {
+    /// State shared with the reading task
+    shared: &'a Mutex<Shared>,
+    /// ID the query was sent with
+    id: u16,
+    /// Where the response arrives
+    receiver: oneshot::Receiver<anyhow::Result<Message>>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.receiver.close();
+        let mut shared = self.shared.lock().expect("poisoned lock");
+        // The ID may already have been reused by another query, whose
+        // receiver is still open
+        if shared
+            .pending
+            .get(&self.id)
+            .is_some_and(|pending| pending.response.is_closed())
+        {
+            shared.pending.remove(&self.id);
+        }
+    }
+}
+
+/// Read responses from `reader` and hand them to the queries waiting for them,
+/// until the stream is closed or stays idle for `idle_timeout`
+async fn read_responses<S: AsyncRead + AsyncWrite>(
+    mut reader: ReadHalf<S>,
+    writer: Arc<tokio::sync::Mutex<WriteHalf<S>>>,
+    shared: Arc<Mutex<Shared>>,
+    last_activity: Arc<Mutex<Instant>>,
+    idle_timeout: Duration,
+) {
+    loop {
+        // The read is kept across wake ups of the timer, as dropping it
+        // halfway through a message would lose part of it
+        let read = read_message(&mut reader);
+        tokio::pin!(read);
+        let mut deadline = *last_activity.lock().expect("poisoned lock") + idle_timeout;
+        let result = loop {
+            tokio::select! {
+                result = &mut read => break Some(result),
+                _ = tokio::time::sleep_until(deadline) => {
+                    let idle = shared.lock().expect("poisoned lock").pending.is_empty();
+                    let last_activity = *last_activity.lock().expect("poisoned lock");
+                    if !idle {
+                        // Not idle while waiting for responses
+                        deadline = Instant::now() + idle_timeout;
+                    } else if last_activity + idle_timeout > Instant::now() {
+                        deadline = last_activity + idle_timeout;
+                    } else {
+                        break None;
+                    }
+                }
+            }
+        };
+        let response = match result {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => {
+                debug!("Connection failed: {}", e);
+                break;
+            }
+            None => {
+                debug!("Closing idle connection");
+                break;
+            }
+        };
+        let pending = shared
+            .lock()
+            .expect("poisoned lock")
+            .pending
+            .remove(&response.id);
+        match pending {
+            Some(pending) => {
+                let result = response
+                    .check_matches(&pending.query)
+                    .map(|_| response)
+                    .map_err(anyhow::Error::from);
+                let _ = pending.response.send(result);
+            }
+            None => warn!("Dropping response with unknown ID {}", response.id),
+        }
+        // A response counts as activity too
+        *last_activity.lock().expect("poisoned lock") = Instant::now();
+    }
+    // Dropping the senders tells the queries still waiting that we are done
+    {
+        let mut shared = shared.lock().expect("poisoned lock");
+        shared.closed = true;
+        shared.pending.clear();
+    }
+    // The stream is only closed once both halves are gone, so close the
+    // sending half here rather than waiting for the connection to be dropped
+    if let Err(e) = writer.lock().await.shutdown().await {
+        debug!("Unable to shut the connection down: {}", e);
+    }
+}
+
+/// Stand-in DNS servers for the tests of the modules sending queries
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use std::future::Future;
+
+    /// Answer `query`, without any record
+    pub(crate) fn response_to(query: &Message) -> Message {
+        let mut response = query.clone();
+        response.flags.qr = true;
+        response
+    }
+
+    /// Answer `query` with [response_to()], to be passed to [serve()]
+    pub(crate) async fn echo(query: Message) -> Option<Message> {
+        Some(response_to(&query))
+    }
+
+    /// Answer the queries read from `stream` with whatever `answer` returns
+    /// for them, until the stream is closed
+    ///
+    /// Queries for which `answer` returns `None` are left unanswered
+    pub(crate) async fn serve<S, F, Fut>(mut stream: S, mut answer: F)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+        F: FnMut(Message) -> Fut,
+        Fut: Future<Output = Option<Message>>,
+    {
+        while let Ok(query) = read_message(&mut stream).await {
+            let Some(response) = answer(query).await else {
+                continue;
+            };
+            if write_message(&mut stream, &response).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::response_to;
+    use super::*;
+    use crate::dns::{build_query, RecordType};
+
+    #[test]
+    fn tcp_round_trip() {
+        let query = build_query("example.com", RecordType::Aaaa).unwrap();
+        let framed = encode_tcp(&query).unwrap();
+        assert_eq!(
+            u16::from_be_bytes([framed[0], framed[1]]) as usize,
+            framed.len() - 2
+        );
+        assert_eq!(decode_tcp(&framed).unwrap(), query);
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let framed = encode_tcp(&build_query("example.com", RecordType::A).unwrap()).unwrap();
+        for len in 0..framed.len() {
+            assert!(decode_tcp(&framed[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        assert!(matches!(
+            frame_tcp(&vec![0; MAX_TCP_MESSAGE_LEN + 1]),
+            Err(FramingError::TooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_are_matched_by_id() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let connection = Arc::new(Connection::new(client, IDLE_TIMEOUT));
+        let names = ["a.example", "b.example", "c.example"];
+        let lookups: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let connection = connection.clone();
+                let query = build_query(name, RecordType::A).unwrap();
+                tokio::spawn(async move { connection.query(query).await })
+            })
+            .collect();
+        // Read all the queries before answering any, then answer them backwards
+        let mut queries = Vec::new();
+        for _ in names {
+            queries.push(read_message(&mut server).await.unwrap());
+        }
+        for query in queries.iter().rev() {
+            write_message(&mut server, &response_to(query))
+                .await
+                .unwrap();
+        }
+        for (name, lookup) in names.iter().zip(lookups) {
+            let response = lookup.await.unwrap().unwrap();
+            assert_eq!(
+                response.question().unwrap().qname.to_string(),
+                format!("{}.", name)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatched_responses_are_rejected() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let connection = Connection::new(client, IDLE_TIMEOUT);
+        let lookup = connection.query(build_query("example.com", RecordType::A).unwrap());
+        let answer = async {
+            let query = read_message(&mut server).await.unwrap();
+            let mut response = response_to(&query);
+            response.questions[0].qtype = RecordType::Aaaa;
+            write_message(&mut server, &response).await.unwrap();
+        };
+        let (result, _) = tokio::join!(lookup, answer);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let connection = Connection::new(client, Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // The server sees the stream being closed
+        let closed = tokio::time::timeout(Duration::from_secs(5), read_message(&mut server));
+        assert!(closed.await.unwrap().is_err());
+        let query = build_query("example.com", RecordType::A).unwrap();
+        assert!(connection.query(query).await.is_err());
+    }
+}
@@ -0,0 +1,717 @@
+//! The servers queries are sent to
+//!
+//! An [Upstream] holds a list of servers, along with a [Connection] to each of
+//! the servers used so far, which is reopened whenever it got closed. Each
+//! attempt at a query has to complete within a timeout, connecting included,
+//! and failed attempts are retried a few times on the same server before
+//! moving on to the next one. Instead of trying the servers one after the
+//! other, a query can also be sent to the first few servers at once, and the
+//! first response used, see [Strategy].
+//!
+//! The health of each server is tracked. Servers which failed several times in
+//! a row are only tried after all the others, until they answer again.
+//!
+//! A response is used whatever its RCODE, it is up to the caller to decide
+//! what to do with a SERVFAIL or a REFUSED.
+//!
+//! Responses can be kept in a [Cache], in which case queries are only sent
+//! to the servers when the cache can't answer them.
+use crate::cache::{Cache, CacheStats};
+use crate::dns::Message;
+use crate::doh::{self, HttpConnection, DOH_PORT};
+use crate::tls::DOT_PORT;
+use crate::transport::{Connection, IDLE_TIMEOUT};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Server used when none is given
+pub const DEFAULT_SERVER: &str = "1.1.1.1:53";
+
+/// Port used for DNS over TCP servers given without one
+pub const DEFAULT_PORT: u16 = 53;
+
+/// How long a single attempt at a query may take, connecting included
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times a query is retried on a server before moving on
+pub const RETRIES: usize = 1;
+
+/// Failures in a row after which a server is only tried after the others
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Error returned when querying upstream servers
+#[derive(Error, Debug)]
+pub enum UpstreamError {
+    /// A server couldn't be parsed
+    #[error("invalid server {0:?}, expected [tls://]host[:port][#name] or https://host[:port][/path][#name]")]
+    InvalidServer(String),
+    /// There is no server to send queries to
+    #[error("no upstream server configured")]
+    NoServers,
+    /// A server didn't answer in time
+    #[error("no response within {0:?}")]
+    Timeout(Duration),
+}
+
+/// How messages are sent to a server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// Plain DNS over TCP
+    #[default]
+    Tcp,
+    /// DNS over TLS, see the [tls](crate::tls) module
+    Tls,
+    /// DNS over HTTPS, see the [doh](crate::doh) module
+    Https,
+}
+
+impl Protocol {
+    /// Port servers speaking this protocol usually listen on
+    pub fn default_port(self) -> u16 {
+        match self {
+            Protocol::Tcp => DEFAULT_PORT,
+            Protocol::Tls => DOT_PORT,
+            Protocol::Https => DOH_PORT,
+        }
+    }
+}
+
+/// Address of a DNS server
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Server {
+    /// How messages are sent to the server
+    pub protocol: Protocol,
+    /// Hostname or IP address of the server
+    pub host: String,
+    /// Port the server listens on
+    pub port: u16,
+    /// Name the certificate of a DNS over TLS or HTTPS server has to be
+    /// valid for, when it isn't its host
+    pub auth_name: Option<String>,
+    /// Path queries are sent to on a DNS over HTTPS server, when it isn't
+    /// [DEFAULT_PATH](doh::DEFAULT_PATH)
+    pub path: Option<String>,
+}
+
+impl FromStr for Server {
+    type Err = UpstreamError;
+
+    /// Parse `host:port`, or just `host` to use the default port
+    ///
+    /// IPv6 addresses can be given on their own, or in brackets along with a
+    /// port, like `[2606:4700:4700::1111]:53`. DNS over TLS servers are
+    /// prefixed with `tls://`, and can be followed by their authentication
+    /// name, like `tls://1.1.1.1#one.one.one.one`. DNS over HTTPS servers are
+    /// given as a URL, like `https://dns.quad9.net/dns-query`, which can also
+    /// be followed by an authentication name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || UpstreamError::InvalidServer(s.to_string());
+        let (protocol, address) = match s.split_once("://") {
+            Some(("tcp", address)) => (Protocol::Tcp, address),
+            Some(("tls", address)) => (Protocol::Tls, address),
+            Some(("https", address)) => (Protocol::Https, address),
+            Some(_) => return Err(invalid()),
+            None => (Protocol::Tcp, s),
+        };
+        let (address, auth_name) = match address.split_once('#') {
+            Some((_, "")) => return Err(invalid()),
+            Some(_) if protocol == Protocol::Tcp => return Err(invalid()),
+            Some((address, name)) => (address, Some(name.to_string())),
+            None => (address, None),
+        };
+        let (address, path) = match address.find('/') {
+            Some(_) if protocol != Protocol::Https => return Err(invalid()),
+            Some(start) => (&address[..start], Some(address[start..].to_string())),
+            None => (address, None),
+        };
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => match rest.split_once(']').ok_or_else(invalid)? {
+                (host, "") => (host, None),
+                (host, port) => (host, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+            },
+            None => match address.split_once(':') {
+                Some((host, port)) if !port.contains(':') => (host, Some(port)),
+                // Either no port, or a bare IPv6 address
+                _ => (address, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => protocol.default_port(),
+        };
+        Ok(Self {
+            protocol,
+            host: host.to_string(),
+            port,
+            auth_name,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            Protocol::Tcp => (),
+            Protocol::Tls => write!(f, "tls://")?,
+            Protocol::Https => write!(f, "https://")?,
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)?;
+        } else {
+            write!(f, "{}:{}", self.host, self.port)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "{}", path)?;
+        }
+        if let Some(name) = &self.auth_name {
+            write!(f, "#{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the servers are picked for a query
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Try the servers one after the other, moving on when one fails
+    #[default]
+    Failover,
+    /// Send the query to this many servers at once and use the first
+    /// response, falling back to the other servers one after the other if
+    /// none of them answer
+    Race(usize),
+}
+
+/// How well a server has been answering
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// Number of queries answered
+    pub successes: u64,
+    /// Number of failed attempts
+    pub failures: u64,
+    /// Number of failed attempts since the last answer
+    pub consecutive_failures: u32,
+    /// Time the last answer took, connecting included
+    pub rtt: Option<Duration>,
+}
+
+impl Health {
+    /// Whether the server failed too many times in a row to be tried first
+    pub fn is_down(&self) -> bool {
+        self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES
+    }
+
+    /// Record an answer which took `rtt`
+    fn success(&mut self, rtt: Duration) {
+        self.successes += 1;
+        self.consecutive_failures = 0;
+        self.rtt = Some(rtt);
+    }
+
+    /// Record a failed attempt
+    fn failure(&mut self) {
+        self.failures += 1;
+        self.consecutive_failures += 1;
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} answered, {} failed", self.successes, self.failures)?;
+        if let Some(rtt) = self.rtt {
+            write!(f, ", last answer in {} msec", rtt.as_millis())?;
+        }
+        if self.is_down() {
+            write!(f, ", down")?;
+        }
+        Ok(())
+    }
+}
+
+/// A response, along with where it came from
+#[derive(Clone, Debug)]
+pub struct Answer {
+    /// Server which answered
+    pub server: Server,
+    /// The response itself
+    pub message: Message,
+    /// Time the query took, connecting included
+    pub rtt: Duration,
+    /// Whether the response came from the cache, rather than straight from
+    /// the server, in which case [Answer::rtt] is zero
+    pub cached: bool,
+}
+
+/// Opens the streams queries are sent over
+///
+/// This is implemented for closures taking a [Server] and returning a future
+/// of a stream, e.g. one opening a Tor stream to the server
+pub trait Connector: Send + Sync + 'static {
+    /// Stream to a server
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+    /// Future resolving to a stream
+    type Future: Future<Output = anyhow::Result<Self::Stream>> + Send + 'static;
+
+    /// Open a stream to `server`
+    fn connect(&self, server: &Server) -> Self::Future;
+}
+
+impl<F, Fut, S> Connector for F
+where
+    F: Fn(Server) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = S;
+    type Future = Fut;
+
+    fn connect(&self, server: &Server) -> Fut {
+        self(server.clone())
+    }
+}
+
+/// Timeouts and retries of the attempts at a query
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    /// How long a single attempt may take
+    timeout: Duration,
+    /// How many times a query is retried on a server
+    retries: usize,
+    /// How long connections are kept open without any query in flight
+    idle_timeout: Duration,
+    /// How queries are sent to DNS over HTTPS servers
+    doh_method: doh::Method,
+}
+
+/// An open connection to a server
+enum Link<S> {
+    /// Messages with their length prefix, for DNS over TCP and TLS
+    Stream(Connection<S>),
+    /// HTTP requests, for DNS over HTTPS
+    Http(HttpConnection),
+}
+
+impl<S> Link<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Whether the connection was closed
+    fn is_closed(&self) -> bool {
+        match self {
+            Link::Stream(connection) => connection.is_closed(),
+            Link::Http(connection) => connection.is_closed(),
+        }
+    }
+
+    /// Send `query` to `server` over this connection
+    async fn query(
+        &self,
+        server: &Server,
+        query: Message,
+        settings: Settings,
+    ) -> anyhow::Result<Message> {
+        match self {
+            Link::Stream(connection) => connection.query(query).await,
+            Link::Http(connection) => connection.query(server, &query, settings.doh_method).await,
+        }
+    }
+}
+
+/// A server, along with its connection and health
+struct Slot<S> {
+    /// Address of the server
+    server: Server,
+    /// Connection to the server, opened on first use
+    connection: tokio::sync::Mutex<Option<Arc<Link<S>>>>,
+    /// How well the server has been answering
+    health: Mutex<Health>,
+}
+
+impl<S> Slot<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Create the slot of a server which wasn't used yet
+    fn new(server: Server) -> Self {
+        Self {
+            server,
+            connection: tokio::sync::Mutex::new(None),
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// The health of the server
+    fn health(&self) -> Health {
+        self.health.lock().expect("poisoned lock").clone()
+    }
+
+    /// Get an open connection to the server, connecting if there is none
+    async fn connection<C>(
+        &self,
+        connector: &C,
+        idle_timeout: Duration,
+    ) -> anyhow::Result<Arc<Link<S>>>
+    where
+        C: Connector<Stream = S>,
+    {
+        // The lock is held while connecting, so that queries made meanwhile
+        // wait for this connection instead of opening their own
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(connection.clone());
+        }
+        debug!("Connecting to {}", self.server);
+        let stream = connector.connect(&self.server).await?;
+        let opened = Arc::new(match self.server.protocol {
+            Protocol::Tcp | Protocol::Tls => Link::Stream(Connection::new(stream, idle_timeout)),
+            Protocol::Https => Link::Http(HttpConnection::new(stream).await?),
+        });
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Send `query` to the server, retrying it on failure
+    async fn ask<C>(
+        &self,
+        connector: &C,
+        query: Message,
+        settings: Settings,
+    ) -> anyhow::Result<Answer>
+    where
+        C: Connector<Stream = S>,
+    {
+        let mut last_error = None;
+        for attempt in 0..=settings.retries {
+            if attempt > 0 {
+                debug!("Retrying query to {}", self.server);
+            }
+            let start = Instant::now();
+            let result = tokio::time::timeout(settings.timeout, async {
+                let connection = self.connection(connector, settings.idle_timeout).await?;
+                connection
+                    .query(&self.server, query.clone(), settings)
+                    .await
+            })
+            .await
+            .unwrap_or_else(|_| Err(UpstreamError::Timeout(settings.timeout).into()));
+            match result {
+                Ok(message) => {
+                    let rtt = start.elapsed();
+                    self.health.lock().expect("poisoned lock").success(rtt);
+                    return Ok(Answer {
+                        server: self.server.clone(),
+                        message,
+                        rtt,
+                        cached: false,
+                    });
+                }
+                Err(e) => {
+                    warn!("Query to {} failed: {}", self.server, e);
+                    self.health.lock().expect("poisoned lock").failure();
+                    last_error = Some(e.context(format!("query to {} failed", self.server)));
+                }
+            }
+        }
+        Err(last_error.expect("at least one attempt is made"))
+    }
+}
+
+/// The servers queries are sent to, see the [module level documentation](self)
+pub struct Upstream<C: Connector> {
+    /// The servers, in the order they were given
+    servers: Vec<Arc<Slot<C::Stream>>>,
+    /// Opens the streams to the servers
+    connector: Arc<C>,
+    /// How the servers are picked for a query
+    strategy: Strategy,
+    /// Timeouts and retries of the attempts
+    settings: Settings,
+    /// Responses received so far, if caching is enabled
+    cache: Option<Cache>,
+}
+
+impl<C: Connector> Upstream<C> {
+    /// Send queries to `servers`, opening streams to them with `connector`
+    pub fn new(servers: Vec<Server>, connector: C) -> Self {
+        Self {
+            servers: servers
+                .into_iter()
+                .map(|server| Arc::new(Slot::new(server)))
+                .collect(),
+            connector: Arc::new(connector),
+            strategy: Strategy::default(),
+            settings: Settings {
+                timeout: QUERY_TIMEOUT,
+                retries: RETRIES,
+                idle_timeout: IDLE_TIMEOUT,
+                doh_method: doh::Method::default(),
+            },
+            cache: None,
+        }
+    }
+
+    /// Pick the servers for each query with `strategy`
+    pub fn set_strategy(&mut self, strategy: Strategy) {
+        self.strategy = strategy;
+    }
+
+    /// Give up on an attempt at a query after `timeout`
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.settings.timeout = timeout;
+    }
+
+    /// Retry failed queries `retries` times on each server before moving on
+    pub fn set_retries(&mut self, retries: usize) {
+        self.settings.retries = retries;
+    }
+
+    /// Close connections once no query has been in flight for `idle_timeout`
+    ///
+    /// Connections to DNS over HTTPS servers are left for the server to close
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.settings.idle_timeout = idle_timeout;
+    }
+
+    /// Send queries to DNS over HTTPS servers with `method`
+    pub fn set_doh_method(&mut self, method: doh::Method) {
+        self.settings.doh_method = method;
+    }
+
+    /// Keep the responses in `cache`, and answer from it when possible
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = Some(cache);
+    }
+
+    /// Hit and miss counts of the cache, if caching is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(Cache::stats)
+    }
+
+    /// The servers along with their health, in the order they were given
+    pub fn health(&self) -> Vec<(Server, Health)> {
+        self.servers
+            .iter()
+            .map(|slot| (slot.server.clone(), slot.health()))
+            .collect()
+    }
+
+    /// Send `query` to `slot`, in a future which can be spawned
+    fn ask(
+        &self,
+        slot: &Arc<Slot<C::Stream>>,
+        query: &Message,
+    ) -> impl Future<Output = anyhow::Result<Answer>> + Send + 'static {
+        let slot = slot.clone();
+        let connector = self.connector.clone();
+        let query = query.clone();
+        let settings = self.settings;
+        async move { slot.ask(&*connector, query, settings).await }
+    }
+
+    /// Answer `query` from the cache, or else send it to the servers until one
+    /// of them answers
+    ///
+    /// If none does, an expired response is used if the cache still has one,
+    /// see [Cache::set_serve_stale()]
+    pub async fn query(&self, query: &Message) -> anyhow::Result<Answer> {
+        let Some(cache) = &self.cache else {
+            return self.ask_servers(query).await;
+        };
+        if let Some(answer) = cache.get(query) {
+            return Ok(answer);
+        }
+        match self.ask_servers(query).await {
+            Ok(answer) => {
+                cache.insert(query, &answer);
+                Ok(answer)
+            }
+            Err(e) => match cache.get_stale(query) {
+                Some(answer) => {
+                    warn!("Using a stale response, as {:#}", e);
+                    Ok(answer)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Send `query` to the servers until one of them answers
+    async fn ask_servers(&self, query: &Message) -> anyhow::Result<Answer> {
+        if self.servers.is_empty() {
+            return Err(UpstreamError::NoServers.into());
+        }
+        // Servers which are down go last, the others stay in the order they
+        // were given
+        let mut servers = self.servers.clone();
+        servers.sort_by_key(|slot| slot.health().is_down());
+        let racing = match self.strategy {
+            Strategy::Failover => 1,
+            Strategy::Race(count) => count.clamp(1, servers.len()),
+        };
+        let (first, rest) = servers.split_at(racing);
+        let mut last_error = None;
+        // Dropping the set cancels the queries which are still running once
+        // one of them got an answer
+        let mut race = JoinSet::new();
+        for slot in first {
+            race.spawn(self.ask(slot, query));
+        }
+        while let Some(result) = race.join_next().await {
+            match result.expect("query task panicked") {
+                Ok(answer) => return Ok(answer),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        for slot in rest {
+            match self.ask(slot, query).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .expect("at least one server is tried")
+            .context("no server answered"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{build_query, RecordType};
+    use crate::transport::testing::{response_to, serve};
+    use tokio::io::DuplexStream;
+
+    /// Connect to fake servers whose behaviour depends on their host: `fail`
+    /// can't be connected to, `mute` never answers, `slow` answers after a
+    /// while and anything else answers right away
+    async fn connector(server: Server) -> anyhow::Result<DuplexStream> {
+        if server.host == "fail" {
+            anyhow::bail!("connection refused");
+        }
+        let (client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve(stream, move |query| {
+            let host = server.host.clone();
+            async move {
+                match host.as_str() {
+                    "mute" => return None,
+                    "slow" => tokio::time::sleep(Duration::from_millis(500)).await,
+                    _ => (),
+                }
+                Some(response_to(&query))
+            }
+        }));
+        Ok(client)
+    }
+
+    /// Create an upstream using the fake servers of [connector]
+    fn upstream(servers: &[&str]) -> Upstream<impl Connector<Stream = DuplexStream>> {
+        let servers = servers.iter().map(|s| s.parse().unwrap()).collect();
+        let mut upstream = Upstream::new(servers, connector);
+        upstream.set_timeout(Duration::from_millis(100));
+        upstream
+    }
+
+    #[test]
+    fn servers_are_parsed() {
+        let parse = |s: &str| s.parse::<Server>().map(|s| (s.host, s.port)).ok();
+        assert_eq!(parse("1.1.1.1"), Some(("1.1.1.1".into(), 53)));
+        assert_eq!(
+            parse("dns.example:5353"),
+            Some(("dns.example".into(), 5353))
+        );
+        assert_eq!(
+            parse("2606:4700::1111"),
+            Some(("2606:4700::1111".into(), 53))
+        );
+        assert_eq!(
+            parse("[2606:4700::1111]:853"),
+            Some(("2606:4700::1111".into(), 853))
+        );
+        assert_eq!(parse("[::1]"), Some(("::1".into(), 53)));
+        assert_eq!(parse(":53"), None);
+        assert_eq!(parse("host:port"), None);
+        assert_eq!(parse("[::1]53"), None);
+        assert_eq!(parse("tls://9.9.9.9"), Some(("9.9.9.9".into(), 853)));
+        assert_eq!(parse("1.1.1.1#one.one.one.one"), None);
+        assert_eq!(parse("tls://1.1.1.1#"), None);
+        assert_eq!(parse("udp://1.1.1.1"), None);
+        assert_eq!(parse("tls://1.1.1.1/dns-query"), None);
+        let server: Server = "tls://[::1]:8853#dns.test".parse().unwrap();
+        assert_eq!(server.protocol, Protocol::Tls);
+        assert_eq!(server.auth_name.as_deref(), Some("dns.test"));
+        let server: Server = "https://dns.test/resolve?ct#other.test".parse().unwrap();
+        assert_eq!(server.protocol, Protocol::Https);
+        assert_eq!(server.port, 443);
+        assert_eq!(server.path.as_deref(), Some("/resolve?ct"));
+        assert_eq!(server.auth_name.as_deref(), Some("other.test"));
+        for server in [
+            "1.1.1.1:53",
+            "[::1]:53",
+            "tls://1.1.1.1:853#one.one.one.one",
+            "https://dns.quad9.net:443/dns-query",
+        ] {
+            assert_eq!(server.parse::<Server>().unwrap().to_string(), server);
+        }
+    }
+
+    #[tokio::test]
+    async fn failover_skips_failing_servers() {
+        let upstream = upstream(&["fail", "mute", "ok"]);
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let answer = upstream.query(&query).await.unwrap();
+        assert_eq!(answer.server.host, "ok");
+        let health = upstream.health();
+        // Each failing server was tried once, then retried once
+        assert_eq!(health[0].1.failures, 2);
+        assert_eq!(health[1].1.failures, 2);
+        assert_eq!(health[2].1.successes, 1);
+    }
+
+    #[tokio::test]
+    async fn servers_which_are_down_go_last() {
+        let mut upstream = upstream(&["mute", "ok"]);
+        upstream.set_retries(MAX_CONSECUTIVE_FAILURES as usize - 1);
+        let query = build_query("example.com", RecordType::A).unwrap();
+        upstream.query(&query).await.unwrap();
+        assert!(upstream.health()[0].1.is_down());
+        // The server which is down isn't tried anymore, since the other one
+        // answers
+        upstream.query(&query).await.unwrap();
+        assert_eq!(
+            upstream.health()[0].1.failures,
+            MAX_CONSECUTIVE_FAILURES as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn racing_uses_the_first_answer() {
+        let mut upstream = upstream(&["slow", "ok"]);
+        upstream.set_timeout(Duration::from_secs(5));
+        upstream.set_strategy(Strategy::Race(2));
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let answer = upstream.query(&query).await.unwrap();
+        assert_eq!(answer.server.host, "ok");
+        assert!(answer.rtt < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn failing_everywhere_is_an_error() {
+        let query = build_query("example.com", RecordType::A).unwrap();
+        let failing = upstream(&["fail", "mute"]);
+        assert!(failing.query(&query).await.is_err());
+        let empty = upstream(&[]);
+        assert!(empty.query(&query).await.is_err());
+    }
+}
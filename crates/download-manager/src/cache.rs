@@ -0,0 +1,279 @@
+//! A local cache of downloaded files, keyed by their SHA256 digest
+//!
+//! Only verified downloads are added to the cache, each in a file named after
+//! its hex encoded digest. A download whose digest is known in advance can
+//! then be served from the cache without touching the network.
+//!
+//! The cache is kept under a size limit by removing the least recently used
+//! files first. The modification time of each file is used to tell when it was
+//! last used, and is updated on every hit. Entries are always copied out of
+//! the cache rather than linked, so that neither touching an entry nor
+//! changing the copy affects the other.
+use sha2::{Digest, Sha256};
+use std::fs::{File, FileTimes};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+use tracing::{debug, info, warn};
+
+/// Default size limit of the cache, in bytes
+pub const DEFAULT_CACHE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// A directory of files named after their SHA256 digest
+pub struct Cache {
+    /// Directory holding the cached files
+    dir: PathBuf,
+    /// Total size the cached files may take, in bytes
+    max_size: u64,
+}
+
+/// Turn `digest` into the name of its cache entry, if it is a SHA256 digest
+///
+/// This makes sure the digest, which may come from the command line or the
+/// network, can't be used to point outside of the cache
+fn entry_name(digest: &str) -> Option<String> {
+    let digest = digest.to_ascii_lowercase();
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// Compute the hex encoded SHA256 digest of the file at `path`
+fn file_digest(path: &Path) -> io::Result<String> {
+    let mut sha256 = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut sha256)?;
+    Ok(format!("{:x}", sha256.finalize()))
+}
+
+impl Cache {
+    /// Use `dir` as a cache of at most `max_size` bytes, creating it if needed
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_size })
+    }
+
+    /// Find the cached file with the given digest
+    ///
+    /// The file is checked against its digest before being returned, so a
+    /// corrupted entry is dropped instead of being handed out
+    pub fn lookup(&self, digest: &str) -> io::Result<Option<PathBuf>> {
+        let Some(name) = entry_name(digest) else {
+            return Ok(None);
+        };
+        let path = self.dir.join(&name);
+        if !path.is_file() {
+            debug!("Cache miss for {}", name);
+            return Ok(None);
+        }
+        if file_digest(&path)? != name {
+            warn!("Removing corrupted cache entry {}", path.display());
+            std::fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        // Mark the entry as recently used
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_times(FileTimes::new().set_modified(SystemTime::now()))?;
+        info!("Cache hit for {}", name);
+        Ok(Some(path))
+    }
+
+    /// Put the cached file with the given digest at `destination`
+    ///
+    /// The file is copied, so the destination doesn't share anything with
+    /// the cache entry. Returns whether the file was found in the cache.
+    pub fn fetch(&self, digest: &str, destination: &Path) -> io::Result<bool> {
+        let Some(path) = self.lookup(digest)? else {
+            return Ok(false);
+        };
+        // Copy next to the destination first, so that an existing file is
+        // only replaced once we have the new one. The copy gets a name of
+        // its own, so that other fetches of the same file can't get in the
+        // way, and is removed if anything goes wrong
+        let dir = match destination.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut staging = tempfile::Builder::new()
+            .prefix(".cache-")
+            .make_in(dir, |path| {
+                File::options().write(true).create_new(true).open(path)
+            })?;
+        io::copy(&mut File::open(&path)?, staging.as_file_mut())?;
+        staging.persist(destination).map_err(|e| e.error)?;
+        Ok(true)
+    }
+
+    /// Add verified data with the given digest to the cache, evicting the
+    /// least recently used entries if that goes over the size limit
+    pub fn store(&self, digest: &str, data: &[u8]) -> io::Result<()> {
+        let Some(name) = entry_name(digest) else {
+            return Ok(());
+        };
+        if data.len() as u64 > self.max_size {
+            debug!("Not caching {}, it is larger than the cache", name);
+            return Ok(());
+        }
+        // Write to a temporary file first so that a crash can't leave a
+        // truncated entry behind. Each download gets its own, so that two
+        // downloads of the same file can't write to the same one
+        let path = self.dir.join(&name);
+        let mut staging = NamedTempFile::new_in(&self.dir)?;
+        staging.write_all(data)?;
+        staging.as_file().sync_all()?;
+        staging.persist(&path).map_err(|e| e.error)?;
+        debug!("Cached {} at {}", name, path.display());
+        self.evict()
+    }
+
+    /// Remove the least recently used entries until the cache fits in its
+    /// size limit
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let is_entry = entry.file_name().to_str().and_then(entry_name).is_some();
+            let metadata = entry.metadata()?;
+            if is_entry && metadata.is_file() {
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            info!("Evicting {} from the cache", path.display());
+            std::fs::remove_file(&path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory for the test called `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "download-manager-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Hex encoded SHA256 digest of `data`
+    fn digest(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    /// Pretend the file at `path` was last used `secs` seconds after the epoch
+    fn set_used(path: &Path, secs: u64) {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(time))
+            .unwrap();
+    }
+
+    #[test]
+    fn entries_are_looked_up_by_digest() {
+        let dir = test_dir("lookup");
+        let cache = Cache::new(dir.join("cache"), 1024).unwrap();
+        let data = b"some file";
+        assert_eq!(cache.lookup(&digest(data)).unwrap(), None);
+        cache.store(&digest(data), data).unwrap();
+        let path = cache.lookup(&digest(data)).unwrap().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        // Digests are case insensitive, and anything else is never found
+        assert_eq!(
+            cache.lookup(&digest(data).to_uppercase()).unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(cache.lookup("../cache").unwrap(), None);
+        // Corrupted entries are dropped
+        std::fs::write(&path, b"something else").unwrap();
+        assert_eq!(cache.lookup(&digest(data)).unwrap(), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn fetched_files_are_independent_copies() {
+        let dir = test_dir("fetch");
+        let cache = Cache::new(dir.join("cache"), 1024).unwrap();
+        let data = b"some file";
+        let destination = dir.join("file");
+        assert!(!cache.fetch(&digest(data), &destination).unwrap());
+        assert!(!destination.exists());
+        cache.store(&digest(data), data).unwrap();
+        std::fs::write(&destination, b"old").unwrap();
+        assert!(cache.fetch(&digest(data), &destination).unwrap());
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        // Using the entry again doesn't touch the copy, and changing the
+        // copy doesn't touch the entry
+        set_used(&destination, 1000);
+        let modified = std::fs::metadata(&destination).unwrap().modified().unwrap();
+        assert!(cache.lookup(&digest(data)).unwrap().is_some());
+        let metadata = std::fs::metadata(&destination).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        std::fs::write(&destination, b"changed").unwrap();
+        assert!(cache.lookup(&digest(data)).unwrap().is_some());
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["cache", "file"]);
+    }
+
+    #[test]
+    fn downloads_of_the_same_file_dont_share_staging_files() {
+        let dir = test_dir("staging");
+        let cache = Cache::new(dir.join("cache"), 1024).unwrap();
+        let data = b"some file";
+        // Stands in for another download of the same file, halfway through
+        // writing its entry
+        let other = NamedTempFile::new_in(dir.join("cache")).unwrap();
+        std::fs::write(other.path(), b"some").unwrap();
+        cache.store(&digest(data), data).unwrap();
+        assert_eq!(std::fs::read(other.path()).unwrap(), b"some");
+        let destination = dir.join("file");
+        assert!(cache.fetch(&digest(data), &destination).unwrap());
+        assert!(cache.fetch(&digest(data), &destination).unwrap());
+        assert_eq!(std::fs::read(&destination).unwrap(), data);
+        drop(other);
+        // Only the entry and the copy are left
+        let entries = std::fs::read_dir(dir.join("cache")).unwrap().count();
+        assert_eq!(entries, 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = test_dir("evict");
+        let cache = Cache::new(&dir, 25).unwrap();
+        let files: [&[u8]; 3] = [&[1; 10], &[2; 10], &[3; 10]];
+        cache.store(&digest(files[0]), files[0]).unwrap();
+        cache.store(&digest(files[1]), files[1]).unwrap();
+        set_used(&dir.join(digest(files[0])), 2000);
+        set_used(&dir.join(digest(files[1])), 1000);
+        // Going over the limit evicts the entry used the longest time ago
+        cache.store(&digest(files[2]), files[2]).unwrap();
+        assert!(cache.lookup(&digest(files[0])).unwrap().is_some());
+        assert!(cache.lookup(&digest(files[1])).unwrap().is_none());
+        assert!(cache.lookup(&digest(files[2])).unwrap().is_some());
+        // Files which can't fit at all aren't cached
+        cache.store(&digest(&[4; 30]), &[4; 30]).unwrap();
+        assert!(cache.lookup(&digest(&[4; 30])).unwrap().is_none());
+        assert!(cache.lookup(&digest(files[0])).unwrap().is_some());
+    }
+}
@@ -0,0 +1,161 @@
+//! Connector making the connections of a `hyper::Client` over Tor
+//!
+//! This does the same job as `arti_hyper::ArtiHttpConnector`: open a stream
+//! through Tor and set up TLS on top of it. On top of that, it tells hyper
+//! which protocol the server picked through ALPN, so that hyper speaks HTTP/2
+//! to servers which agreed to it and HTTP/1.1 to the others. It can also
+//! record the circuit of every stream it opens in [Statistics].
+use crate::stats::{circuit_path, Statistics};
+use crate::tls::TlsConnector;
+use arti_client::{DataStream, TorClient};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tls_api::{TlsConnector as TlsConnectorTrait, TlsStream, TlsStreamDyn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tor_rtcompat::PreferredRuntime;
+use tracing::debug;
+
+/// ALPN identifier of HTTP/2
+pub const ALPN_H2: &[u8] = b"h2";
+/// ALPN identifier of HTTP/1.1
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// Opens the connections of a `hyper::Client` through a [TorClient]
+#[derive(Clone)]
+pub struct TorConnector {
+    /// Client the streams are opened with
+    tor_client: TorClient<PreferredRuntime>,
+    /// Sets up TLS for `https` URLs
+    tls: Arc<TlsConnector>,
+    /// Where to record the circuit of each stream, and for which connection
+    circuits: Option<(Arc<Statistics>, usize)>,
+}
+
+impl TorConnector {
+    /// Create a connector opening streams with `tor_client`, and using `tls`
+    /// for `https` URLs
+    pub fn new(tor_client: TorClient<PreferredRuntime>, tls: TlsConnector) -> Self {
+        Self {
+            tor_client,
+            tls: Arc::new(tls),
+            circuits: None,
+        }
+    }
+
+    /// Record the circuit of every stream we open in `stats`, as the circuit
+    /// of `connection`
+    pub fn record_circuits(mut self, stats: Arc<Statistics>, connection: usize) -> Self {
+        self.circuits = Some((stats, connection));
+        self
+    }
+
+    /// Open a stream to the host of `uri`, with TLS if it is an `https` URL
+    async fn connect(self, uri: Uri) -> anyhow::Result<TorStream> {
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow::anyhow!("No host in {}", uri))?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => anyhow::bail!("Unsupported scheme in {}", uri),
+        };
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let stream = self.tor_client.connect((host, port)).await?;
+        if let Some((stats, connection)) = &self.circuits {
+            stats.set_circuit(*connection, circuit_path(&stream));
+        }
+        if !https {
+            return Ok(TorStream::Plain(stream));
+        }
+        let stream = self.tls.connect(host, stream).await?;
+        let h2 = stream.get_alpn_protocol()?.as_deref() == Some(ALPN_H2);
+        debug!(
+            "Connected to {}:{} using {}",
+            host,
+            port,
+            if h2 { "HTTP/2" } else { "HTTP/1.1" }
+        );
+        Ok(TorStream::Tls { stream, h2 })
+    }
+}
+
+impl Service<Uri> for TorConnector {
+    type Response = TorStream;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = anyhow::Result<TorStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+/// A connection opened by [TorConnector]
+pub enum TorStream {
+    /// A plain stream, for `http` URLs
+    Plain(DataStream),
+    /// A TLS stream, for `https` URLs
+    Tls {
+        /// The stream itself
+        stream: TlsStream,
+        /// Whether HTTP/2 was picked through ALPN
+        h2: bool,
+    },
+}
+
+impl Connection for TorStream {
+    fn connected(&self) -> Connected {
+        match self {
+            TorStream::Tls { h2: true, .. } => Connected::new().negotiated_h2(),
+            _ => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for TorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TorStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            TorStream::Tls { stream, .. } => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            TorStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            TorStream::Tls { stream, .. } => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TorStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            TorStream::Tls { stream, .. } => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TorStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            TorStream::Tls { stream, .. } => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
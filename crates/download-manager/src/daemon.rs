@@ -0,0 +1,1123 @@
+//! Long running download daemon with a local HTTP/JSON control API
+//!
+//! The daemon bootstraps a single [TorClient] and keeps it around, so that
+//! queued downloads don't have to wait for Tor to start every time. Jobs are
+//! run one after another in the order they were added, and the queue is saved
+//! to a JSON file after every change so that it survives restarts.
+//!
+//! The API is served on localhost by default. It is only served on other
+//! addresses if a token is set, which every request then has to carry as
+//! `Authorization: Bearer <token>`. The token is checked on localhost too
+//! when it is set.
+//!
+//! - `GET /downloads`: list all jobs
+//! - `POST /downloads`: queue a new job, the body is a [NewJob], which may
+//!   include headers and credentials. Those are saved with the queue but are
+//!   never sent back through the API, and are forgotten once the job is
+//!   completed, has failed or is cancelled. A failed job has to be added
+//!   again to be retried
+//! - `GET /downloads/:id`: inspect a single job
+//! - `POST /downloads/:id/pause`: stop a job without forgetting about it
+//! - `POST /downloads/:id/resume`: put a paused job back in the queue
+//! - `POST /downloads/:id/cancel`: stop a job for good
+//!
+//! Destinations are relative to the download directory, and can't point
+//! anywhere outside of it. Each file is downloaded to a temporary file next
+//! to its destination, which is only renamed over the destination once the
+//! download is verified, so a failed job leaves the destination untouched.
+//!
+//! The chunks a job has downloaded are saved next to its destination, as
+//! `.<file>.<id>.chunks` and `.<file>.<id>.chunks.json`, when it is paused or
+//! when the daemon is stopped with Ctrl-C (SIGINT) or SIGTERM. They are
+//! picked back up once the job is resumed, or once the daemon is started
+//! again, and removed once the job is over.
+use crate::ratelimit::RateLimiter;
+use anyhow::Context;
+use arti_client::TorClient;
+use axum::extract::{Path, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use download_manager::headers::{redact_url, RequestHeaders};
+use download_manager::partial::PartialDownload;
+use download_manager::tls::TlsConfig;
+use download_manager::{Downloader, Verification};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use tor_rtcompat::PreferredRuntime;
+use tracing::{error, info, warn};
+
+/// The state a download job can be in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum JobState {
+    /// Waiting for its turn
+    Queued,
+    /// Being downloaded right now
+    Running,
+    /// Stopped by the user, can be resumed
+    Paused,
+    /// Downloaded and verified
+    Completed,
+    /// Stopped because of an error
+    Failed,
+    /// Stopped by the user for good
+    Cancelled,
+}
+
+impl JobState {
+    /// Whether the job is over for good, in which case it doesn't need its
+    /// headers anymore
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// A single download managed by the daemon
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Job {
+    /// Identifier used to refer to the job in the API
+    id: u64,
+    /// URL of the file to download
+    url: String,
+    /// Where the file is saved
+    destination: PathBuf,
+    /// Expected SHA256 sum of the file, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// What the job is currently doing
+    state: JobState,
+    /// Number of bytes downloaded so far
+    downloaded: u64,
+    /// Size of the file, once known
+    total: u64,
+    /// Error that made the job fail, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The input to the `POST /downloads` handler
+#[derive(Deserialize)]
+struct NewJob {
+    /// URL of the file to download
+    url: String,
+    /// Where the file should be saved
+    destination: PathBuf,
+    /// Expected SHA256 sum of the file, if known
+    sha256: Option<String>,
+    /// Extra headers to send, as `Name: value`
+    #[serde(default)]
+    headers: Vec<String>,
+    /// Credentials for HTTP basic auth, as `user:password`
+    user: Option<String>,
+    /// Cookies to send, as `name=value`
+    #[serde(default)]
+    cookies: Vec<String>,
+}
+
+/// Everything that is saved to the state file
+#[derive(Serialize, Deserialize, Default)]
+struct Queue {
+    /// Identifier to give to the next job
+    next_id: u64,
+    /// All the jobs we know about, in the order they were added
+    jobs: BTreeMap<u64, Job>,
+    /// Extra headers of the jobs which have some
+    ///
+    /// These are kept apart from [Queue::jobs] as they may hold credentials,
+    /// which should be saved but never be shown through the API
+    #[serde(default)]
+    headers: BTreeMap<u64, RequestHeaders>,
+}
+
+/// Settings of the daemon, see [run()]
+pub struct Options {
+    /// Address on which to serve the API
+    pub listen: SocketAddr,
+    /// File in which the download queue is saved
+    pub state_file: PathBuf,
+    /// Directory the files are downloaded to
+    pub download_dir: PathBuf,
+    /// Token clients of the API have to send, if set
+    pub api_token: Option<String>,
+}
+
+/// What the downloads are made with
+struct Runner {
+    /// The one bootstrapped client used for every download
+    tor_client: TorClient<PreferredRuntime>,
+    /// Number of simultaneous connections used by each download
+    connections: usize,
+    /// Limits the bandwidth used by the downloads
+    limiter: Arc<RateLimiter>,
+    /// CA certificates and pins used for the TLS connections
+    tls: TlsConfig,
+}
+
+/// A job being downloaded right now
+struct RunningJob {
+    /// Aborts the task of the job
+    task: AbortHandle,
+    /// Stops the range requests of the download, see
+    /// [download_manager::DownloaderBuilder::cancellation()]
+    stop: CancellationToken,
+}
+
+/// Shared state of the daemon
+struct Daemon {
+    /// Directory the files are downloaded to, canonicalized
+    download_dir: PathBuf,
+    /// Token clients of the API have to send, if set
+    api_token: Option<String>,
+    /// Where [Daemon::queue] is saved
+    state_file: PathBuf,
+    /// The job queue
+    queue: Mutex<Queue>,
+    /// Handles to stop the running jobs
+    running: Mutex<HashMap<u64, RunningJob>>,
+    /// Wakes up [run_queue()] when a job is queued
+    wakeup: Notify,
+    /// Stops every job once cancelled, leaving them to be picked up by the
+    /// next run of the daemon
+    shutdown: CancellationToken,
+}
+
+impl Daemon {
+    /// Write the queue out to the state file
+    ///
+    /// We write to a temporary file first so that a crash can't leave us with
+    /// a half written state file
+    fn save(&self, queue: &Queue) {
+        let result = serde_json::to_vec_pretty(queue)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| {
+                let tmp = self.state_file.with_extension("tmp");
+                // The queue may hold credentials, keep it to ourselves
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&tmp)?
+                    .write_all(&contents)?;
+                std::fs::rename(&tmp, &self.state_file)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            error!("Failed to save download queue: {}", e);
+        }
+    }
+
+    /// Run `f` on the job with the given id and save the queue afterwards
+    ///
+    /// The headers of the job are dropped if it is finished after that.
+    /// Returns the updated job, or `None` if there is no such job
+    fn update_job<F: FnOnce(&mut Job)>(&self, id: u64, f: F) -> Option<Job> {
+        let mut queue = self.queue.lock().expect("poisoned lock");
+        let job = queue.jobs.get_mut(&id)?;
+        f(job);
+        let job = job.clone();
+        if job.state.is_finished() {
+            queue.headers.remove(&id);
+        }
+        self.save(&queue);
+        Some(job)
+    }
+
+    /// Tell a running job to stop, if there is one
+    ///
+    /// The job saves the chunks it has before its task finishes, so that it
+    /// can be resumed from there
+    fn pause_task(&self, id: u64) {
+        if let Some(job) = self.running.lock().expect("poisoned lock").get(&id) {
+            job.stop.cancel();
+        }
+    }
+
+    /// Stop a running job right away, if there is one, and return whether
+    /// there was
+    ///
+    /// Its range requests are told to stop as well as its task being
+    /// aborted, so that none of them keeps going on its own
+    fn stop_task(&self, id: u64) -> bool {
+        match self.running.lock().expect("poisoned lock").remove(&id) {
+            Some(job) => {
+                job.stop.cancel();
+                job.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the chunks saved by a job which is over
+    fn forget_chunks(&self, job: &Job) {
+        if let Ok(destination) = confine(&self.download_dir, &job.destination) {
+            saved_chunks(&destination, job.id).remove();
+        }
+    }
+}
+
+/// Work out where `destination` is, making sure it is inside `download_dir`
+///
+/// `download_dir` has to be canonicalized already. The directory of the
+/// destination has to exist, and is canonicalized as well, so that symbolic
+/// links can't lead outside of `download_dir` either
+fn confine(
+    download_dir: &std::path::Path,
+    destination: &std::path::Path,
+) -> anyhow::Result<PathBuf> {
+    if destination
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        anyhow::bail!("Destination {} contains '..'", destination.display());
+    }
+    let path = download_dir.join(destination);
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Destination {} has no file name", destination.display()))?;
+    let parent = path
+        .parent()
+        .unwrap_or(download_dir)
+        .canonicalize()
+        .with_context(|| format!("Directory of {} not found", destination.display()))?;
+    if !parent.starts_with(download_dir) {
+        anyhow::bail!(
+            "Destination {} is outside of {}",
+            destination.display(),
+            download_dir.display()
+        );
+    }
+    Ok(parent.join(file_name))
+}
+
+/// A temporary file a job is downloaded to, next to its destination
+///
+/// The file is removed once dropped, unless it was moved into place with
+/// [PartFile::persist()]. As it is dropped along with the task of the job,
+/// this also cleans up after paused and cancelled jobs
+struct PartFile {
+    /// Where the temporary file is
+    path: PathBuf,
+    /// Where the file goes once the download is complete
+    destination: PathBuf,
+    /// Whether the file was moved into place
+    persisted: bool,
+}
+
+impl PartFile {
+    /// Create a new temporary file for `destination`
+    ///
+    /// The file must not exist yet, so that we never write to, or later
+    /// remove, a file we didn't create ourselves
+    fn create(destination: PathBuf, id: u64) -> std::io::Result<(Self, File)> {
+        let name = destination
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let path =
+            destination.with_file_name(format!(".{}.{}-{}.part", name, id, std::process::id()));
+        let fd = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let part = PartFile {
+            path,
+            destination,
+            persisted: false,
+        };
+        Ok((part, fd))
+    }
+
+    /// Move the file over its destination
+    fn persist(mut self) -> std::io::Result<()> {
+        std::fs::rename(&self.path, &self.destination)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where the job with the given `id` saves its chunks, next to its
+/// `destination`
+///
+/// Unlike the name of its [PartFile], this stays the same across restarts
+fn saved_chunks(destination: &std::path::Path, id: u64) -> PartialDownload {
+    let name = destination
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    PartialDownload::new(destination.with_file_name(format!(".{}.{}.chunks", name, id)))
+}
+
+/// Whether the `Authorization` header of a request carries `token`
+fn authorized(token: &str, header: Option<&HeaderValue>) -> bool {
+    let Some(given) = header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Look at every byte, so that the time taken doesn't tell how much of
+    // the token was right
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Refuse requests without the API token, if one is set
+async fn require_token<B>(
+    State(daemon): State<Arc<Daemon>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    if let Some(token) = &daemon.api_token {
+        if !authorized(token, request.headers().get(AUTHORIZATION)) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Load the queue from the state file, if there is one
+///
+/// Jobs which were running when the daemon stopped are queued again, they
+/// pick up the chunks they had saved when they run
+fn load_queue(state_file: &std::path::Path) -> anyhow::Result<Queue> {
+    if !state_file.exists() {
+        return Ok(Queue::default());
+    }
+    let contents = std::fs::read(state_file)?;
+    let mut queue: Queue = serde_json::from_slice(&contents)?;
+    for job in queue.jobs.values_mut() {
+        if job.state == JobState::Running {
+            job.state = JobState::Queued;
+            job.downloaded = 0;
+        }
+    }
+    // Older state files may still hold the headers of finished jobs
+    let Queue { jobs, headers, .. } = &mut queue;
+    headers.retain(|id, _| jobs.get(id).is_some_and(|job| !job.state.is_finished()));
+    Ok(queue)
+}
+
+/// Download a single job, keeping its progress up to date
+///
+/// The file is downloaded next to its destination, and only moved into place
+/// once it is complete
+///
+/// The download stops once `stop` is cancelled, saving the chunks it has so
+/// that the next run of the job can continue from there
+async fn run_job(
+    daemon: Arc<Daemon>,
+    runner: Arc<Runner>,
+    job: Job,
+    stop: CancellationToken,
+) -> anyhow::Result<()> {
+    // Check again, the directories may have changed since the job was added
+    let destination = confine(&daemon.download_dir, &job.destination)?;
+    let partial = saved_chunks(&destination, job.id);
+    let (part, fd) = PartFile::create(destination, job.id)?;
+    let verification = match job.sha256 {
+        Some(sum) => Verification::Sha256(sum),
+        None => Verification::None,
+    };
+    let progress_daemon = daemon.clone();
+    let id = job.id;
+    let headers = daemon
+        .queue
+        .lock()
+        .expect("poisoned lock")
+        .headers
+        .get(&job.id)
+        .cloned()
+        .unwrap_or_default();
+    let downloader = Downloader::builder(runner.tor_client.clone(), job.url)
+        .destination(fd)
+        .verification(verification)
+        .connections(runner.connections)
+        .rate_limiter(runner.limiter.clone())
+        .tls_config(runner.tls.clone())
+        .headers(headers)
+        .cancellation(stop)
+        .partial_download(partial)
+        .on_progress(move |progress| {
+            // Progress isn't worth a write to disk, so we skip saving here
+            let mut queue = progress_daemon.queue.lock().expect("poisoned lock");
+            if let Some(job) = queue.jobs.get_mut(&id) {
+                job.downloaded = progress.downloaded;
+                job.total = progress.total;
+            }
+        })
+        .build()?;
+    downloader.download().await?;
+    part.persist()?;
+    Ok(())
+}
+
+/// Take jobs off the queue and run them one at a time with `run`, which is
+/// given a token that is cancelled when the job is paused or cancelled
+///
+/// Returns once the daemon is shut down, leaving the job which was running
+/// as is in the state file
+async fn run_queue<F, Fut>(daemon: Arc<Daemon>, run: F)
+where
+    F: Fn(Job, CancellationToken) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    while !daemon.shutdown.is_cancelled() {
+        // The task is started, and its handle kept, under the same lock as
+        // the job is marked as running, so that pausing or cancelling the
+        // job always finds the task to abort
+        let task = {
+            let mut queue = daemon.queue.lock().expect("poisoned lock");
+            let next = queue
+                .jobs
+                .values_mut()
+                .find(|job| job.state == JobState::Queued)
+                .map(|job| {
+                    job.state = JobState::Running;
+                    job.error = None;
+                    job.clone()
+                });
+            next.map(|job| {
+                let id = job.id;
+                info!("Starting download {}: {}", id, redact_url(&job.url));
+                let stop = daemon.shutdown.child_token();
+                let task = tokio::spawn(run(job, stop.clone()));
+                daemon.running.lock().expect("poisoned lock").insert(
+                    id,
+                    RunningJob {
+                        task: task.abort_handle(),
+                        stop,
+                    },
+                );
+                daemon.save(&queue);
+                (id, task)
+            })
+        };
+        let Some((id, task)) = task else {
+            tokio::select! {
+                _ = daemon.wakeup.notified() => {}
+                _ = daemon.shutdown.cancelled() => {}
+            }
+            continue;
+        };
+        let result = task.await;
+        daemon.running.lock().expect("poisoned lock").remove(&id);
+        if daemon.shutdown.is_cancelled() {
+            info!("Download {} interrupted, it will continue on restart", id);
+            break;
+        }
+        // A job which was paused or cancelled while its task was finishing
+        // stays that way
+        match result {
+            Ok(Ok(())) => {
+                daemon.update_job(id, |job| {
+                    if job.state == JobState::Running {
+                        info!("Download {} completed", id);
+                        job.state = JobState::Completed;
+                    }
+                });
+            }
+            Ok(Err(e)) => {
+                warn!("Download {} failed: {}", id, e);
+                daemon.update_job(id, |job| {
+                    if job.state == JobState::Running {
+                        job.state = JobState::Failed;
+                        job.error = Some(e.to_string());
+                    }
+                });
+            }
+            // The job was cancelled, whoever did that already updated its
+            // state, and the task cleaned up its file
+            Err(_) => {}
+        }
+        let job = daemon.queue.lock().expect("poisoned lock").jobs[&id].clone();
+        if job.state.is_finished() {
+            daemon.forget_chunks(&job);
+        }
+    }
+}
+
+/// List all jobs
+async fn list_jobs(State(daemon): State<Arc<Daemon>>) -> Json<Vec<Job>> {
+    let queue = daemon.queue.lock().expect("poisoned lock");
+    Json(queue.jobs.values().cloned().collect())
+}
+
+/// Queue a new job
+async fn add_job(
+    State(daemon): State<Arc<Daemon>>,
+    Json(payload): Json<NewJob>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, String)> {
+    let destination = confine(&daemon.download_dir, &payload.destination)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    let headers =
+        RequestHeaders::from_options(&payload.headers, payload.user.as_deref(), &payload.cookies)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let job = {
+        let mut queue = daemon.queue.lock().expect("poisoned lock");
+        let id = queue.next_id;
+        queue.next_id += 1;
+        let job = Job {
+            id,
+            url: payload.url,
+            destination,
+            sha256: payload.sha256,
+            state: JobState::Queued,
+            downloaded: 0,
+            total: 0,
+            error: None,
+        };
+        queue.jobs.insert(id, job.clone());
+        queue.headers.insert(id, headers);
+        daemon.save(&queue);
+        job
+    };
+    daemon.wakeup.notify_one();
+    Ok((StatusCode::CREATED, Json(job)))
+}
+
+/// Inspect a single job
+async fn get_job(
+    State(daemon): State<Arc<Daemon>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, StatusCode> {
+    let queue = daemon.queue.lock().expect("poisoned lock");
+    queue
+        .jobs
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Move a job to `to` if it is currently in one of the `from` states
+///
+/// Returns [StatusCode::CONFLICT] if the job is in some other state
+fn transition(
+    daemon: &Daemon,
+    id: u64,
+    from: &[JobState],
+    to: JobState,
+) -> Result<Json<Job>, StatusCode> {
+    let mut allowed = true;
+    let job = daemon
+        .update_job(id, |job| {
+            allowed = from.contains(&job.state);
+            if allowed {
+                job.state = to;
+            }
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if allowed {
+        Ok(Json(job))
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+/// Stop a job, it can be resumed later from the chunks it saves
+async fn pause_job(
+    State(daemon): State<Arc<Daemon>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, StatusCode> {
+    let job = transition(
+        &daemon,
+        id,
+        &[JobState::Queued, JobState::Running],
+        JobState::Paused,
+    )?;
+    daemon.pause_task(id);
+    Ok(job)
+}
+
+/// Put a paused job back in the queue
+async fn resume_job(
+    State(daemon): State<Arc<Daemon>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, StatusCode> {
+    let job = transition(&daemon, id, &[JobState::Paused], JobState::Queued)?;
+    daemon.wakeup.notify_one();
+    Ok(job)
+}
+
+/// Stop a job for good
+async fn cancel_job(
+    State(daemon): State<Arc<Daemon>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Job>, StatusCode> {
+    let job = transition(
+        &daemon,
+        id,
+        &[
+            JobState::Queued,
+            JobState::Running,
+            JobState::Paused,
+            JobState::Failed,
+        ],
+        JobState::Cancelled,
+    )?;
+    // A running job is cleaned up by run_queue() once its task is done
+    if !daemon.stop_task(id) {
+        daemon.forget_chunks(&job);
+    }
+    Ok(job)
+}
+
+/// Build the routes of the API
+fn router(daemon: Arc<Daemon>) -> Router {
+    Router::new()
+        .route("/downloads", get(list_jobs).post(add_job))
+        .route("/downloads/:id", get(get_job))
+        .route("/downloads/:id/pause", post(pause_job))
+        .route("/downloads/:id/resume", post(resume_job))
+        .route("/downloads/:id/cancel", post(cancel_job))
+        .route_layer(middleware::from_fn_with_state(
+            daemon.clone(),
+            require_token,
+        ))
+        .with_state(daemon)
+}
+
+/// Run the daemon until it is killed
+///
+/// Jobs are loaded from the state file if it exists, and the API is served on
+/// the address of `options`. Addresses other than loopback ones are refused
+/// unless an API token is set
+pub async fn run(
+    tor_client: TorClient<PreferredRuntime>,
+    connections: usize,
+    limiter: Arc<RateLimiter>,
+    tls: TlsConfig,
+    options: Options,
+) -> anyhow::Result<()> {
+    if !options.listen.ip().is_loopback() && options.api_token.is_none() {
+        anyhow::bail!(
+            "Refusing to serve the API on {} without an API token",
+            options.listen
+        );
+    }
+    let download_dir = options.download_dir.canonicalize().with_context(|| {
+        format!(
+            "Download directory {} not found",
+            options.download_dir.display()
+        )
+    })?;
+    let queue = load_queue(&options.state_file)?;
+    info!(
+        "Loaded {} jobs from {}",
+        queue.jobs.len(),
+        options.state_file.display()
+    );
+    let daemon = Arc::new(Daemon {
+        download_dir,
+        api_token: options.api_token,
+        state_file: options.state_file,
+        queue: Mutex::new(queue),
+        running: Mutex::new(HashMap::new()),
+        wakeup: Notify::new(),
+        shutdown: CancellationToken::new(),
+    });
+    let runner = Arc::new(Runner {
+        tor_client,
+        connections,
+        limiter,
+        tls,
+    });
+    let job_daemon = daemon.clone();
+    let queue = tokio::spawn(run_queue(daemon.clone(), move |job, stop| {
+        run_job(job_daemon.clone(), runner.clone(), job, stop)
+    }));
+    tokio::spawn(crate::stop_on_signal(daemon.shutdown.clone()));
+
+    info!("Listening for API requests on {}", options.listen);
+    axum::Server::bind(&options.listen)
+        .serve(router(daemon.clone()).into_make_service())
+        .with_graceful_shutdown(daemon.shutdown.cancelled())
+        .await?;
+    // Let the running job save its chunks
+    queue.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Create an empty directory for the test called `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("download-manager-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    /// Create a daemon downloading to `dir`, without any job
+    fn daemon(dir: &std::path::Path) -> Arc<Daemon> {
+        Arc::new(Daemon {
+            download_dir: dir.to_path_buf(),
+            api_token: None,
+            state_file: dir.join("state.json"),
+            queue: Mutex::new(Queue::default()),
+            running: Mutex::new(HashMap::new()),
+            wakeup: Notify::new(),
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    /// Queue a job saving to `destination`, with a cookie, and return its id
+    async fn add(daemon: &Arc<Daemon>, destination: &str) -> u64 {
+        let job = NewJob {
+            url: "https://example.com/file".to_string(),
+            destination: destination.into(),
+            sha256: None,
+            headers: Vec::new(),
+            user: None,
+            cookies: vec!["session=secret".to_string()],
+        };
+        let (_, Json(job)) = add_job(State(daemon.clone()), Json(job)).await.unwrap();
+        job.id
+    }
+
+    /// Wait for the job with the given id to get to `state`
+    async fn wait_for(daemon: &Daemon, id: u64, state: JobState) {
+        let reached = async {
+            while daemon.queue.lock().unwrap().jobs[&id].state != state {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reached)
+            .await
+            .unwrap_or_else(|_| panic!("job {} never got {:?}", id, state));
+    }
+
+    /// Sets a flag once dropped, to tell a task is over
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn destinations_are_confined() {
+        let dir = test_dir("confine");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), dir.join("link")).unwrap();
+        for (destination, expected) in [
+            ("file", dir.join("file")),
+            ("./file", dir.join("file")),
+            ("sub/file", dir.join("sub/file")),
+        ] {
+            assert_eq!(confine(&dir, destination.as_ref()).unwrap(), expected);
+        }
+        assert_eq!(
+            confine(&dir, &dir.join("sub/file")).unwrap(),
+            dir.join("sub/file")
+        );
+        for destination in [
+            "../file",
+            "sub/../../file",
+            "/etc/passwd",
+            "link/file",
+            "missing/file",
+            "",
+        ] {
+            assert!(
+                confine(&dir, destination.as_ref()).is_err(),
+                "{} was accepted",
+                destination
+            );
+        }
+    }
+
+    #[test]
+    fn part_files_are_only_moved_into_place_on_success() {
+        let dir = test_dir("part");
+        let destination = dir.join("file");
+        std::fs::write(&destination, "old").unwrap();
+        let (part, mut fd) = PartFile::create(destination.clone(), 1).unwrap();
+        let path = part.path.clone();
+        // Someone else's file with the same name is never touched
+        assert!(PartFile::create(destination.clone(), 1).is_err());
+        fd.write_all(b"half").unwrap();
+        drop(part);
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "old");
+        let (part, mut fd) = PartFile::create(destination.clone(), 1).unwrap();
+        fd.write_all(b"new").unwrap();
+        part.persist().unwrap();
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn api_tokens_are_checked() {
+        let header = |value: &str| HeaderValue::from_str(value).unwrap();
+        assert!(authorized("secret", Some(&header("Bearer secret"))));
+        assert!(!authorized("secret", Some(&header("Bearer secreT"))));
+        assert!(!authorized("secret", Some(&header("Bearer secret2"))));
+        assert!(!authorized("secret", Some(&header("Bearer "))));
+        assert!(!authorized("secret", Some(&header("Basic secret"))));
+        assert!(!authorized("secret", None));
+    }
+
+    #[tokio::test]
+    async fn jobs_complete_or_fail() {
+        let dir = test_dir("complete");
+        let daemon = daemon(&dir);
+        tokio::spawn(run_queue(daemon.clone(), |job: Job, _| async move {
+            match job.destination.ends_with("fail") {
+                true => anyhow::bail!("no luck"),
+                false => Ok(()),
+            }
+        }));
+        let ok = add(&daemon, "ok").await;
+        let failing = add(&daemon, "fail").await;
+        wait_for(&daemon, ok, JobState::Completed).await;
+        wait_for(&daemon, failing, JobState::Failed).await;
+        {
+            let queue = daemon.queue.lock().unwrap();
+            assert_eq!(queue.jobs[&failing].error.as_deref(), Some("no luck"));
+            // Finished jobs don't hold on to their credentials
+            assert!(queue.headers.is_empty());
+        }
+        // The state file is kept up to date
+        let saved = load_queue(&daemon.state_file).unwrap();
+        assert_eq!(saved.jobs[&ok].state, JobState::Completed);
+        assert!(saved.headers.is_empty());
+        // Failed jobs have to be added again
+        assert_eq!(
+            resume_job(State(daemon.clone()), Path(failing))
+                .await
+                .unwrap_err(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[tokio::test]
+    async fn paused_jobs_are_stopped_and_resumed() {
+        let dir = test_dir("pause");
+        let daemon = daemon(&dir);
+        let started = Arc::new(AtomicUsize::new(0));
+        let aborted = Arc::new(AtomicBool::new(false));
+        let (counter, flag) = (started.clone(), aborted.clone());
+        tokio::spawn(run_queue(
+            daemon.clone(),
+            move |_, stop: CancellationToken| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let flag = DropFlag(flag.clone());
+                async move {
+                    let _flag = flag;
+                    stop.cancelled().await;
+                    anyhow::bail!("interrupted")
+                }
+            },
+        ));
+        let id = add(&daemon, "file").await;
+        wait_for(&daemon, id, JobState::Running).await;
+        let Json(job) = pause_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Paused);
+        // Paused jobs keep their headers for when they are resumed
+        assert!(daemon.queue.lock().unwrap().headers.contains_key(&id));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !aborted.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the task of the paused job kept running");
+        assert!(pause_job(State(daemon.clone()), Path(id)).await.is_err());
+
+        let Json(job) = resume_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        wait_for(&daemon, id, JobState::Running).await;
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        let Json(job) = cancel_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert!(daemon.queue.lock().unwrap().headers.is_empty());
+        assert!(resume_job(State(daemon.clone()), Path(id)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn paused_jobs_make_no_more_requests() {
+        let dir = test_dir("requests");
+        let daemon = daemon(&dir);
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(run_queue(
+            daemon.clone(),
+            move |_, stop: CancellationToken| {
+                // Stands in for the range requests, which run in tasks of their
+                // own and only check the token between two requests
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    while !stop.is_cancelled() {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                });
+                std::future::pending()
+            },
+        ));
+        let id = add(&daemon, "file").await;
+        wait_for(&daemon, id, JobState::Running).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let Json(job) = pause_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Paused);
+        // Give a request which was already going a chance to finish
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let made = requests.load(Ordering::SeqCst);
+        assert!(made > 0);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), made);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_kept_until_the_job_is_over() {
+        let dir = test_dir("chunks");
+        let daemon = daemon(&dir);
+        let url = "https://example.com/file";
+        let resumed = Arc::new(AtomicBool::new(false));
+        let flag = resumed.clone();
+        tokio::spawn(run_queue(
+            daemon.clone(),
+            move |job: Job, stop: CancellationToken| {
+                let flag = flag.clone();
+                async move {
+                    let partial = saved_chunks(&job.destination, job.id);
+                    if !partial.load(url, 20, 10).is_empty() {
+                        flag.store(true, Ordering::SeqCst);
+                    }
+                    // Stands in for the downloader saving what it has once stopped
+                    stop.cancelled().await;
+                    let chunks = BTreeMap::from([(0, vec![1; 10])]);
+                    partial.save(url, 20, 10, chunks.iter())?;
+                    anyhow::bail!("interrupted")
+                }
+            },
+        ));
+        let id = add(&daemon, "file").await;
+        let chunks = saved_chunks(&dir.join("file"), id);
+        wait_for(&daemon, id, JobState::Running).await;
+        let Json(job) = pause_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Paused);
+        // The job isn't failed by being interrupted, and keeps its chunks
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while daemon.running.lock().unwrap().contains_key(&id) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            daemon.queue.lock().unwrap().jobs[&id].state,
+            JobState::Paused
+        );
+        assert_eq!(chunks.load(url, 20, 10).len(), 1);
+
+        let Json(job) = resume_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Queued);
+        wait_for(&daemon, id, JobState::Running).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !resumed.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the resumed job didn't find its chunks");
+        let Json(job) = cancel_job(State(daemon.clone()), Path(id)).await.unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while chunks.path().exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the chunks of the cancelled job were kept");
+    }
+
+    #[tokio::test]
+    async fn interrupted_jobs_continue_after_a_restart() {
+        let dir = test_dir("restart");
+        let daemon = daemon(&dir);
+        let queue = tokio::spawn(run_queue(
+            daemon.clone(),
+            |_, stop: CancellationToken| async move {
+                stop.cancelled().await;
+                anyhow::bail!("interrupted")
+            },
+        ));
+        let id = add(&daemon, "file").await;
+        wait_for(&daemon, id, JobState::Running).await;
+        daemon.shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(5), queue)
+            .await
+            .expect("the queue kept running after shutdown")
+            .unwrap();
+        let saved = load_queue(&daemon.state_file).unwrap();
+        assert_eq!(saved.jobs[&id].state, JobState::Queued);
+        assert!(saved.headers.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn jobs_paused_while_finishing_stay_paused() {
+        let dir = test_dir("finishing");
+        let daemon = daemon(&dir);
+        let pausing = daemon.clone();
+        tokio::spawn(run_queue(daemon.clone(), move |job: Job, _| {
+            let daemon = pausing.clone();
+            async move {
+                // The pause comes in once the download is done, too late
+                // to abort the task
+                if job.destination.ends_with("paused") {
+                    assert!(
+                        transition(&daemon, job.id, &[JobState::Running], JobState::Paused).is_ok()
+                    );
+                }
+                Ok(())
+            }
+        }));
+        let paused = add(&daemon, "paused").await;
+        let next = add(&daemon, "next").await;
+        // Jobs run one at a time, so the first one is settled by then
+        wait_for(&daemon, next, JobState::Completed).await;
+        assert_eq!(
+            daemon.queue.lock().unwrap().jobs[&paused].state,
+            JobState::Paused
+        );
+    }
+}
@@ -0,0 +1,932 @@
+//! The main download loop, wrapped up in a reusable [Downloader]
+//!
+//! A [Downloader] is set up with a [DownloaderBuilder], which takes the
+//! [TorClient] to make connections with, the URL to download and where to
+//! write the result, along with a few knobs to tune the download.
+use crate::cache::Cache;
+use crate::headers::RequestHeaders;
+use crate::partial::PartialDownload;
+use crate::ratelimit::RateLimiter;
+use crate::stats::Statistics;
+use crate::tls::TlsConfig;
+use crate::{
+    build_recording_hyper_client, download_segment, resolve_url, DownloadMgrError, HttpClient,
+    SegmentOptions, HTTP2_STREAMS, MAX_CONNECTIONS, MAX_RETRIES, REQSIZE,
+};
+use arti_client::TorClient;
+use futures::future::join_all;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tor_rtcompat::PreferredRuntime;
+use tracing::{debug, error, info, warn};
+
+/// Default time given to requests in flight to finish once we are asked to stop
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Default time a request may wait on the server before it is retried
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the downloaded data should be checked before it is written out
+#[derive(Clone, Debug)]
+pub enum Verification {
+    /// Don't check the data at all
+    None,
+    /// Compare the SHA256 sum of the data against this hex encoded digest,
+    /// in either case
+    Sha256(String),
+}
+
+impl Verification {
+    /// The same policy, with the digest in lower case like the ones we
+    /// compute
+    fn normalized(self) -> Self {
+        match self {
+            Verification::Sha256(digest) => Verification::Sha256(digest.to_ascii_lowercase()),
+            Verification::None => Verification::None,
+        }
+    }
+
+    /// Check the digest of the downloaded data against the policy
+    fn check(&self, sha256: Sha256) -> Result<(), DownloadMgrError> {
+        if let Verification::Sha256(expected) = self {
+            let hash_result = sha256.finalize();
+            let observed = format!("{:x}", hash_result);
+            if observed != *expected {
+                error!("Incorrect SHA 256 sum in download! Aborting");
+                return Err(DownloadMgrError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    observed,
+                });
+            }
+            debug!("SHA 256 sum of download matches");
+        }
+        Ok(())
+    }
+}
+
+/// Progress report handed to the callback set with
+/// [DownloaderBuilder::on_progress()]
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// Number of bytes downloaded so far
+    pub downloaded: u64,
+    /// Total number of bytes to download
+    pub total: u64,
+}
+
+/// Type of the callback used to report [Progress]
+type ProgressCallback = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Builder for a [Downloader]
+///
+/// Created with [Downloader::builder()]. Everything apart from the destination
+/// has a default value.
+pub struct DownloaderBuilder {
+    /// Client used to create the isolated connections
+    tor_client: TorClient<PreferredRuntime>,
+    /// URL of the file to download
+    url: String,
+    /// Where the verified data is written to
+    destination: Option<Box<dyn Write + Send>>,
+    /// How the data is checked before being written out
+    verification: Verification,
+    /// Number of simultaneous connections
+    connections: usize,
+    /// Size of each range request
+    chunk_size: u64,
+    /// Number of attempts made for each chunk
+    max_retries: usize,
+    /// Limits the bandwidth used by the download, if set
+    limiter: Option<Arc<RateLimiter>>,
+    /// Called every time a chunk is downloaded
+    on_progress: Option<ProgressCallback>,
+    /// Stops the download once cancelled
+    stop: CancellationToken,
+    /// Time given to requests in flight to finish once stopped
+    grace_period: Duration,
+    /// Time a request may wait on the server before it is retried
+    request_timeout: Duration,
+    /// Where unfinished downloads are kept, if set
+    partial: Option<PartialDownload>,
+    /// Number of chunks held back for reordering when streaming, if set
+    streaming: Option<usize>,
+    /// CA certificates and pins used for the TLS connections
+    tls: TlsConfig,
+    /// Where the statistics of each connection are recorded, if set
+    stats: Option<Arc<Statistics>>,
+    /// Where verified downloads are looked up and kept, if set
+    cache: Option<Arc<Cache>>,
+    /// Extra headers and credentials sent with every request
+    headers: RequestHeaders,
+    /// Number of requests in flight on each connection when using HTTP/2
+    http2_streams: usize,
+}
+
+impl DownloaderBuilder {
+    /// Set where the downloaded data is written to
+    pub fn destination<W: Write + Send + 'static>(mut self, destination: W) -> Self {
+        self.destination = Some(Box::new(destination));
+        self
+    }
+
+    /// Set how the downloaded data is checked, defaults to [Verification::None]
+    pub fn verification(mut self, verification: Verification) -> Self {
+        self.verification = verification.normalized();
+        self
+    }
+
+    /// Set the number of simultaneous connections, defaults to [MAX_CONNECTIONS]
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Set the size of each range request, defaults to [REQSIZE]
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Set how many times each chunk is attempted, defaults to [MAX_RETRIES]
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Limit the bandwidth used by the download
+    ///
+    /// The limiter has to be created for at least as many connections as set
+    /// with [DownloaderBuilder::connections()], or building fails
+    pub fn rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Set a callback to be run every time a chunk has been downloaded
+    pub fn on_progress<F: Fn(Progress) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Set a token which stops the download once cancelled
+    ///
+    /// No new range requests are made after that, and the ones in flight are
+    /// dropped if they haven't finished within the grace period. The download
+    /// then fails with [DownloadMgrError::Interrupted]
+    pub fn cancellation(mut self, stop: CancellationToken) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Set how long requests in flight may take to finish once the download
+    /// is stopped, defaults to [GRACE_PERIOD]
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Set how long a request may wait for the server, either for the
+    /// response or for more of its body, before it is counted as timed out
+    /// and retried, defaults to [REQUEST_TIMEOUT]
+    ///
+    /// Time spent waiting on the rate limits doesn't count
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Save the chunks of an unfinished download to disk, and pick them back
+    /// up on the next attempt
+    pub fn partial_download(mut self, partial: PartialDownload) -> Self {
+        self.partial = Some(partial);
+        self
+    }
+
+    /// Write the data out in order while the download is still going on,
+    /// instead of holding on to all of it until it is verified
+    ///
+    /// At most `reorder_buffer` chunks are requested ahead of the one that
+    /// is due to be written next, which bounds the memory used. Note that the
+    /// data is written before it could be checked, a verification failure is
+    /// only reported once everything has been written. Saving unfinished
+    /// downloads with [DownloaderBuilder::partial_download()] isn't supported
+    /// in this mode, building fails if both are set.
+    pub fn streaming(mut self, reorder_buffer: usize) -> Self {
+        self.streaming = Some(reorder_buffer.max(1));
+        self
+    }
+
+    /// Set the extra CA certificates and pinned keys used for the TLS
+    /// connections, defaults to the roots of the TLS backend only
+    pub fn tls_config(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Record the statistics of each connection, along with the relays of
+    /// its circuit, see [Statistics]
+    ///
+    /// Like the rate limiter, the statistics have to be created for at least
+    /// as many connections as set with [DownloaderBuilder::connections()], or
+    /// building fails
+    pub fn statistics(mut self, stats: Arc<Statistics>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Look the file up in a [Cache] before downloading it, and add it to
+    /// the cache once it is verified
+    ///
+    /// Only downloads verified with [Verification::Sha256] can be cached, and
+    /// they are only added to it when not streaming
+    pub fn cache(mut self, cache: Arc<Cache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Set extra headers and credentials to send with every request
+    ///
+    /// Credentials are only sent to the origin of the URL, see
+    /// [RequestHeaders] for details
+    pub fn headers(mut self, headers: RequestHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Set how many range requests may be in flight at once on each
+    /// connection when HTTP/2 is enabled, defaults to [HTTP2_STREAMS]
+    ///
+    /// HTTP/2 is enabled with [TlsConfig::set_http2()]. The requests are then
+    /// multiplexed over a single TLS session per circuit. If a server sticks
+    /// to HTTP/1.1, each of these requests gets its own stream instead.
+    pub fn http2_streams(mut self, streams: usize) -> Self {
+        self.http2_streams = streams.max(1);
+        self
+    }
+
+    /// Create the [Downloader]
+    ///
+    /// Fails if no destination was set, if the rate limiter or statistics
+    /// are too small for the number of connections, or if a streamed download is to be
+    /// saved as a partial download
+    pub fn build(self) -> Result<Downloader, DownloadMgrError> {
+        let destination = self
+            .destination
+            .ok_or(DownloadMgrError::MissingDestination)?;
+        if self.streaming.is_some() && self.partial.is_some() {
+            return Err(DownloadMgrError::StreamingPartialDownload);
+        }
+        let connections = self.connections;
+        if let Some(limiter) = &self.limiter {
+            if limiter.connections() < connections {
+                return Err(DownloadMgrError::LimiterTooSmall {
+                    limiter: limiter.connections(),
+                    connections,
+                });
+            }
+        }
+        if let Some(stats) = &self.stats {
+            if stats.connections() < connections {
+                return Err(DownloadMgrError::StatisticsTooSmall {
+                    statistics: stats.connections(),
+                    connections,
+                });
+            }
+        }
+        Ok(Downloader {
+            tor_client: self.tor_client,
+            url: self.url,
+            destination,
+            verification: self.verification,
+            connections,
+            chunk_size: self.chunk_size,
+            max_retries: self.max_retries,
+            limiter: self
+                .limiter
+                .unwrap_or_else(|| Arc::new(RateLimiter::new(0, 0, connections))),
+            on_progress: self.on_progress,
+            stop: self.stop,
+            grace_period: self.grace_period,
+            request_timeout: self.request_timeout,
+            partial: self.partial,
+            streaming: self.streaming,
+            tls: self.tls,
+            stats: self.stats,
+            cache: self.cache,
+            headers: self.headers,
+            http2_streams: self.http2_streams,
+        })
+    }
+}
+
+/// Downloads a single file over several Tor connections
+///
+/// See [DownloaderBuilder] for the available settings
+pub struct Downloader {
+    /// Client used to create the isolated connections
+    tor_client: TorClient<PreferredRuntime>,
+    /// URL of the file to download
+    url: String,
+    /// Where the verified data is written to
+    destination: Box<dyn Write + Send>,
+    /// How the data is checked before being written out
+    verification: Verification,
+    /// Number of simultaneous connections
+    connections: usize,
+    /// Size of each range request
+    chunk_size: u64,
+    /// Number of attempts made for each chunk
+    max_retries: usize,
+    /// Limits the bandwidth used by the download
+    limiter: Arc<RateLimiter>,
+    /// Called every time a chunk is downloaded
+    on_progress: Option<ProgressCallback>,
+    /// Stops the download once cancelled
+    stop: CancellationToken,
+    /// Time given to requests in flight to finish once stopped
+    grace_period: Duration,
+    /// Time a request may wait on the server before it is retried
+    request_timeout: Duration,
+    /// Where unfinished downloads are kept, if set
+    partial: Option<PartialDownload>,
+    /// Number of chunks held back for reordering when streaming, if set
+    streaming: Option<usize>,
+    /// CA certificates and pins used for the TLS connections
+    tls: TlsConfig,
+    /// Where the statistics of each connection are recorded, if set
+    stats: Option<Arc<Statistics>>,
+    /// Where verified downloads are looked up and kept, if set
+    cache: Option<Arc<Cache>>,
+    /// Extra headers and credentials sent with every request
+    headers: RequestHeaders,
+    /// Number of requests in flight on each connection when using HTTP/2
+    http2_streams: usize,
+}
+
+/// Result of the task downloading a single chunk, made up of the offset of
+/// the chunk and its data
+type ChunkResult = Result<(usize, Vec<u8>), DownloadMgrError>;
+
+/// What is needed to spawn the tasks downloading each chunk
+struct ChunkTasks {
+    /// URL of the file to download
+    url: String,
+    /// Total size of the file
+    length: u64,
+    /// The connections to cycle through, each with a semaphore that only
+    /// lets one request through at a time, or a few with HTTP/2
+    connections: Vec<(HttpClient, Arc<Semaphore>)>,
+    /// Settings for each [download_segment()] call
+    options: SegmentOptions,
+    /// Number of bytes downloaded so far
+    downloaded: Arc<AtomicU64>,
+    /// Called every time a chunk is downloaded
+    on_progress: Option<ProgressCallback>,
+    /// Every task spawned for the download, aborted once we are dropped
+    running: Vec<AbortHandle>,
+}
+
+impl ChunkTasks {
+    /// Keep track of `task`, so that it doesn't outlive the download
+    fn track<T>(&mut self, task: JoinHandle<T>) -> JoinHandle<T> {
+        self.running.push(task.abort_handle());
+        task
+    }
+
+    /// Spawn a task which downloads the bytes from `start` to `end` on the
+    /// given connection
+    fn spawn(&mut self, taskid: usize, start: usize, end: usize) -> JoinHandle<ChunkResult> {
+        let (newhttp, permit) = self.connections[taskid].clone();
+        let urlclone = self.url.clone();
+        let options = self.options.clone();
+        let downloaded = self.downloaded.clone();
+        let on_progress = self.on_progress.clone();
+        let length = self.length;
+        self.track(tokio::spawn(async move {
+            // Only one request in flight per connection, unless multiplexed
+            let _permit = permit
+                .acquire_owned()
+                .await
+                .map_err(|_| DownloadMgrError::ConnectionError)?;
+            let body = download_segment(urlclone, start, end, newhttp, taskid, &options).await?;
+            let so_far =
+                downloaded.fetch_add(body.len() as u64, Ordering::Relaxed) + body.len() as u64;
+            if let Some(callback) = on_progress {
+                callback(Progress {
+                    downloaded: so_far,
+                    total: length,
+                });
+            }
+            Ok((start, body))
+        }))
+    }
+}
+
+impl Drop for ChunkTasks {
+    /// Abort the requests still in flight, which happens when the download
+    /// fails early or is itself dropped
+    fn drop(&mut self) {
+        for task in &self.running {
+            task.abort();
+        }
+    }
+}
+
+/// Write the chunks `spawn` downloads for each of `ranges` to `destination`,
+/// strictly in order, returning the digest and length of what was written
+///
+/// Chunks are requested in order, and at most `reorder_buffer` of them are
+/// in flight or waiting for an earlier chunk at any time. The first chunk
+/// which fails, or doesn't start where expected, fails the whole thing
+async fn write_in_order<F>(
+    destination: &mut (dyn Write + Send),
+    mut ranges: impl Iterator<Item = (usize, usize)>,
+    reorder_buffer: usize,
+    mut spawn: F,
+) -> anyhow::Result<(Sha256, usize)>
+where
+    F: FnMut(usize, usize) -> JoinHandle<ChunkResult>,
+{
+    let mut pending: VecDeque<JoinHandle<ChunkResult>> = VecDeque::new();
+    let mut sha256 = Sha256::new();
+    let mut written = 0;
+    loop {
+        // Keep the buffer full
+        while pending.len() < reorder_buffer {
+            let Some((start, end)) = ranges.next() else {
+                break;
+            };
+            pending.push_back(spawn(start, end));
+        }
+        let Some(task) = pending.pop_front() else {
+            break;
+        };
+        let (start, body) = match task.await {
+            Ok(Ok(chunk)) => chunk,
+            failure => {
+                error!("Possible missing chunk! Aborting");
+                return Err(match failure {
+                    Ok(Err(e)) => e.into(),
+                    _ => DownloadMgrError::MissingChunk.into(),
+                });
+            }
+        };
+        if start != written {
+            error!("Mismatch in expected and observed offset! Aborting");
+            return Err(DownloadMgrError::MissingChunk.into());
+        }
+        debug!("Writing chunk offset {} to destination...", start);
+        sha256.update(&body);
+        destination.write_all(&body)?;
+        written += body.len();
+    }
+    Ok((sha256, written))
+}
+
+/// Split `length` bytes into (start, end) ranges of `chunk_size` bytes
+///
+/// Like HTTP ranges, both ends are included
+fn chunk_ranges(length: u64, chunk_size: usize) -> impl Iterator<Item = (usize, usize)> {
+    let length = length as usize;
+    (0..length).step_by(chunk_size).map(move |start| {
+        // the upper bound of what block we need from the server
+        let end = (start + chunk_size - 1).min(length - 1);
+        (start, end)
+    })
+}
+
+impl Downloader {
+    /// Start building a [Downloader] for `url`, making connections with `tor_client`
+    pub fn builder(
+        tor_client: TorClient<PreferredRuntime>,
+        url: impl Into<String>,
+    ) -> DownloaderBuilder {
+        DownloaderBuilder {
+            tor_client,
+            url: url.into(),
+            destination: None,
+            verification: Verification::None,
+            connections: MAX_CONNECTIONS,
+            chunk_size: REQSIZE,
+            max_retries: MAX_RETRIES,
+            limiter: None,
+            on_progress: None,
+            stop: CancellationToken::new(),
+            grace_period: GRACE_PERIOD,
+            request_timeout: REQUEST_TIMEOUT,
+            partial: None,
+            streaming: None,
+            tls: TlsConfig::default(),
+            stats: None,
+            cache: None,
+            headers: RequestHeaders::default(),
+            http2_streams: HTTP2_STREAMS,
+        }
+    }
+
+    /// Run the download, returning the number of bytes written
+    ///
+    /// Summary:
+    ///
+    /// 0. If the file is already in the [Cache], write it out from there and
+    ///    skip the rest
+    ///
+    /// 1. Follow any redirects to find where the file really is, and get its
+    ///    content length so we know how many requests to make
+    ///
+    /// 2. Create the configured number of connections, these will be all
+    ///    that is used for the main loop
+    ///
+    /// 3. Cycle through the connections we initialized in step 2 and make a
+    ///    range request with them for each chunk of the file, one request at
+    ///    a time on each connection, or a few at a time over HTTP/2
+    ///
+    /// 4. Check the downloaded data against the [Verification] policy and
+    ///    write it to the destination, see [Downloader::download_buffered()]
+    ///    and [Downloader::download_streaming()] for how that is done
+    pub async fn download(mut self) -> anyhow::Result<u64> {
+        if let Some(written) = self.copy_from_cache()? {
+            return Ok(written);
+        }
+        // Every range request goes to the URL we were redirected to, but the
+        // partial download is still saved under the URL we were asked for
+        let resolved = resolve_url(&self.url, &self.tor_client, &self.tls, &self.headers).await?;
+        let length = resolved.length;
+
+        // Initialize the connections we will use for this download, which
+        // record the circuit of each of their streams. With HTTP/2, several
+        // requests can share each connection
+        let streams = if self.tls.http2() {
+            self.http2_streams
+        } else {
+            1
+        };
+        let stats = self
+            .stats
+            .clone()
+            .unwrap_or_else(|| Arc::new(Statistics::new(self.connections)));
+        let mut connections: Vec<(HttpClient, Arc<Semaphore>)> =
+            Vec::with_capacity(self.connections);
+        for connection in 0..self.connections {
+            let newhttp = build_recording_hyper_client(
+                self.tor_client.isolated_client(),
+                &self.tls,
+                stats.clone(),
+                connection,
+            )?;
+            connections.push((newhttp, Arc::new(Semaphore::new(streams))));
+        }
+
+        // Once we are asked to stop, give the requests in flight some time
+        // before dropping them
+        let options = SegmentOptions {
+            limiter: self.limiter.clone(),
+            max_retries: self.max_retries,
+            stop: self.stop.clone(),
+            abort: CancellationToken::new(),
+            stats,
+            request_timeout: self.request_timeout,
+            headers: Arc::new(if resolved.cross_origin {
+                self.headers.without_credentials()
+            } else {
+                self.headers.clone()
+            }),
+        };
+        let watchdog = {
+            let stop = options.stop.clone();
+            let abort = options.abort.clone();
+            let grace_period = self.grace_period;
+            tokio::spawn(async move {
+                stop.cancelled().await;
+                info!("Stopping download, waiting for requests in flight...");
+                tokio::time::sleep(grace_period).await;
+                abort.cancel();
+            })
+        };
+        let mut tasks = ChunkTasks {
+            url: resolved.url,
+            length,
+            connections,
+            options,
+            downloaded: Arc::new(AtomicU64::new(0)),
+            on_progress: self.on_progress.clone(),
+            running: Vec::new(),
+        };
+        tasks.track(watchdog);
+        match self.streaming {
+            Some(reorder_buffer) => self.download_streaming(tasks, reorder_buffer).await,
+            None => self.download_buffered(tasks).await,
+        }
+    }
+
+    /// Download every chunk, and only write the file out once all of it is
+    /// there and verified
+    ///
+    /// Chunks saved by an earlier attempt are picked up instead of being
+    /// requested again, and if some chunks are still missing at the end, the
+    /// ones we have are saved for later
+    async fn download_buffered(mut self, mut tasks: ChunkTasks) -> anyhow::Result<u64> {
+        let length = tasks.length;
+        let mut chunks = match &self.partial {
+            Some(partial) => partial.load(&self.url, length, self.chunk_size),
+            None => BTreeMap::new(),
+        };
+        let already_downloaded: u64 = chunks.values().map(|chunk| chunk.len() as u64).sum();
+        if already_downloaded != 0 {
+            info!(
+                "Continuing download, {} bytes already done",
+                already_downloaded
+            );
+        }
+        tasks
+            .downloaded
+            .store(already_downloaded, Ordering::Relaxed);
+
+        let chunk_size = self.chunk_size as usize;
+        let mut downloadtasks = Vec::new();
+        let mut taskid = 0;
+        for (start, end) in chunk_ranges(length, chunk_size) {
+            if chunks.contains_key(&start) {
+                continue;
+            }
+            downloadtasks.push(tasks.spawn(taskid, start, end));
+            taskid = (taskid + 1) % self.connections;
+        }
+        let results_options: Vec<Result<_, _>> = join_all(downloadtasks)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        // if we got an Error from network operations, that means we don't have entire file
+        // thus we keep what we have for later and return an error instead of
+        // writing out a partial file
+        let mut has_err = false;
+        for result in results_options {
+            match result {
+                Ok((start, body)) => {
+                    chunks.insert(start, body);
+                }
+                Err(_) => has_err = true,
+            }
+        }
+        if has_err {
+            if let Some(partial) = &self.partial {
+                partial.save(&self.url, length, self.chunk_size, chunks.iter())?;
+                info!(
+                    "Saved {} completed chunks to {}",
+                    chunks.len(),
+                    partial.path().display()
+                );
+            }
+            if self.stop.is_cancelled() {
+                return Err(DownloadMgrError::Interrupted.into());
+            }
+            error!("Possible missing chunk! Aborting");
+            return Err(DownloadMgrError::MissingChunk.into());
+        }
+        let mut file_vec: Vec<u8> = Vec::with_capacity(length as usize);
+        // write all chunks to memory representation of file, checking along the
+        // way if the offsets and lengths match our expectations
+        if chunks.len() != chunk_ranges(length, chunk_size).count() {
+            error!("Unexpected number of chunks! Aborting");
+            return Err(DownloadMgrError::MissingChunk.into());
+        }
+        for ((start, chunk), (start_check, end_check)) in
+            chunks.iter().zip(chunk_ranges(length, chunk_size))
+        {
+            if *start != start_check || chunk.len() != end_check - start_check + 1 {
+                error!("Mismatch in expected and observed chunk! Aborting");
+                return Err(DownloadMgrError::MissingChunk.into());
+            }
+            debug!(
+                "Writing chunk offset {} to memory representation of file...",
+                start
+            );
+            file_vec.extend(chunk);
+        }
+        // The saved chunks are all in file_vec now, and are of no use if
+        // the data turns out to be bad
+        if let Some(partial) = &self.partial {
+            partial.remove();
+        }
+
+        // Verify downloaded content's checksum
+        let mut sha256 = Sha256::new();
+        sha256.update(&file_vec);
+        self.verify(sha256)?;
+        if let (Some(cache), Verification::Sha256(digest)) = (&self.cache, &self.verification) {
+            if let Err(e) = cache.store(digest, &file_vec) {
+                warn!("Failed to add download to the cache: {}", e);
+            }
+        }
+        // Write validated data out
+        info!("Writing downloaded content to destination");
+        self.destination.write_all(&file_vec)?;
+        self.destination.flush()?;
+        Ok(file_vec.len() as u64)
+    }
+
+    /// Write the chunks out strictly in order while the rest are still being
+    /// downloaded, verifying the data once it has all been written
+    ///
+    /// At most `reorder_buffer` chunks are in flight or waiting for an
+    /// earlier chunk at any time, see [write_in_order()]
+    async fn download_streaming(
+        mut self,
+        mut tasks: ChunkTasks,
+        reorder_buffer: usize,
+    ) -> anyhow::Result<u64> {
+        if !matches!(self.verification, Verification::None) {
+            warn!("Streaming mode: data is written out before it is verified!");
+        }
+        let ranges = chunk_ranges(tasks.length, self.chunk_size as usize);
+        let connections = self.connections;
+        let mut taskid = 0;
+        let result = write_in_order(
+            &mut self.destination,
+            ranges,
+            reorder_buffer,
+            |start, end| {
+                let task = tasks.spawn(taskid, start, end);
+                taskid = (taskid + 1) % connections;
+                task
+            },
+        )
+        .await;
+        let (sha256, written) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                // Nothing after this chunk can be written, so don't wait
+                // for the rest
+                tasks.options.abort.cancel();
+                if self.stop.is_cancelled() {
+                    return Err(DownloadMgrError::Interrupted.into());
+                }
+                return Err(e);
+            }
+        };
+        self.destination.flush()?;
+        self.verify(sha256)?;
+        Ok(written as u64)
+    }
+
+    /// Write the file out from the cache if it is there, returning the number
+    /// of bytes written
+    fn copy_from_cache(&mut self) -> anyhow::Result<Option<u64>> {
+        let (Some(cache), Verification::Sha256(digest)) = (&self.cache, &self.verification) else {
+            return Ok(None);
+        };
+        let Some(path) = cache.lookup(digest)? else {
+            return Ok(None);
+        };
+        info!("Copying {} from the cache", path.display());
+        let written = std::io::copy(&mut std::fs::File::open(path)?, &mut self.destination)?;
+        self.destination.flush()?;
+        Ok(Some(written))
+    }
+
+    /// Check the digest of the downloaded data against the [Verification] policy
+    fn verify(&self, sha256: Sha256) -> Result<(), DownloadMgrError> {
+        self.verification.check(sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_ranges_stop_at_the_last_byte() {
+        let ranges: Vec<_> = chunk_ranges(25, 10).collect();
+        assert_eq!(ranges, [(0, 9), (10, 19), (20, 24)]);
+        let ranges: Vec<_> = chunk_ranges(20, 10).collect();
+        assert_eq!(ranges, [(0, 9), (10, 19)]);
+        let ranges: Vec<_> = chunk_ranges(1, 10).collect();
+        assert_eq!(ranges, [(0, 0)]);
+        assert_eq!(chunk_ranges(0, 10).count(), 0);
+    }
+
+    #[test]
+    fn digests_are_checked_in_either_case() {
+        let data = b"some file";
+        let digest = format!("{:x}", Sha256::digest(data));
+        let check = |expected: String| {
+            let mut sha256 = Sha256::new();
+            sha256.update(data);
+            Verification::Sha256(expected).normalized().check(sha256)
+        };
+        assert!(check(digest.clone()).is_ok());
+        assert!(check(digest.to_uppercase()).is_ok());
+        assert!(matches!(
+            check("00".repeat(32)),
+            Err(DownloadMgrError::ChecksumMismatch { .. })
+        ));
+        assert!(Verification::None.check(Sha256::new()).is_ok());
+    }
+
+    /// A destination which can be looked at while it is written to
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_finishing_out_of_order_are_written_in_order() {
+        let buffer = SharedBuffer::default();
+        let written = buffer.0.clone();
+        let mut spawned = 0;
+        let ranges = chunk_ranges(95, 10);
+        let (sha256, length) = write_in_order(&mut buffer.clone(), ranges, 3, |start, end| {
+            spawned += 1;
+            // Never more than the reorder buffer ahead of what is written
+            let done = written.lock().unwrap().len() / 10;
+            assert!(spawned - done <= 3, "{} chunks ahead", spawned - done);
+            tokio::spawn(async move {
+                // Each chunk of the buffer finishes before the one before it
+                let delay = 3 - (start / 10) % 3;
+                tokio::time::sleep(Duration::from_millis(5 * delay as u64)).await;
+                Ok((start, vec![(start / 10) as u8; end - start + 1]))
+            })
+        })
+        .await
+        .unwrap();
+        let expected: Vec<u8> = (0..10u8)
+            .flat_map(|chunk| vec![chunk; if chunk == 9 { 5 } else { 10 }])
+            .collect();
+        assert_eq!(length, 95);
+        assert_eq!(*buffer.0.lock().unwrap(), expected);
+        assert_eq!(sha256.finalize(), Sha256::digest(&expected));
+        assert_eq!(spawned, 10);
+    }
+
+    #[tokio::test]
+    async fn missing_chunks_stop_the_stream() {
+        let mut buffer = Vec::new();
+        let mut spawned = 0;
+        let result = write_in_order(&mut buffer, chunk_ranges(50, 10), 2, |start, end| {
+            spawned += 1;
+            tokio::spawn(async move {
+                match start {
+                    20 => Err(DownloadMgrError::DownloadError),
+                    _ => Ok((start, vec![0; end - start + 1])),
+                }
+            })
+        })
+        .await;
+        assert!(result.is_err());
+        // What came before the missing chunk is out, nothing after it
+        assert_eq!(buffer.len(), 20);
+        assert!(spawned < 5);
+    }
+
+    #[tokio::test]
+    async fn requests_stop_once_the_download_is_dropped() {
+        let mut tasks = ChunkTasks {
+            url: "https://example.com/file".to_string(),
+            length: 0,
+            connections: Vec::new(),
+            options: SegmentOptions {
+                limiter: Arc::new(RateLimiter::new(0, 0, 1)),
+                max_retries: MAX_RETRIES,
+                stop: CancellationToken::new(),
+                abort: CancellationToken::new(),
+                stats: Arc::new(Statistics::new(1)),
+                headers: Arc::new(RequestHeaders::default()),
+                request_timeout: REQUEST_TIMEOUT,
+            },
+            downloaded: Arc::new(AtomicU64::new(0)),
+            on_progress: None,
+            running: Vec::new(),
+        };
+        // Stands in for a chunk request which never finishes
+        let requests = Arc::new(AtomicU64::new(0));
+        let counter = requests.clone();
+        let task = tasks.track(tokio::spawn(async move {
+            loop {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(tasks);
+        assert!(task.await.unwrap_err().is_cancelled());
+        let made = requests.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), made);
+    }
+}
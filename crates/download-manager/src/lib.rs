@@ -0,0 +1,797 @@
+#![warn(clippy::missing_docs_in_private_items)]
+//! # download-manager
+//! Use Tor to download files in parallel over several isolated circuits
+//!
+//! ### Intro
+//! This is a project intended to illustrate how Arti can be used to tunnel an HTTPS
+//! based project through Tor and also some of the design choices that go into making that
+//! happen, most notably, the usage of isolated clients to create different connections
+//! which won't lock each other up or run into some Arti shared state issues.
+//!
+//! ### Library
+//! The building blocks used by the `download-manager` binary are exposed here
+//! so that other tools can reuse them. Most users will want [Downloader],
+//! which is configured through [DownloaderBuilder]:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use download_manager::{create_tor_client, Downloader, Verification};
+//!
+//! let tor_client = create_tor_client().await?;
+//! let file = std::fs::File::create("download.tar.xz")?;
+//! let downloader = Downloader::builder(tor_client, "https://example.com/file.tar.xz")
+//!     .destination(file)
+//!     .verification(Verification::Sha256("<expected hex digest>".to_string()))
+//!     .connections(4)
+//!     .on_progress(|progress| println!("{}/{}", progress.downloaded, progress.total))
+//!     .build()?;
+//! downloader.download().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ### Disclaimer
+//! The download manager showcased is not really meant for production. It is simply an example of how Arti
+//! can be utilized. Many features you would expect from a real download manager aren't present. Don't
+//! use it for any real usage other than academic
+use arti_client::{TorClient, TorClientConfig};
+use hyper::body::HttpBody;
+use hyper::client::connect::Connect;
+use hyper::header::LOCATION;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tor_rtcompat::PreferredRuntime;
+use tracing::{debug, info, warn};
+
+pub mod cache;
+pub mod connector;
+mod downloader;
+pub mod headers;
+pub mod partial;
+pub mod ratelimit;
+pub mod release;
+pub mod stats;
+pub mod tls;
+
+use connector::TorConnector;
+pub use downloader::{
+    Downloader, DownloaderBuilder, Progress, Verification, GRACE_PERIOD, REQUEST_TIMEOUT,
+};
+use headers::{redact_url, RequestHeaders};
+use ratelimit::RateLimiter;
+use stats::Statistics;
+use tls::TlsConfig;
+
+/// REQSIZE is just the size of each chunk we get from a particular circuit
+pub const REQSIZE: u64 = 1024 * 1024;
+/// Default number of simultaneous connections that are made
+pub const MAX_CONNECTIONS: usize = 6;
+/// Default number of retries to make if a particular request failed
+pub const MAX_RETRIES: usize = 6;
+/// Default number of range requests in flight on each connection, when it
+/// speaks HTTP/2
+pub const HTTP2_STREAMS: usize = 4;
+/// Number of redirects followed before giving up on a URL
+pub const MAX_REDIRECTS: usize = 10;
+
+/// The `hyper::Client` type used for every request we send over Tor
+pub type HttpClient = Client<TorConnector>;
+
+#[derive(thiserror::Error, Debug)]
+#[error("Download Manager Error")]
+/// Enum storing all the Errors that our program can raise
+pub enum DownloadMgrError {
+    #[error("Download failed due to unspecified reason")]
+    /// Blanket download error to catch almost all download errors
+    DownloadError,
+    #[error("Got unexpected status code")]
+    /// Error to represent an unexpected status code from the network
+    RequestFailed {
+        /// The status code that we got instead of the intended one
+        status: StatusCode,
+    },
+    /// Error to represent raw bytes properly
+    #[error("Unable to read body into bytes")]
+    BodyDownload {
+        /// Error raised while reading body into bytes, wraps [hyper::Error]`
+        error: hyper::Error,
+    },
+    #[error("Failed to get a connection from pool")]
+    /// Used to denote a failed .get() request from `Vec<Client>`
+    ConnectionError,
+    #[error("Possible missing chunk")]
+    /// Used when a chunk couldn't be downloaded, or doesn't start or end where expected
+    MissingChunk,
+    #[error("Incorrect SHA 256 sum in download")]
+    /// The downloaded data doesn't match the digest it was meant to have
+    ChecksumMismatch {
+        /// The digest we were told to expect
+        expected: String,
+        /// The digest of what we actually downloaded
+        observed: String,
+    },
+    #[error("No destination set for download")]
+    /// [DownloaderBuilder::build()] was called without a destination
+    MissingDestination,
+    #[error("Rate limiter is too small for the number of connections")]
+    /// The [RateLimiter] given to [DownloaderBuilder::rate_limiter()] has
+    /// fewer per-connection limits than there are connections
+    LimiterTooSmall {
+        /// Number of connections the limiter was created for
+        limiter: usize,
+        /// Number of connections of the download
+        connections: usize,
+    },
+    #[error("Statistics are too small for the number of connections")]
+    /// The [Statistics] given to [DownloaderBuilder::statistics()] cover
+    /// fewer connections than there are
+    StatisticsTooSmall {
+        /// Number of connections the statistics were created for
+        statistics: usize,
+        /// Number of connections of the download
+        connections: usize,
+    },
+    #[error("Partial downloads can't be saved when streaming")]
+    /// [DownloaderBuilder::streaming()] and
+    /// [DownloaderBuilder::partial_download()] were both set
+    StreamingPartialDownload,
+    #[error("Range request returned the wrong amount of data")]
+    /// A range request returned more or less data than was asked for
+    WrongChunkLength {
+        /// Number of bytes in the requested range
+        expected: usize,
+        /// Number of bytes we got
+        received: usize,
+    },
+    #[error("Download interrupted")]
+    /// The download was asked to stop before it was complete
+    Interrupted,
+    #[error("Too many redirects")]
+    /// We were redirected more than [MAX_REDIRECTS] times
+    TooManyRedirects,
+    #[error("Refusing to follow unsafe redirect")]
+    /// We were redirected from HTTPS to plain HTTP, or to something that
+    /// isn't HTTP at all
+    UnsafeRedirect {
+        /// Where we were redirected to
+        location: String,
+    },
+}
+
+/// Settings shared by every [download_segment()] call of a download
+#[derive(Clone)]
+pub struct SegmentOptions {
+    /// Limits the bandwidth used by the request
+    pub limiter: Arc<RateLimiter>,
+    /// Number of attempts made before giving up
+    pub max_retries: usize,
+    /// Once cancelled, no new request is made
+    pub stop: CancellationToken,
+    /// Once cancelled, the request in flight is dropped
+    pub abort: CancellationToken,
+    /// Where the outcome of each request is recorded
+    pub stats: Arc<Statistics>,
+    /// Extra headers sent with each request
+    pub headers: Arc<RequestHeaders>,
+    /// How long a request may go without any progress before it is given up
+    pub request_timeout: Duration,
+}
+
+/// Create a single TorClient which will be used to spawn isolated connections
+///
+/// This Client uses the default config with no other changes
+pub async fn create_tor_client() -> Result<TorClient<PreferredRuntime>, arti_client::Error> {
+    let config = TorClientConfig::default();
+    TorClient::create_bootstrapped(config).await
+}
+
+/// Creates a `hyper::Client` for sending HTTPS requests over Tor
+///
+/// Note that it first creates an isolated circuit from the `TorClient`
+/// passed into it, this is generally an Arti best practice
+///
+/// The TLS connections are set up according to `tls`, see [TlsConfig]
+pub async fn build_tor_hyper_client(
+    baseconn: &TorClient<PreferredRuntime>,
+    tls: &TlsConfig,
+) -> anyhow::Result<HttpClient> {
+    build_hyper_client(baseconn.isolated_client(), tls)
+}
+
+/// Creates a `hyper::Client` which makes its connections with `tor_client`
+/// as is, without isolating it first
+pub fn build_hyper_client(
+    tor_client: TorClient<PreferredRuntime>,
+    tls: &TlsConfig,
+) -> anyhow::Result<HttpClient> {
+    let tls_connector = tls.build_connector()?;
+
+    let connector = TorConnector::new(tor_client, tls_connector);
+    Ok(hyper::Client::builder().build::<_, Body>(connector))
+}
+
+/// Like [build_hyper_client()], but the circuit of every connection the
+/// client opens is recorded in `stats` as the one of `connection`
+pub fn build_recording_hyper_client(
+    tor_client: TorClient<PreferredRuntime>,
+    tls: &TlsConfig,
+    stats: Arc<Statistics>,
+    connection: usize,
+) -> anyhow::Result<HttpClient> {
+    let tls_connector = tls.build_connector()?;
+
+    let connector = TorConnector::new(tor_client, tls_connector).record_circuits(stats, connection);
+    Ok(hyper::Client::builder().build::<_, Body>(connector))
+}
+
+/// Where a file was found after following redirects, and how big it is
+#[derive(Clone, Debug)]
+pub struct ResolvedUrl {
+    /// URL at the end of the redirect chain
+    pub url: String,
+    /// Size of the file
+    pub length: u64,
+    /// Whether [ResolvedUrl::url] is on another origin than the URL we were
+    /// asked for, in which case no credentials should be sent to it
+    pub cross_origin: bool,
+}
+
+/// Scheme, host and port of `uri`, with the port filled in from the scheme
+fn origin(uri: &Uri) -> (Option<&str>, Option<&str>, Option<u16>) {
+    let port = uri.port_u16().or(match uri.scheme_str() {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    });
+    (uri.scheme_str(), uri.host(), port)
+}
+
+/// Work out where the `Location` header of a redirect from `base` points to
+///
+/// `location` may be an absolute URL, or a reference relative to `base`
+/// which is resolved as described in RFC 3986 section 5.2
+fn redirect_target(base: &Uri, location: &str) -> anyhow::Result<Uri> {
+    let mut target = url::Url::parse(&base.to_string())?.join(location)?;
+    // Fragments are never sent to the server
+    target.set_fragment(None);
+    Ok(Uri::from_str(target.as_str())?)
+}
+
+/// Whether a redirect from `from` to `to` can be followed: only HTTP(S)
+/// targets are, and HTTPS can't be downgraded to plain HTTP
+fn is_safe_redirect(from: &Uri, to: &Uri) -> bool {
+    let downgrade = from.scheme_str() == Some("https") && to.scheme_str() != Some("https");
+    !downgrade && matches!(to.scheme_str(), Some("https") | Some("http"))
+}
+
+/// Send a request without a body to `url`, following up to [MAX_REDIRECTS]
+/// redirects
+///
+/// Redirects to anything but HTTP(S), and from HTTPS to plain HTTP, are
+/// refused. `headers` are sent with every request, leaving out the
+/// credentials once we are redirected to another origin. Returns the URL the
+/// request ended up at along with its response
+async fn request_following_redirects<C>(
+    url: &str,
+    method: Method,
+    http: &Client<C>,
+    headers: &RequestHeaders,
+) -> anyhow::Result<(Uri, Response<Body>)>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let mut uri = Uri::from_str(url)?;
+    let original = uri.clone();
+    let mut chain = vec![redact_url(url)];
+    for _ in 0..=MAX_REDIRECTS {
+        let builder = Request::builder().method(method.clone()).uri(uri.clone());
+        let builder = if origin(&uri) == origin(&original) {
+            headers.apply(builder)
+        } else {
+            headers.without_credentials().apply(builder)
+        };
+        let req = builder.body(Body::empty())?;
+        let resp = http.request(req).await?;
+        let status = resp.status();
+        if !matches!(
+            status,
+            StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT
+        ) {
+            if chain.len() > 1 {
+                info!("Followed redirects: {}", chain.join(" -> "));
+            }
+            return Ok((uri, resp));
+        }
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .ok_or(DownloadMgrError::RequestFailed { status })?
+            .to_str()?;
+        let next = redirect_target(&uri, location)?;
+        if !is_safe_redirect(&uri, &next) {
+            let location = redact_url(&next.to_string());
+            warn!(
+                "Refusing to follow redirect from {} to {}",
+                redact_url(&uri.to_string()),
+                location
+            );
+            return Err(DownloadMgrError::UnsafeRedirect { location }.into());
+        }
+        if origin(&uri) != origin(&next) {
+            debug!(
+                "Cross-origin redirect from {} to {}, credentials won't be sent",
+                redact_url(&uri.to_string()),
+                redact_url(&next.to_string())
+            );
+        }
+        chain.push(redact_url(&next.to_string()));
+        uri = next;
+    }
+    warn!("Gave up following redirects: {}", chain.join(" -> "));
+    Err(DownloadMgrError::TooManyRedirects.into())
+}
+
+/// Find where the file to be downloaded really is and get its size, so we can
+/// prep main loop
+///
+/// Redirects are only followed here, so that every range request goes
+/// straight to the same final URL
+pub async fn resolve_url(
+    url: &str,
+    baseconn: &TorClient<PreferredRuntime>,
+    tls: &TlsConfig,
+    headers: &RequestHeaders,
+) -> anyhow::Result<ResolvedUrl> {
+    let http = build_tor_hyper_client(baseconn, tls).await?;
+    debug!(
+        "Requesting content length of {} via Tor...",
+        redact_url(url)
+    );
+    resolve_url_with(url, &http, headers).await
+}
+
+/// The part of [resolve_url()] done once we have an HTTP client
+async fn resolve_url_with<C>(
+    url: &str,
+    http: &Client<C>,
+    headers: &RequestHeaders,
+) -> anyhow::Result<ResolvedUrl>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let (uri, resp) = request_following_redirects(url, Method::HEAD, http, headers).await?;
+    if !resp.status().is_success() {
+        warn!("Non 2xx Status code: {}", resp.status());
+        return Err(DownloadMgrError::RequestFailed {
+            status: resp.status(),
+        }
+        .into());
+    }
+    // Get Content-Length
+    match resp.headers().get("Content-Length") {
+        Some(raw_length) => {
+            let length = raw_length.to_str()?.parse::<u64>()?;
+            debug!("Content-Length of resource: {}", length);
+            Ok(ResolvedUrl {
+                cross_origin: origin(&uri) != origin(&Uri::from_str(url)?),
+                url: uri.to_string(),
+                length,
+            })
+        }
+        None => Err(DownloadMgrError::DownloadError.into()),
+    }
+}
+
+/// Gets a portion of the file from the server and store it in a Vec if successful
+///
+/// The body is read through the limiter of `options`, which is charged for
+/// every piece of data received on the given `connection`, and its headers
+/// are added to the request. The request times out once it goes
+/// [SegmentOptions::request_timeout] without any progress
+///
+/// Note that it returns a Result to denote any network issues that may have arisen from the request
+pub async fn request_range(
+    url: &str,
+    start: usize,
+    end: usize,
+    http: &HttpClient,
+    connection: usize,
+    options: &SegmentOptions,
+) -> anyhow::Result<Vec<u8>> {
+    let timeout = options.request_timeout;
+    warn!("Requesting {} via Tor...", redact_url(url));
+    let uri = Uri::from_str(url)?;
+    let partial_req_value = format!("bytes={}-{}", start, end);
+    // GET the contents of URL from byte offset "start" to "end"
+    let req = options
+        .headers
+        .apply(Request::builder())
+        .method(Method::GET)
+        .uri(uri)
+        .header("Range", partial_req_value)
+        .body(Body::default())?;
+    let mut resp = stall_timeout(timeout, http.request(req)).await??;
+
+    // Got partial content, this is good
+    if resp.status() == hyper::StatusCode::PARTIAL_CONTENT {
+        debug!("Good request, getting partial content...");
+        // Get the body of the response, pausing whenever we go over the limits
+        let mut body = Vec::with_capacity(end - start + 1);
+        while let Some(data) = stall_timeout(timeout, resp.body_mut().data()).await? {
+            let data = data.map_err(|e| DownloadMgrError::BodyDownload { error: e })?;
+            options.limiter.consume(connection, data.len()).await;
+            body.extend_from_slice(&data);
+        }
+        // A short body would otherwise leave a hole in the file
+        check_chunk_length(start, end, body.len())?;
+        return Ok(body);
+    }
+    // Got something else, return an Error
+    warn!("Non 206 Status code: {}", resp.status());
+    Err(DownloadMgrError::RequestFailed {
+        status: resp.status(),
+    }
+    .into())
+}
+
+/// Make sure a response to a request for the bytes from `start` to `end`,
+/// which are both included, is `received` bytes long
+fn check_chunk_length(start: usize, end: usize, received: usize) -> Result<(), DownloadMgrError> {
+    let expected = end - start + 1;
+    if received != expected {
+        warn!(
+            "Expected {} bytes at offset {}, got {}",
+            expected, start, received
+        );
+        return Err(DownloadMgrError::WrongChunkLength { expected, received });
+    }
+    Ok(())
+}
+
+/// Find the digest of `file_name` in the contents of a checksum file, as
+/// written by `sha256sum`
+///
+/// Each line holds a digest and a file name separated by two spaces, lines
+/// which don't look like that are skipped
+fn find_sha256_sum(sums: &str, file_name: &str) -> Option<String> {
+    sums.lines()
+        .filter_map(|line| line.split_once("  "))
+        .find(|(_, name)| *name == file_name)
+        .map(|(digest, _)| digest.to_string())
+}
+
+/// Gets the expected SHA256 sum of the download file from the server
+///
+/// Note that it returns a Result to denote any network issues that may have arisen from the request
+pub async fn request_sha256_sum(
+    url: String,
+    http: &HttpClient,
+    file_name: &str,
+) -> anyhow::Result<String> {
+    let (_, mut resp) =
+        request_following_redirects(&url, Method::GET, http, &RequestHeaders::default()).await?;
+
+    if resp.status() == hyper::StatusCode::OK {
+        debug!("Good request, getting content...");
+        // Get the body of the response
+        return match hyper::body::to_bytes(resp.body_mut()).await {
+            Ok(bytes) => {
+                let str_body = std::str::from_utf8(&bytes)?;
+                find_sha256_sum(str_body, file_name)
+                    .ok_or_else(|| DownloadMgrError::DownloadError.into())
+            }
+            Err(e) => Err(DownloadMgrError::BodyDownload { error: e }.into()),
+        };
+    }
+    // Got something else, return an Error
+    warn!("Non 200 Status code: {}", resp.status());
+    Err(DownloadMgrError::RequestFailed {
+        status: resp.status(),
+    }
+    .into())
+}
+
+/// Run `future`, failing with a [std::io::ErrorKind::TimedOut] error if it
+/// doesn't finish within `timeout`
+///
+/// Only the time spent waiting on the server is limited this way, not the
+/// time spent waiting on the rate limiter
+async fn stall_timeout<T>(
+    timeout: Duration,
+    future: impl std::future::Future<Output = T>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(timeout, future).await.map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("No progress within {:?}", timeout),
+        )
+    })
+}
+
+/// Backoff function for determining timeout duration for each repeated download try
+fn wait_time_for_iteration(iteration: usize) -> u64 {
+    1000.min(500 + 100 * iteration as u64)
+}
+
+/// Wrapper around [request_range] in order to overcome network issues
+///
+/// We try a maximum of [SegmentOptions::max_retries] times to get the portion of the file we require
+///
+/// If we are successful, we return the bytes to be later written to disk, else we simply return None
+///
+/// If we are asked to stop, we return [DownloadMgrError::Interrupted] instead of
+/// making another request, and drop the request in flight if we are asked to abort
+pub async fn download_segment(
+    url: String,
+    start: usize,
+    end: usize,
+    newhttp: HttpClient,
+    connection: usize,
+    options: &SegmentOptions,
+) -> Result<Vec<u8>, DownloadMgrError> {
+    for trial in 0..options.max_retries {
+        if trial != 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(wait_time_for_iteration(
+                trial,
+            )))
+            .await;
+        }
+        if options.stop.is_cancelled() {
+            return Err(DownloadMgrError::Interrupted);
+        }
+        // request via new Tor connection
+        let started = Instant::now();
+        let request = request_range(&url, start, end, &newhttp, connection, options);
+        let result = tokio::select! {
+            result = request => result,
+            _ = options.abort.cancelled() => return Err(DownloadMgrError::Interrupted),
+        };
+        match result {
+            // save to disk
+            Ok(body) => {
+                options
+                    .stats
+                    .record_chunk(connection, body.len(), started.elapsed());
+                return Ok(body);
+            }
+            // retry if we failed
+            Err(e) => {
+                options
+                    .stats
+                    .record_failure(connection, stats::is_timeout(&e));
+                warn!(
+                    "Error while trying to get a segment: {}, retrying...",
+                    e.to_string()
+                );
+            }
+        }
+    }
+    Err(DownloadMgrError::DownloadError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderMap, AUTHORIZATION, CONTENT_LENGTH, COOKIE};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+
+    /// Requests received by a [serve()] server, as their path and headers
+    type Received = Arc<Mutex<Vec<(String, HeaderMap)>>>;
+
+    /// Answer HTTP requests on a local port with `respond`, which is given
+    /// the path of each request. Returns the address of the server along
+    /// with the requests it gets
+    fn serve<F>(respond: F) -> (SocketAddr, Received)
+    where
+        F: Fn(&str) -> Response<Body> + Send + Sync + 'static,
+    {
+        let respond = Arc::new(respond);
+        let received = Received::default();
+        let log = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let log = log.clone();
+            let respond = respond.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let path = req.uri().path().to_string();
+                    let response = respond(&path);
+                    log.lock()
+                        .expect("poisoned lock")
+                        .push((path, req.headers().clone()));
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server =
+            hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    /// A redirect to `location`
+    fn redirect(location: &str) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Headers with credentials, along with one which isn't
+    fn credentials() -> RequestHeaders {
+        RequestHeaders::from_options(
+            &["User-Agent: test".to_string()],
+            Some("user:password"),
+            &["session=1234".to_string()],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn credentials_stay_with_the_original_origin() {
+        // The mirror is on another port, so on another origin
+        let (mirror, mirror_received) = serve(|_| {
+            Response::builder()
+                .header(CONTENT_LENGTH, 10)
+                .body(Body::empty())
+                .unwrap()
+        });
+        let (origin, received) = serve(move |path| match path {
+            "/start" => redirect("/moved"),
+            _ => redirect(&format!("http://{}/file", mirror)),
+        });
+        let http = Client::new();
+        let resolved = resolve_url_with(&format!("http://{}/start", origin), &http, &credentials())
+            .await
+            .unwrap();
+        assert_eq!(resolved.url, format!("http://{}/file", mirror));
+        assert_eq!(resolved.length, 10);
+        assert!(resolved.cross_origin);
+        // Redirects within the origin keep the credentials
+        let received = received.lock().expect("poisoned lock");
+        let paths: Vec<_> = received.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/start", "/moved"]);
+        for (_, headers) in received.iter() {
+            assert!(headers.contains_key(AUTHORIZATION));
+            assert_eq!(headers[COOKIE], "session=1234");
+            assert_eq!(headers["user-agent"], "test");
+        }
+        // The mirror only gets the other headers
+        let mirror_received = mirror_received.lock().expect("poisoned lock");
+        assert_eq!(mirror_received.len(), 1);
+        let (_, headers) = &mirror_received[0];
+        assert!(!headers.contains_key(AUTHORIZATION));
+        assert!(!headers.contains_key(COOKIE));
+        assert_eq!(headers["user-agent"], "test");
+    }
+
+    #[tokio::test]
+    async fn redirects_within_the_origin_are_not_cross_origin() {
+        let (origin, _) = serve(|path| match path {
+            "/start" => redirect("/file"),
+            _ => Response::builder()
+                .header(CONTENT_LENGTH, 5)
+                .body(Body::empty())
+                .unwrap(),
+        });
+        let http = Client::new();
+        let resolved = resolve_url_with(&format!("http://{}/start", origin), &http, &credentials())
+            .await
+            .unwrap();
+        assert_eq!(resolved.url, format!("http://{}/file", origin));
+        assert!(!resolved.cross_origin);
+    }
+
+    #[test]
+    fn sha256_sums_are_found_by_file_name() {
+        let sums = "\
+aaaa  tor-browser-linux64-13.0_ALL.tar.xz
+garbage line
+
+bbbb  tor-browser-linux64-13.0_ALL.tar.xz.asc
+cccc  name with  two spaces
+";
+        let find = |name| find_sha256_sum(sums, name);
+        assert_eq!(
+            find("tor-browser-linux64-13.0_ALL.tar.xz").as_deref(),
+            Some("aaaa")
+        );
+        assert_eq!(
+            find("tor-browser-linux64-13.0_ALL.tar.xz.asc").as_deref(),
+            Some("bbbb")
+        );
+        assert_eq!(find("name with  two spaces").as_deref(), Some("cccc"));
+        assert_eq!(find("garbage line"), None);
+        assert_eq!(find("missing.tar.xz"), None);
+    }
+
+    #[test]
+    fn chunks_must_cover_their_whole_range() {
+        assert!(check_chunk_length(0, 9, 10).is_ok());
+        assert!(check_chunk_length(10, 10, 1).is_ok());
+        assert!(matches!(
+            check_chunk_length(0, 9, 9),
+            Err(DownloadMgrError::WrongChunkLength {
+                expected: 10,
+                received: 9
+            })
+        ));
+        assert!(check_chunk_length(0, 9, 11).is_err());
+    }
+
+    #[tokio::test]
+    async fn stalled_requests_time_out() {
+        let timeout = Duration::from_millis(10);
+        assert_eq!(stall_timeout(timeout, async { 1 }).await.unwrap(), 1);
+        let error = stall_timeout(timeout, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert!(stats::is_timeout(&error.into()));
+        assert!(!stats::is_timeout(&anyhow::anyhow!("refused")));
+    }
+
+    #[test]
+    fn redirects_are_resolved_against_the_current_url() {
+        let base = Uri::from_static("https://example.com/a/b/file?x=1");
+        for (location, expected) in [
+            ("https://other.org/file", "https://other.org/file"),
+            ("http://other.org/file", "http://other.org/file"),
+            ("//mirror.org/dist/file", "https://mirror.org/dist/file"),
+            ("/dist/file", "https://example.com/dist/file"),
+            ("other", "https://example.com/a/b/other"),
+            ("./other", "https://example.com/a/b/other"),
+            ("c/other", "https://example.com/a/b/c/other"),
+            ("?page=2", "https://example.com/a/b/file?page=2"),
+            ("other?page=2", "https://example.com/a/b/other?page=2"),
+            ("../x", "https://example.com/a/x"),
+            ("../../../../x", "https://example.com/x"),
+            ("/a/./b/../c", "https://example.com/a/c"),
+            ("#part", "https://example.com/a/b/file?x=1"),
+            ("", "https://example.com/a/b/file?x=1"),
+        ] {
+            let target = redirect_target(&base, location).unwrap();
+            assert_eq!(target.to_string(), expected, "{}", location);
+        }
+    }
+
+    #[test]
+    fn unsafe_redirects_are_refused() {
+        let https = Uri::from_static("https://example.com/file");
+        let http = Uri::from_static("http://example.com/file");
+        let target = |base, location| redirect_target(base, location).unwrap();
+        assert!(is_safe_redirect(&https, &target(&https, "/other")));
+        assert!(is_safe_redirect(
+            &https,
+            &target(&https, "https://other.org/")
+        ));
+        assert!(is_safe_redirect(
+            &http,
+            &target(&http, "https://other.org/")
+        ));
+        assert!(is_safe_redirect(&http, &target(&http, "//other.org/")));
+        assert!(!is_safe_redirect(
+            &https,
+            &target(&https, "http://example.com/file")
+        ));
+        assert!(!is_safe_redirect(
+            &https,
+            &target(&https, "ftp://example.com/file")
+        ));
+        assert!(!is_safe_redirect(
+            &http,
+            &target(&http, "ftp://example.com/file")
+        ));
+        assert!(redirect_target(&https, "http://[::1").is_err());
+    }
+}
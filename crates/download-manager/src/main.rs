@@ -3,365 +3,526 @@
 //! Use Tor to download the Tor Browser Bundle
 //!
 //! ### Intro
-//! This is a project intended to illustrate how Arti can be used to tunnel an HTTPS
-//! based project through Tor and also some of the design choices that go into making that
-//! happen, most notably, the usage of isolated clients to create different connections
-//! which won't lock each other up or run into some Arti shared state issues.
+//! This is a small command line front end over the `download_manager` library,
+//! which does the actual work of tunnelling HTTPS requests through Tor over
+//! several isolated circuits.
 //!
 //! ### Usage
 //! Simply run the program:
 //! `cargo run`
 //!
-//! The program will then attempt to create new Tor connections and download the Linux version of
-//! the Tor Browser Bundle in chunks using [HTTP Range requests](https://developer.mozilla.org/en-US/docs/Web/HTTP/Range_requests)
+//! The program will then attempt to create new Tor connections and download the latest stable
+//! Linux version of the Tor Browser Bundle in chunks using [HTTP Range requests](https://developer.mozilla.org/en-US/docs/Web/HTTP/Range_requests)
 //! in order to overcome the relatively slow connections that the Tor network provides.
-//! It is currently capped to six concurrent connections in order to respect the Tor network's bandwidth
-//! The Tor Browser Bundle is saved as `download.tar.xz`
+//! It is capped to six concurrent connections by default in order to respect the Tor network's
+//! bandwidth, which can be changed with `--connections`
+//! The Tor Browser Bundle is saved under its own name in the current directory
+//!
+//! Other builds can be picked with `--platform` (`linux64`, `win64`, `macos`,
+//! `android-aarch64`, ...), `--channel stable|alpha` and `--locale`. The
+//! latest version is read from the `downloads.json` of the channel, which can
+//! be replaced with `--downloads-json <URL>`, e.g. to test against a local
+//! mirror.
+//!
+//! Any other file can be downloaded by passing its URL, and optionally where to
+//! save it and its expected SHA256 sum:
+//! `cargo run -- https://example.com/file.tar.xz file.tar.xz --sha256 <hex digest>`
+//!
+//! ### Streaming to stdout
+//! Passing `-` as the output writes the file to stdout in order while the
+//! download is still going on, so it can be used in a pipeline:
+//! `cargo run -- https://example.com/file.tar.xz - | tar xJ`
+//!
+//! Only `--reorder-buffer` chunks are requested ahead of the next one to be
+//! written. Note that the data is written before it is verified, a mismatch is
+//! only reported at the end, with a non-zero exit status.
+//!
+//! ### Bandwidth limits
+//! The download can be capped with `--rate-limit` (bytes per second for all
+//! connections together) and `--per-connection-rate-limit` (bytes per second
+//! for each connection). Passing `--control-socket <path>` lets you change these
+//! limits while the download is running, for example
+//! `echo "global 100000" | nc -U <path>`
+//!
+//! ### Headers and credentials
+//! Extra headers can be sent with every request with `--header 'Name: value'`,
+//! HTTP basic auth with `--user <user:password>` and cookies with
+//! `--cookie name=value`. Credentials are only sent to the host of the URL,
+//! not to wherever it redirects to, and are never logged.
+//!
+//! ### HTTP/2
+//! With `--http2`, HTTP/2 is offered to servers through ALPN. Servers which
+//! pick it get `--http2-streams` range requests multiplexed over a single TLS
+//! session on each circuit, the others are talked to with HTTP/1.1 as usual.
+//! Like pinning, HTTP/2 needs the rustls backend, other builds refuse
+//! `--http2`.
+//!
+//! ### TLS
+//! Extra CA certificates can be trusted with `--ca-cert <file.pem>`, which is
+//! handy to test against a local server with a self-signed certificate. The
+//! key of a server can be pinned with `--pin <host>=sha256/<base64 hash>`,
+//! see [TlsConfig::pin()] for how to compute the hash. Pinning needs the
+//! rustls backend, built with `cargo build --no-default-features --features rustls`,
+//! which also removes the need for OpenSSL on the system. Other builds refuse
+//! `--pin`.
+//!
+//! ### Cache
+//! With `--cache-dir <dir>`, verified downloads are kept in that directory
+//! under their SHA256 sum. When the expected sum of a download is already
+//! there, the file is copied into place without using the network, nor even
+//! starting Tor when the sum is given with `--sha256`. `--cache-size` caps the size of the directory, evicting the least
+//! recently used files first.
+//!
+//! ### Statistics
+//! Once the download is over, a table showing how much each connection
+//! downloaded, how fast, how often it had to retry (and how often because a
+//! request went `--request-timeout` seconds without hearing from the server)
+//! and through which exit relay is printed to stderr. `--stats-json <file>`
+//! also saves it as JSON, along with the full circuit of each connection, to
+//! help spot bad exits.
+//!
+//! ### Daemon mode
+//! `cargo run -- daemon` keeps a single bootstrapped Tor client around and
+//! serves a small HTTP/JSON API on `127.0.0.1:5000` to queue, pause, resume,
+//! cancel and inspect downloads. The queue is saved to `--state-file` so it
+//! survives restarts, and the chunks of paused jobs, or of the running job
+//! when the daemon gets Ctrl-C or SIGTERM, are saved next to their
+//! destination so they continue from there. For example:
+//!
+//! `curl -X POST -H 'Content-Type: application/json' -d '{"url": "https://...", "destination": "file.tar.xz"}' 127.0.0.1:5000/downloads`
+//!
+//! Files are saved in `--download-dir`, the current directory by default, and
+//! destinations outside of it are refused. The API has no authentication
+//! unless `--api-token-file <file>` is given, in which case the token in that
+//! file has to be sent as `Authorization: Bearer <token>`. Without a token,
+//! only loopback addresses are accepted by `--listen`.
+//!
+//! ### Interrupting a download
+//! On Ctrl-C (SIGINT) or SIGTERM no new range requests are made, and the ones
+//! in flight get `--grace-period` seconds to finish. The completed chunks are
+//! then saved next to the download as `<file>.part` and `<file>.part.json`,
+//! and the program exits with status 130. Running it again continues from
+//! those chunks.
 //!
 //! ### Disclaimer
 //! The download manager showcased is not really meant for production. It is simply an example of how Arti
-//! can be utilized. Many features, like resumeable downloads, aren't present. Don't use it for any real
-//! usage other than academic
-use arti_client::{TorClient, TorClientConfig};
-use arti_hyper::*;
-use futures::future::join_all;
-use hyper::{Body, Client, Method, Request, StatusCode, Uri};
-use sha2::{Digest, Sha256};
+//! can be utilized. Many features you would expect from a real download manager aren't present. Don't
+//! use it for any real usage other than academic
+use clap::{Parser, Subcommand};
+use download_manager::cache::{Cache, DEFAULT_CACHE_SIZE};
+use download_manager::headers::RequestHeaders;
+use download_manager::partial::PartialDownload;
+use download_manager::ratelimit::{self, RateLimiter};
+use download_manager::release::{self, Channel};
+use download_manager::stats::Statistics;
+use download_manager::tls::TlsConfig;
+use download_manager::{
+    build_tor_hyper_client, create_tor_client, request_sha256_sum, DownloadMgrError, Downloader,
+    DownloaderBuilder, Verification, GRACE_PERIOD, HTTP2_STREAMS, MAX_CONNECTIONS, REQUEST_TIMEOUT,
+};
 use std::fs::OpenOptions;
-use std::io::Write;
-use std::str::FromStr;
-use tls_api::{TlsConnector as TlsConnectorTrait, TlsConnectorBuilder};
-use tls_api_native_tls::TlsConnector;
-use tor_rtcompat::PreferredRuntime;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
-/// REQSIZE is just the size of each chunk we get from a particular circuit
-const REQSIZE: u64 = 1024 * 1024;
-/// This denotes the version of Tor Browser to get
-///
-/// It also helps us create the URL to get the SHA256 sums for the browser we download
-const TOR_VERSION: &str = "12.5.2";
-/// Number of simultaneous connections that are made
-// TODO: make this user configurable
-const MAX_CONNECTIONS: usize = 6;
-/// Number of retries to make if a particular request failed
-const MAX_RETRIES: usize = 6;
+mod daemon;
 
-#[derive(thiserror::Error, Debug)]
-#[error("Download Manager Error")]
-/// Enum storing all the Errors that our program can raise
-enum DownloadMgrError {
-    #[error("Download failed due to unspecified reason")]
-    /// Blanket download error to catch almost all download errors
-    DownloadError,
-    #[error("Got unexpected status code")]
-    /// Error to represent an unexpected status code from the network
-    RequestFailed {
-        /// The status code that we got instead of the intended one
-        status: StatusCode,
-    },
-    /// Error to represent raw bytes properly
-    #[error("Unable to read body into bytes")]
-    BodyDownload {
-        /// Error raised while reading body into bytes, wraps [hyper::Error]`
-        error: hyper::Error,
+/// Exit status used when the download was interrupted by a signal
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Specify which mode we wish to use the program in
+#[derive(Subcommand)]
+enum Command {
+    /// Keep running and download files queued through a local HTTP API
+    Daemon {
+        /// Address on which to serve the API
+        #[arg(long, default_value = "127.0.0.1:5000")]
+        listen: SocketAddr,
+        /// File in which the download queue is saved
+        #[arg(long, default_value = "download-manager-state.json")]
+        state_file: PathBuf,
+        /// Directory in which the files are saved, destinations can't be outside of it
+        #[arg(long, default_value = ".")]
+        download_dir: PathBuf,
+        /// File holding a token API clients have to send, needed to listen on other addresses than loopback ones
+        #[arg(long)]
+        api_token_file: Option<PathBuf>,
     },
-    #[error("Failed to get a connection from pool")]
-    /// Used to denote a failed .get() request from `Vec<Client>`
-    ConnectionError,
 }
 
-/// Create a single TorClient which will be used to spawn isolated connections
-///
-/// This Client uses the default config with no other changes
-async fn create_tor_client() -> Result<TorClient<PreferredRuntime>, arti_client::Error> {
-    let config = TorClientConfig::default();
-    TorClient::create_bootstrapped(config).await
+/// Download the Tor Browser Bundle, or any other file, over Tor
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Run in some other mode than downloading a single file
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// URL to download, defaults to the latest Tor Browser Bundle
+    url: Option<String>,
+    /// Where to save the file, `-` for stdout. Defaults to the last part of the URL
+    output: Option<String>,
+    /// Platform of the Tor Browser Bundle to download, like linux64, win64, macos or android-aarch64
+    #[arg(long, default_value = release::DEFAULT_PLATFORM)]
+    platform: String,
+    /// Release channel of the Tor Browser Bundle, stable or alpha
+    #[arg(long, default_value_t = Channel::Stable)]
+    channel: Channel,
+    /// Locale of the Tor Browser Bundle, for platforms which aren't bundling all of them
+    #[arg(long, default_value = release::ALL_LOCALES)]
+    locale: String,
+    /// URL of the list of releases to use instead of the one of the channel
+    #[arg(long)]
+    downloads_json: Option<String>,
+    /// Expected SHA256 sum of the file, as hex
+    #[arg(long)]
+    sha256: Option<String>,
+    /// Number of chunks requested ahead of the one being written, when writing to stdout
+    #[arg(long, default_value_t = 2 * MAX_CONNECTIONS)]
+    reorder_buffer: usize,
+    /// Number of simultaneous connections to make
+    #[arg(long, default_value_t = MAX_CONNECTIONS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    connections: usize,
+    /// Maximum download speed of all connections together, in bytes per second
+    #[arg(long, default_value = "0")]
+    rate_limit: u64,
+    /// Maximum download speed of each connection, in bytes per second
+    #[arg(long, default_value = "0")]
+    per_connection_rate_limit: u64,
+    /// Unix socket on which to accept commands to change the rate limits
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Seconds given to requests in flight to finish after being interrupted
+    #[arg(long, default_value_t = GRACE_PERIOD.as_secs())]
+    grace_period: u64,
+    /// Seconds a request may wait on the server before it is retried
+    #[arg(long, default_value_t = REQUEST_TIMEOUT.as_secs())]
+    request_timeout: u64,
+    /// PEM file of CA certificates to trust on top of the default ones, can be repeated
+    #[arg(long)]
+    ca_cert: Vec<PathBuf>,
+    /// Offer HTTP/2, to multiplex several range requests over each circuit
+    #[arg(long)]
+    http2: bool,
+    /// Number of range requests in flight on each connection with HTTP/2
+    #[arg(long, default_value_t = HTTP2_STREAMS)]
+    http2_streams: usize,
+    /// Only accept a key for a host, as `<host>=sha256/<base64 SPKI hash>`, can be repeated
+    #[arg(long, value_parser = parse_pin)]
+    pin: Vec<String>,
+    /// Directory in which to keep verified downloads, to skip downloading them again
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Maximum size of the cache directory, in bytes
+    #[arg(long, default_value_t = DEFAULT_CACHE_SIZE)]
+    cache_size: u64,
+    /// Extra header to send with every request, as `Name: value`, can be repeated
+    #[arg(long = "header", short = 'H')]
+    headers: Vec<String>,
+    /// Credentials for HTTP basic auth, as `user:password`
+    #[arg(long)]
+    user: Option<String>,
+    /// Cookie to send with every request, as `name=value`, can be repeated
+    #[arg(long = "cookie")]
+    cookies: Vec<String>,
+    /// File in which to save the statistics of each connection as JSON
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
 }
 
-/// Creates a `hyper::Client` for sending HTTPS requests over Tor
+/// Check a `--pin` option when parsing the arguments
 ///
-/// Note that it first creates an isolated circuit from the `TorClient`
-/// passed into it, this is generally an Arti best practice
-async fn build_tor_hyper_client(
-    baseconn: &TorClient<PreferredRuntime>,
-) -> anyhow::Result<Client<ArtiHttpConnector<PreferredRuntime, TlsConnector>>> {
-    let tor_client = baseconn.isolated_client();
-    let tls_connector = TlsConnector::builder()?.build()?;
-
-    let connector = ArtiHttpConnector::new(tor_client, tls_connector);
-    Ok(hyper::Client::builder().build::<_, Body>(connector))
+/// Only the rustls backend can look at the key of a server, so pins are
+/// refused straight away by builds without it rather than being ignored
+fn parse_pin(pin: &str) -> Result<String, String> {
+    if !cfg!(feature = "rustls") {
+        return Err("certificate pinning needs the rustls backend, build with \
+            `--no-default-features --features rustls`"
+            .to_string());
+    }
+    match pin.split_once('=') {
+        Some((host, hash)) if !host.is_empty() && !hash.is_empty() => Ok(pin.to_string()),
+        _ => Err("pins should look like <host>=sha256/<hash>".to_string()),
+    }
 }
 
-/// Get the size of file to be downloaded so we can prep main loop
-async fn get_content_length(
-    url: String,
-    baseconn: &TorClient<PreferredRuntime>,
-) -> anyhow::Result<u64> {
-    let http = build_tor_hyper_client(baseconn).await?;
-    let uri = Uri::from_str(url.as_str())?;
-    debug!("Requesting content length of {} via Tor...", url);
-    // Create a new request
-    let req = Request::builder()
-        .method(Method::HEAD)
-        .uri(uri)
-        .body(Body::empty())?;
-
-    let resp = http.request(req).await?;
-    // Get Content-Length
-    match resp.headers().get("Content-Length") {
-        Some(raw_length) => {
-            let length = raw_length.to_str()?.parse::<u64>()?;
-            debug!("Content-Length of resource: {}", length);
-            // Return it after a suitable typecast
-            Ok(length)
-        }
-        None => Err(DownloadMgrError::DownloadError.into()),
+/// Where to save the download of `url`, unless `--output` says otherwise
+fn output_file_name(output: Option<&str>, url: &str) -> String {
+    match output {
+        Some(output) => output.to_string(),
+        None => file_name_from_url(url).unwrap_or_else(|| "download".to_string()),
     }
 }
 
-/// Gets a portion of the file from the server and store it in a Vec if successful
+/// Copy the file with the given SHA256 `digest` from `cache` to
+/// `download_file_name`, returning whether it was there
 ///
-/// Note that it returns a Result to denote any network issues that may have arisen from the request
-async fn request_range(
-    url: &String,
-    start: usize,
-    end: usize,
-    http: &Client<ArtiHttpConnector<PreferredRuntime, TlsConnector>>,
-) -> anyhow::Result<Vec<u8>> {
-    warn!("Requesting {} via Tor...", url);
-    let uri = Uri::from_str(url)?;
-    let partial_req_value = format!("bytes={}-{}", start, end);
-    // GET the contents of URL from byte offset "start" to "end"
-    let req = Request::builder()
-        .method(Method::GET)
-        .uri(uri)
-        .header("Range", partial_req_value)
-        .body(Body::default())?;
-    let mut resp = http.request(req).await?;
+/// Files streamed to stdout are left to [Downloader], which also looks them
+/// up in the cache
+fn fetch_from_cache(
+    cache: Option<&Cache>,
+    digest: &str,
+    download_file_name: &str,
+) -> anyhow::Result<bool> {
+    let Some(cache) = cache else {
+        return Ok(false);
+    };
+    if download_file_name == "-" || !cache.fetch(digest, Path::new(download_file_name))? {
+        return Ok(false);
+    }
+    info!("Got {} from the cache", download_file_name);
+    Ok(true)
+}
 
-    // Got partial content, this is good
-    if resp.status() == hyper::StatusCode::PARTIAL_CONTENT {
-        debug!("Good request, getting partial content...");
-        // Get the body of the response
-        return match hyper::body::to_bytes(resp.body_mut()).await {
-            Ok(bytes) => Ok(bytes.to_vec()),
-            Err(e) => Err(DownloadMgrError::BodyDownload { error: e }.into()),
-        };
+/// Build the TLS settings out of the `--ca-cert` and `--pin` options
+fn tls_config(ca_certs: &[PathBuf], pins: &[String]) -> anyhow::Result<TlsConfig> {
+    let mut tls = TlsConfig::new();
+    for path in ca_certs {
+        tls.add_ca_certificates(path)?;
     }
-    // Got something else, return an Error
-    warn!("Non 206 Status code: {}", resp.status());
-    Err(DownloadMgrError::RequestFailed {
-        status: resp.status(),
+    for pin in pins {
+        let (host, hash) = pin
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Pin should look like <host>=sha256/<hash>"))?;
+        tls.pin(host, hash)?;
     }
-    .into())
+    // Fail now rather than on the first connection
+    tls.build_connector()?;
+    Ok(tls)
 }
 
-/// Gets the expected SHA256 sum of the download file from the server
-///
-/// Note that it returns a Result to denote any network issues that may have arisen from the request
-async fn request_sha256_sum(
-    url: String,
-    http: &Client<ArtiHttpConnector<PreferredRuntime, TlsConnector>>,
-    file_name: &str,
-) -> anyhow::Result<String> {
-    let uri = Uri::from_str(url.as_str())?;
-    // GET the contents of URL from byte offset "start" to "end"
-    let req = Request::builder()
-        .method(Method::GET)
-        .uri(uri)
-        .body(Body::default())?;
-    let mut resp = http.request(req).await?;
+/// Read the token clients of the daemon API have to send from `path`
+fn read_api_token(path: &Path) -> anyhow::Result<String> {
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        anyhow::bail!("API token file {} is empty", path.display());
+    }
+    Ok(token)
+}
 
-    if resp.status() == hyper::StatusCode::OK {
-        debug!("Good request, getting content...");
-        // Get the body of the response
-        return match hyper::body::to_bytes(resp.body_mut()).await {
-            Ok(bytes) => {
-                let bytes_vec = bytes.to_vec();
-                let str_body = std::str::from_utf8(&bytes_vec)?;
-                for line in str_body.lines() {
-                    let parts: Vec<&str> = line.splitn(2, "  ").collect();
-                    if parts[1] == file_name {
-                        return Ok(parts[0].to_string());
-                    }
-                }
-                Err(DownloadMgrError::DownloadError.into())
-            }
-            Err(e) => Err(DownloadMgrError::BodyDownload { error: e }.into()),
-        };
+/// Print the statistics of each connection to stderr, and save them to
+/// `json` if set
+fn report_stats(stats: &Statistics, json: Option<&Path>) {
+    eprint!("{}", stats.table());
+    if let Some(path) = json {
+        if let Err(e) = stats.write_json(path) {
+            warn!("Failed to save statistics to {}: {}", path.display(), e);
+        }
     }
-    // Got something else, return an Error
-    warn!("Non 200 Status code: {}", resp.status());
-    Err(DownloadMgrError::RequestFailed {
-        status: resp.status(),
+}
+
+/// Cancel `stop` once we get SIGINT or SIGTERM
+async fn stop_on_signal(stop: CancellationToken) -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = sigterm.recv() => {}
     }
-    .into())
+    warn!("Interrupted, saving what we have downloaded so far...");
+    stop.cancel();
+    Ok(())
 }
 
-/// Backoff function for determining timeout duration for each repeated download try
-fn wait_time_for_iteration(iteration: usize) -> u64 {
-    1000.min(500 + 100 * iteration as u64)
+/// Get the name of the file at the end of `url`
+fn file_name_from_url(url: &str) -> Option<String> {
+    let uri: hyper::Uri = url.parse().ok()?;
+    let name = uri.path().rsplit('/').next()?;
+    (!name.is_empty()).then(|| name.to_string())
 }
 
-/// Wrapper around [request_range] in order to overcome network issues
+/// Run the download, writing the file to stdout in order as it arrives
 ///
-/// We try a maximum of [MAX_RETRIES] to get the portion of the file we require
-///
-/// If we are successful, we return the bytes to be later written to disk, else we simply return None
-async fn download_segment(
-    url: String,
-    start: usize,
-    end: usize,
-    newhttp: Client<ArtiHttpConnector<PreferredRuntime, TlsConnector>>,
-) -> Result<Vec<u8>, crate::DownloadMgrError> {
-    for trial in 0..MAX_RETRIES {
-        if trial != 0 {
-            tokio::time::sleep(std::time::Duration::from_millis(wait_time_for_iteration(
-                trial,
-            )))
-            .await;
-        }
-        // request via new Tor connection
-        match request_range(&url, start, end, &newhttp).await {
-            // save to disk
-            Ok(body) => {
-                return Ok(body);
-            }
-            // retry if we failed
-            Err(e) => {
-                warn!(
-                    "Error while trying to get a segment: {}, retrying...",
-                    e.to_string()
-                );
-            }
-        }
-    }
-    Err(DownloadMgrError::DownloadError)
+/// Unlike when writing to a file, errors are returned so that we exit with a
+/// non-zero status, since whatever reads our output has already consumed the data
+async fn stream_to_stdout(builder: DownloaderBuilder, reorder_buffer: usize) -> anyhow::Result<()> {
+    warn!("Writing to stdout: data is emitted before it is verified, check the exit status!");
+    let downloader = builder
+        .destination(std::io::stdout())
+        .streaming(reorder_buffer)
+        .build()?;
+    downloader.download().await?;
+    Ok(())
+}
+
+/// Whether `e` means the download was interrupted by a signal
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(DownloadMgrError::Interrupted))
 }
 
 /// Main method which brings it all together
 ///
 /// Summary:
 ///
-/// 1. Get the SHA256 checksum of the Tor Browser Bundle for later
-/// verification of the downloaded data
-///
-/// 2. Create [MAX_CONNECTIONS] number of connections, these will be all
-/// that is used for the main loop of the program
+/// 1. Unless some other URL was given, look up the latest Tor Browser
+///    Bundle and get its SHA256 checksum for later verification of the
+///    downloaded data
 ///
-/// 3. Get content length of the Tor Browser Bundle so we know how
-/// many loops to run
+/// 2. Hand the URL, the checksum and the file to write to over to a
+///    [Downloader], which fetches the file in parallel and verifies it
 ///
-/// 4. Create the main loop of the program; it simply cycles through the
-/// connections we initialized in step 2 and makes a request with them for the
-/// bulk of the payload we request from the network
-///
-/// 5. Check SHA256 checksum of the file in memory and compare it to the
-/// expected value we got from the Tor Project's website
-///
-/// 6. Write all that data to the disk
+/// 3. If we get interrupted along the way, the chunks we have are kept
+///    on disk and we exit with [EXIT_INTERRUPTED]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    // generate the URLs and file names from the version number
-    // and some known conventions
-    let download_file_name = format!("tor-browser-linux64-{}_ALL.tar.xz", TOR_VERSION);
-    let url = format!(
-        "https://dist.torproject.org/torbrowser/{}/{}",
-        TOR_VERSION, download_file_name
-    );
-    let verification_url = format!(
-        "https://dist.torproject.org/torbrowser/{}/sha256sums-signed-build.txt",
-        TOR_VERSION
-    );
+    // stdout may be used for the download itself
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    let args = Args::parse();
+    let mut tls = tls_config(&args.ca_cert, &args.pin)?;
+    tls.set_http2(args.http2)?;
+    let headers = RequestHeaders::from_options(&args.headers, args.user.as_deref(), &args.cookies)?;
+    let limiter = Arc::new(RateLimiter::new(
+        args.rate_limit,
+        args.per_connection_rate_limit,
+        args.connections,
+    ));
+    // Keep the guard around so the socket is cleaned up when we return
+    let control_socket = match args.control_socket {
+        Some(path) => Some(ratelimit::serve_control_socket(&path, limiter.clone())?),
+        None => None,
+    };
+    let cache = match &args.cache_dir {
+        Some(dir) => Some(Arc::new(Cache::new(dir, args.cache_size)?)),
+        None => None,
+    };
+    // Skip Tor altogether if we already have the file
+    if let (None, Some(url), Some(digest)) = (&args.command, &args.url, &args.sha256) {
+        let download_file_name = output_file_name(args.output.as_deref(), url);
+        if fetch_from_cache(cache.as_deref(), digest, &download_file_name)? {
+            return Ok(());
+        }
+    }
     let baseconn = create_tor_client().await?;
-    let length = get_content_length(url.clone(), &baseconn).await?;
-
-    let sha_http_client = build_tor_hyper_client(&baseconn).await?;
-    let expected_sha256sum =
-        request_sha256_sum(verification_url, &sha_http_client, &download_file_name).await?;
-    debug!("Expected SHA256 sum of file: {}", expected_sha256sum);
-
-    // Initialize the connections we will use for this download
-    let mut connections: Vec<Client<_>> = Vec::with_capacity(MAX_CONNECTIONS);
-    for _ in 0..MAX_CONNECTIONS {
-        let newhttp = build_tor_hyper_client(&baseconn).await?;
-        connections.push(newhttp);
+    if let Some(Command::Daemon {
+        listen,
+        state_file,
+        download_dir,
+        api_token_file,
+    }) = args.command
+    {
+        let api_token = match api_token_file {
+            Some(path) => Some(read_api_token(&path)?),
+            None => None,
+        };
+        let options = daemon::Options {
+            listen,
+            state_file,
+            download_dir,
+            api_token,
+        };
+        return daemon::run(baseconn, args.connections, limiter, tls, options).await;
     }
+    let (url, download_file_name, expected_sha256sum) = match args.url {
+        Some(url) => {
+            let download_file_name = output_file_name(args.output.as_deref(), &url);
+            (url, download_file_name, args.sha256)
+        }
+        None => {
+            // look up the latest release, and the checksums published with it
+            let http = build_tor_hyper_client(&baseconn, &tls).await?;
+            let downloads_url = args
+                .downloads_json
+                .unwrap_or_else(|| args.channel.downloads_url());
+            let release =
+                release::latest_release(&http, &downloads_url, &args.platform, &args.locale)
+                    .await?;
+            info!("Signature of the release: {}", release.signature_url);
+            let expected_sha256sum =
+                request_sha256_sum(release.checksums_url, &http, &release.file_name).await?;
+            debug!("Expected SHA256 sum of file: {}", expected_sha256sum);
+            let download_file_name = args.output.unwrap_or(release.file_name);
+            if fetch_from_cache(cache.as_deref(), &expected_sha256sum, &download_file_name)? {
+                return Ok(());
+            }
+            (release.url, download_file_name, Some(expected_sha256sum))
+        }
+    };
+    let verification = match expected_sha256sum {
+        Some(sum) => Verification::Sha256(sum),
+        None => Verification::None,
+    };
 
-    // determine the amount of iterations required
-    let steps = length / REQSIZE;
-    let mut downloadtasks = Vec::with_capacity(steps as usize);
-    let mut start = 0;
-    let mut taskid = 0;
-    while start < length as usize {
-        // the upper bound of what block we need from the server
-        let end = (start + (REQSIZE as usize) - 1).min(length as usize);
-        let http = connections
-            .get(taskid)
-            .ok_or(DownloadMgrError::ConnectionError)?;
-        let newhttp = http.clone();
-        let urlclone = url.clone();
-        downloadtasks.push(tokio::spawn(async move {
-            download_segment(urlclone, start, end, newhttp)
-                .await
-                .map(|body| (start, body))
-        }));
-        start = end + 1;
-        taskid = (taskid + 1) % MAX_CONNECTIONS;
+    let stop = CancellationToken::new();
+    tokio::spawn(stop_on_signal(stop.clone()));
+    let stats = Arc::new(Statistics::new(args.connections));
+    let mut builder = Downloader::builder(baseconn, url)
+        .cancellation(stop)
+        .grace_period(Duration::from_secs(args.grace_period))
+        .request_timeout(Duration::from_secs(args.request_timeout))
+        .verification(verification)
+        .connections(args.connections)
+        .rate_limiter(limiter)
+        .tls_config(tls)
+        .statistics(stats.clone())
+        .http2_streams(args.http2_streams)
+        .headers(headers)
+        .on_progress(|progress| {
+            debug!(
+                "Downloaded {}/{} bytes",
+                progress.downloaded, progress.total
+            )
+        });
+    if let Some(cache) = cache {
+        builder = builder.cache(cache);
     }
-    let results_options: Vec<Result<_, _>> = join_all(downloadtasks)
-        .await
-        .into_iter()
-        .flatten()
-        .collect();
-    // if we got an Error from network operations, that means we don't have entire file
-    // thus we delete the partial file and print an error
-    let has_err = results_options.iter().any(|result_op| result_op.is_err());
-    if has_err {
-        error!("Possible missing chunk! Aborting");
-        return Ok(());
-    }
-    let mut results: Vec<_> = results_options
-        .into_iter()
-        .filter_map(|result| result.ok())
-        .collect();
-    results.sort_by(|a, b| a.0.cmp(&b.0));
-    let mut file_vec: Vec<u8> = Vec::new();
-    // write all chunks to memory representation of file, checking along the
-    // way if the offsets match our expectations
-    let mut start_check = 0;
-    for (start, chunk) in results.iter() {
-        if *start != start_check {
-            error!("Mismatch in expected and observed offset! Aborting");
-            return Ok(());
-        }
-        let end_check = start_check + (REQSIZE as usize) - 1;
-        debug!(
-            "Writing chunk offset {} to memory representation of file...",
-            start
-        );
-        file_vec.extend(chunk);
-        start_check = end_check + 1;
+    if download_file_name == "-" {
+        let result = stream_to_stdout(builder, args.reorder_buffer).await;
+        report_stats(&stats, args.stats_json.as_deref());
+        // exit() skips destructors, so clean up the socket ourselves
+        drop(control_socket);
+        return match result {
+            Err(e) if is_interrupted(&e) => {
+                error!("Download interrupted, output is incomplete");
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            Err(e) => {
+                error!("Download failed, output can't be trusted: {}", e);
+                Err(e)
+            }
+            Ok(()) => Ok(()),
+        };
     }
 
-    // Verify downloaded content's checksum
-    let mut sha256 = Sha256::new();
-    sha256.update(&file_vec);
-    let hash_result = sha256.finalize();
-    let observed_hash = format!("{:x}", hash_result);
-    if observed_hash != expected_sha256sum {
-        error!("Incorrect SHA 256 sum in download! Aborting");
-        return Ok(());
-    }
-    // Write validated data to disk
     info!("Creating download file");
-    let mut fd = OpenOptions::new()
+    let fd = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(&download_file_name)?;
-    debug!("Created file, now writing downloaded content to disk...");
-    fd.write_all(&file_vec)?;
+    let partial = PartialDownload::new(format!("{}.part", download_file_name));
+    let downloader = builder.destination(fd).partial_download(partial).build()?;
+    let result = downloader.download().await;
+    report_stats(&stats, args.stats_json.as_deref());
+    if let Err(e) = result {
+        // Nothing was written, don't leave an empty file behind
+        std::fs::remove_file(&download_file_name)?;
+        if is_interrupted(&e) {
+            error!("Download interrupted, run again to continue where we stopped");
+            // exit() skips destructors, so clean up the socket ourselves
+            drop(control_socket);
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        return Err(e.context("Download failed"));
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_need_the_rustls_backend() {
+        let pin = "example.com=sha256/+ZIDConLTOBU/40X6CeYtkK9vd9FJD3xOHYjxqDAPIE=";
+        let args = Args::try_parse_from(["download-manager", "--pin", pin]);
+        assert_eq!(args.is_ok(), cfg!(feature = "rustls"));
+        if cfg!(feature = "rustls") {
+            assert!(parse_pin("example.com").is_err());
+            assert!(parse_pin("=sha256/abc").is_err());
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Keeps the chunks of an unfinished download on disk so it can be continued
+//!
+//! Completed chunks are written at their offset in a data file, next to a
+//! small JSON file which records which ranges of the data file are valid and
+//! what download they belong to. A later run for the same URL and size picks
+//! those chunks back up instead of requesting them again.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Contents of the JSON state file
+#[derive(Serialize, Deserialize)]
+struct PartialState {
+    /// URL the chunks were downloaded from
+    url: String,
+    /// Total size of the file
+    length: u64,
+    /// Size of the range requests the chunks were downloaded with
+    chunk_size: u64,
+    /// Offset and length of every chunk saved in the data file
+    chunks: Vec<(u64, u64)>,
+}
+
+/// Location of the files holding an unfinished download
+pub struct PartialDownload {
+    /// File holding the chunks, each at its own offset
+    data: PathBuf,
+    /// JSON file describing what is in [PartialDownload::data]
+    state: PathBuf,
+}
+
+impl PartialDownload {
+    /// Keep the chunks in `data`, and their description in the same path
+    /// with `.json` added to it
+    pub fn new(data: impl Into<PathBuf>) -> Self {
+        let data = data.into();
+        let mut state = data.clone().into_os_string();
+        state.push(".json");
+        Self {
+            data,
+            state: state.into(),
+        }
+    }
+
+    /// Read back the chunks saved by an earlier run for the same download
+    ///
+    /// Returns the chunks keyed by their offset, or nothing if the saved
+    /// chunks are missing or belong to some other download
+    pub fn load(&self, url: &str, length: u64, chunk_size: u64) -> BTreeMap<usize, Vec<u8>> {
+        match self.try_load(url, length, chunk_size) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                warn!("Ignoring saved partial download: {}", e);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    /// Fallible part of [PartialDownload::load()]
+    fn try_load(
+        &self,
+        url: &str,
+        length: u64,
+        chunk_size: u64,
+    ) -> anyhow::Result<BTreeMap<usize, Vec<u8>>> {
+        let mut chunks = BTreeMap::new();
+        if !self.state.exists() {
+            return Ok(chunks);
+        }
+        let state: PartialState = serde_json::from_slice(&std::fs::read(&self.state)?)?;
+        if state.url != url || state.length != length || state.chunk_size != chunk_size {
+            warn!("Saved partial download is for another file, starting over");
+            return Ok(chunks);
+        }
+        let mut fd = File::open(&self.data)?;
+        for (start, len) in state.chunks {
+            let mut chunk = vec![0; len as usize];
+            fd.seek(SeekFrom::Start(start))?;
+            fd.read_exact(&mut chunk)?;
+            chunks.insert(start as usize, chunk);
+        }
+        debug!(
+            "Loaded {} chunks from {}",
+            chunks.len(),
+            self.data.display()
+        );
+        Ok(chunks)
+    }
+
+    /// Write out the given chunks along with the state needed to continue
+    /// the download later
+    pub fn save<'a>(
+        &self,
+        url: &str,
+        length: u64,
+        chunk_size: u64,
+        chunks: impl Iterator<Item = (&'a usize, &'a Vec<u8>)>,
+    ) -> std::io::Result<()> {
+        let mut fd = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.data)?;
+        let mut saved = Vec::new();
+        for (start, chunk) in chunks {
+            fd.seek(SeekFrom::Start(*start as u64))?;
+            fd.write_all(chunk)?;
+            saved.push((*start as u64, chunk.len() as u64));
+        }
+        fd.sync_all()?;
+        let state = PartialState {
+            url: url.to_string(),
+            length,
+            chunk_size,
+            chunks: saved,
+        };
+        // Write to a temporary file first so that a crash, or a second
+        // signal, can't leave us with a half written state file
+        let mut tmp = self.state.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut fd = File::create(&tmp)?;
+        fd.write_all(&serde_json::to_vec(&state)?)?;
+        fd.sync_all()?;
+        std::fs::rename(&tmp, &self.state)?;
+        debug!("Saved partial download to {}", self.data.display());
+        Ok(())
+    }
+
+    /// Delete the saved chunks, once they aren't needed anymore
+    pub fn remove(&self) {
+        for path in [&self.data, &self.state] {
+            if path.exists() {
+                if let Err(e) = std::fs::remove_file(path) {
+                    warn!("Failed to remove {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Path of the file holding the chunks
+    pub fn path(&self) -> &Path {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory for the test called `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "download-manager-partial-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn chunks_are_saved_and_loaded_back() {
+        let dir = test_dir("round-trip");
+        let partial = PartialDownload::new(dir.join("file.part"));
+        let url = "https://example.com/file";
+        let chunks = BTreeMap::from([(0, vec![1; 10]), (20, vec![3; 5])]);
+        partial.save(url, 25, 10, chunks.iter()).unwrap();
+        assert_eq!(partial.load(url, 25, 10), chunks);
+        // A later save adds to what was there
+        let more = BTreeMap::from([(10, vec![2; 10])]);
+        partial
+            .save(url, 25, 10, chunks.iter().chain(more.iter()))
+            .unwrap();
+        assert_eq!(partial.load(url, 25, 10).len(), 3);
+        // No temporary file is left behind
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["file.part", "file.part.json"]);
+        partial.remove();
+        assert!(partial.load(url, 25, 10).is_empty());
+    }
+
+    #[test]
+    fn chunks_of_other_downloads_are_ignored() {
+        let dir = test_dir("other");
+        let partial = PartialDownload::new(dir.join("file.part"));
+        let chunks = BTreeMap::from([(0, vec![1; 10])]);
+        partial
+            .save("https://example.com/a", 10, 10, chunks.iter())
+            .unwrap();
+        assert!(partial.load("https://example.com/b", 10, 10).is_empty());
+        assert!(partial.load("https://example.com/a", 20, 10).is_empty());
+        assert!(partial.load("https://example.com/a", 10, 5).is_empty());
+        // A corrupt state file is ignored rather than failing the download
+        std::fs::write(dir.join("file.part.json"), "{\"url\": ").unwrap();
+        assert!(partial.load("https://example.com/a", 10, 10).is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Bandwidth limiting for the download tasks
+//!
+//! Each limit is a token bucket that is refilled at the configured rate in
+//! bytes per second. Readers take tokens out of the bucket as body data
+//! comes in, and are put to sleep once the bucket runs dry, which in turn
+//! stops us from polling the connection and lets TCP flow control throttle
+//! the sender.
+//!
+//! There is one bucket shared by every connection, and one bucket for every
+//! connection on its own. Either of them can be changed while the download is
+//! running through the control socket served by [serve_control_socket()]
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+/// Mutable state of a [TokenBucket], kept behind a lock
+struct BucketState {
+    /// Rate at which the bucket is refilled, in bytes per second
+    ///
+    /// A rate of 0 means the bucket is unlimited
+    rate: u64,
+    /// Number of bytes that can be read right now
+    ///
+    /// This goes negative when a reader takes more than what is available,
+    /// the reader then has to wait until it is paid back
+    tokens: f64,
+    /// The last time we added tokens to the bucket
+    last_refill: Instant,
+}
+
+/// A single token bucket
+///
+/// At most one second worth of tokens is ever stored, so an idle connection
+/// can't build up a large burst.
+struct TokenBucket {
+    /// State of the bucket, see [BucketState]
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Create a full bucket with the given rate in bytes per second
+    fn new(rate: u64) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `amount` tokens out of the bucket and report how long the caller
+    /// has to wait before reading any more data
+    fn take(&self, amount: usize) -> Duration {
+        self.take_at(amount, Instant::now())
+    }
+
+    /// Take `amount` tokens out of the bucket at `now`, see [TokenBucket::take()]
+    fn take_at(&self, amount: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().expect("poisoned lock");
+        if state.rate == 0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        let rate = state.rate as f64;
+        state.tokens = (state.tokens + elapsed * rate).min(rate);
+        state.last_refill = now;
+        state.tokens -= amount as f64;
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Change the rate of the bucket
+    fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().expect("poisoned lock");
+        state.rate = rate;
+        // Don't let an old debt or surplus carry over into the new rate
+        state.tokens = state.tokens.clamp(0.0, rate as f64);
+        state.last_refill = Instant::now();
+    }
+
+    /// Get the current rate of the bucket
+    fn rate(&self) -> u64 {
+        self.state.lock().expect("poisoned lock").rate
+    }
+}
+
+/// Limits the bandwidth used by all connections together as well as by
+/// each connection separately
+///
+/// Rates are in bytes per second, and a rate of 0 disables that limit
+pub struct RateLimiter {
+    /// Bucket shared by all connections
+    global: TokenBucket,
+    /// One bucket per connection, indexed in the same way as the
+    /// connections of a [Downloader](crate::Downloader)
+    per_connection: Vec<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Create a new [RateLimiter] for `connections` number of connections
+    pub fn new(global_rate: u64, per_connection_rate: u64, connections: usize) -> Self {
+        Self {
+            global: TokenBucket::new(global_rate),
+            per_connection: (0..connections)
+                .map(|_| TokenBucket::new(per_connection_rate))
+                .collect(),
+        }
+    }
+
+    /// Account for `amount` bytes read on the given connection, waiting as
+    /// long as needed to stay under both limits
+    pub async fn consume(&self, connection: usize, amount: usize) {
+        let mut wait = self.global.take(amount);
+        if let Some(bucket) = self.per_connection.get(connection) {
+            wait = wait.max(bucket.take(amount));
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Change the limit for all connections together
+    pub fn set_global_rate(&self, rate: u64) {
+        self.global.set_rate(rate);
+    }
+
+    /// Change the limit for each connection
+    pub fn set_per_connection_rate(&self, rate: u64) {
+        for bucket in self.per_connection.iter() {
+            bucket.set_rate(rate);
+        }
+    }
+
+    /// Number of connections the limiter has a per-connection limit for
+    pub fn connections(&self) -> usize {
+        self.per_connection.len()
+    }
+
+    /// Get the current limits, as (global, per connection)
+    pub fn rates(&self) -> (u64, u64) {
+        let per_connection = self.per_connection.first().map_or(0, |b| b.rate());
+        (self.global.rate(), per_connection)
+    }
+}
+
+/// Interpret a single command sent over the control socket
+///
+/// The supported commands are:
+///
+/// - `global <bytes/s>`: change the total limit
+/// - `per-connection <bytes/s>`: change the limit of each connection
+/// - `status`: report the current limits
+///
+/// A rate of 0 removes the limit
+fn handle_command(limiter: &RateLimiter, line: &str) -> String {
+    let mut parts = line.split_whitespace();
+    let command = parts.next();
+    let rate = parts.next().map(|value| value.parse::<u64>());
+    match (command, rate) {
+        (Some("global"), Some(Ok(rate))) => {
+            info!("Setting global rate limit to {} bytes/s", rate);
+            limiter.set_global_rate(rate);
+            "ok".to_string()
+        }
+        (Some("per-connection"), Some(Ok(rate))) => {
+            info!("Setting per-connection rate limit to {} bytes/s", rate);
+            limiter.set_per_connection_rate(rate);
+            "ok".to_string()
+        }
+        (Some("status"), None) => {
+            let (global, per_connection) = limiter.rates();
+            format!("global {} per-connection {}", global, per_connection)
+        }
+        _ => "error: unknown command".to_string(),
+    }
+}
+
+/// Answer the commands of one client of the control socket
+async fn handle_control_client(stream: UnixStream, limiter: &RateLimiter) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut reply = handle_command(limiter, &line);
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Removes the control socket from the filesystem once dropped
+pub struct ControlSocketGuard {
+    /// Location of the socket
+    path: PathBuf,
+}
+
+impl Drop for ControlSocketGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on a Unix socket for commands to change the rate limits at runtime
+///
+/// Commands are newline terminated, see [handle_command()] for the list. For
+/// example, `echo "global 100000" | nc -U download-manager.sock` limits the
+/// download to 100 kB/s
+///
+/// The socket is served in the background for as long as the returned
+/// [ControlSocketGuard] is alive
+pub fn serve_control_socket(
+    path: &Path,
+    limiter: std::sync::Arc<RateLimiter>,
+) -> std::io::Result<ControlSocketGuard> {
+    let listener = UnixListener::bind(path)?;
+    let guard = ControlSocketGuard {
+        path: path.to_path_buf(),
+    };
+    debug!("Listening for rate limit commands on {}", path.display());
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let limiter = limiter.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_control_client(stream, &limiter).await {
+                            warn!("Control socket client error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Failed to accept control socket client: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_buckets_never_wait() {
+        let bucket = TokenBucket::new(0);
+        assert_eq!(bucket.take(usize::MAX), Duration::ZERO);
+        assert_eq!(bucket.take(1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn empty_buckets_wait_to_be_paid_back() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        assert_eq!(bucket.take_at(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+        // Half a second later the debt is paid back, and nothing more
+        assert_eq!(
+            bucket.take_at(0, start + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.take_at(250, start + Duration::from_millis(500)),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn idle_buckets_hold_at_most_one_second() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(1000, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(1000, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn changing_the_rate_drops_the_debt() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+        bucket.take_at(5000, start);
+        bucket.set_rate(100);
+        assert_eq!(bucket.rate(), 100);
+        let start = bucket.state.lock().unwrap().last_refill;
+        assert_eq!(bucket.take_at(0, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(50, start), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn commands_change_the_limits() {
+        let limiter = RateLimiter::new(0, 0, 3);
+        assert_eq!(handle_command(&limiter, "global 1000"), "ok");
+        assert_eq!(handle_command(&limiter, "per-connection 200"), "ok");
+        assert_eq!(limiter.rates(), (1000, 200));
+        assert_eq!(
+            handle_command(&limiter, "  status  "),
+            "global 1000 per-connection 200"
+        );
+        assert_eq!(handle_command(&limiter, "global 0"), "ok");
+        assert_eq!(limiter.rates(), (0, 200));
+    }
+
+    #[test]
+    fn invalid_commands_are_refused() {
+        let limiter = RateLimiter::new(1000, 200, 3);
+        for line in [
+            "",
+            "global",
+            "global fast",
+            "global -5",
+            "per-connection",
+            "status now",
+            "burst 10",
+        ] {
+            assert_eq!(handle_command(&limiter, line), "error: unknown command");
+        }
+        assert_eq!(limiter.rates(), (1000, 200));
+    }
+}
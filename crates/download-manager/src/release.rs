@@ -0,0 +1,284 @@
+//! Finding the latest Tor Browser release for a platform
+//!
+//! The Tor Project publishes a `downloads.json` file for each release channel,
+//! listing the current version along with the binary and signature URLs of
+//! every platform and locale. We read it to find out what to download, and
+//! expect the SHA256 sums of the release next to the binaries, the way
+//! `dist.torproject.org` lays them out.
+use crate::headers::RequestHeaders;
+use crate::{request_following_redirects, DownloadMgrError, HttpClient};
+use hyper::Method;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use tracing::{debug, info, warn};
+
+/// Locale used by the releases which bundle every language together
+pub const ALL_LOCALES: &str = "ALL";
+/// Platform downloaded by default
+pub const DEFAULT_PLATFORM: &str = "linux64";
+
+/// Release channel of Tor Browser
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Channel {
+    /// The regular releases
+    #[default]
+    Stable,
+    /// Test releases with upcoming changes
+    Alpha,
+}
+
+impl Channel {
+    /// URL of the `downloads.json` file of this channel
+    pub fn downloads_url(&self) -> String {
+        let channel = match self {
+            Channel::Stable => "release",
+            Channel::Alpha => "alpha",
+        };
+        format!(
+            "https://aus1.torproject.org/torbrowser/update_3/{}/downloads.json",
+            channel
+        )
+    }
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" | "release" => Ok(Channel::Stable),
+            "alpha" => Ok(Channel::Alpha),
+            _ => Err(anyhow::anyhow!(
+                "Unknown channel {}, use stable or alpha",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Stable => write!(f, "stable"),
+            Channel::Alpha => write!(f, "alpha"),
+        }
+    }
+}
+
+/// Files listed for one platform and locale in `downloads.json`
+#[derive(Deserialize)]
+struct Artifact {
+    /// URL of the release itself
+    binary: String,
+    /// URL of the detached OpenPGP signature of the release
+    sig: String,
+}
+
+/// Contents of `downloads.json`
+#[derive(Deserialize)]
+struct Downloads {
+    /// Version of the latest release
+    version: String,
+    /// Files of the release, by platform and then by locale
+    downloads: BTreeMap<String, BTreeMap<String, Artifact>>,
+}
+
+/// Everything needed to download and check a single Tor Browser release
+#[derive(Clone, Debug)]
+pub struct Release {
+    /// Version of Tor Browser, like `12.5.2`
+    pub version: String,
+    /// Platform the release is for, like `linux64` or `android-aarch64`
+    pub platform: String,
+    /// Name of the file, as listed in the checksums file
+    pub file_name: String,
+    /// URL of the release
+    pub url: String,
+    /// URL of the OpenPGP signature of the release
+    pub signature_url: String,
+    /// URL of the file listing the SHA256 sums of the whole release
+    pub checksums_url: String,
+}
+
+/// Look up the latest release for `platform` and `locale` in the
+/// `downloads.json` file at `downloads_url`
+///
+/// Platforms are named like in that file, e.g. `linux64`, `win64`, `macos` or
+/// `android-aarch64`. If there is no build for `locale`, the one bundling all
+/// locales is used instead.
+pub async fn latest_release(
+    http: &HttpClient,
+    downloads_url: &str,
+    platform: &str,
+    locale: &str,
+) -> anyhow::Result<Release> {
+    debug!("Requesting {} via Tor...", downloads_url);
+    let (_, resp) =
+        request_following_redirects(downloads_url, Method::GET, http, &RequestHeaders::default())
+            .await?;
+    if !resp.status().is_success() {
+        warn!("Non 2xx Status code: {}", resp.status());
+        return Err(DownloadMgrError::RequestFailed {
+            status: resp.status(),
+        }
+        .into());
+    }
+    let body = hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_err(|e| DownloadMgrError::BodyDownload { error: e })?;
+    let release = parse_release(&body, platform, locale)?;
+    info!(
+        "Latest release for {} is {} ({})",
+        platform, release.version, release.file_name
+    );
+    Ok(release)
+}
+
+/// Find the release for `platform` and `locale` in the contents of a
+/// `downloads.json` file, see [latest_release()]
+fn parse_release(downloads: &[u8], platform: &str, locale: &str) -> anyhow::Result<Release> {
+    let downloads: Downloads = serde_json::from_slice(downloads)?;
+    let Some(locales) = downloads.downloads.get(platform) else {
+        let known: Vec<&str> = downloads.downloads.keys().map(|p| p.as_str()).collect();
+        anyhow::bail!(
+            "No {} release for platform {}, available platforms: {}",
+            downloads.version,
+            platform,
+            known.join(", ")
+        );
+    };
+    let artifact = match locales.get(locale) {
+        Some(artifact) => artifact,
+        None => {
+            debug!(
+                "No {} build of {}, trying {}",
+                locale, platform, ALL_LOCALES
+            );
+            locales.get(ALL_LOCALES).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No {} release for {} in locale {}",
+                    platform,
+                    downloads.version,
+                    locale
+                )
+            })?
+        }
+    };
+
+    let (directory, file_name) = artifact
+        .binary
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow::anyhow!("Invalid release URL {}", artifact.binary))?;
+    Ok(Release {
+        version: downloads.version.clone(),
+        platform: platform.to_string(),
+        file_name: file_name.to_string(),
+        url: artifact.binary.clone(),
+        signature_url: artifact.sig.clone(),
+        checksums_url: format!("{}/sha256sums-signed-build.txt", directory),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trimmed down `downloads.json`, laid out like the real one
+    const DOWNLOADS: &str = r#"{
+  "version": "13.0.1",
+  "tag": "tbb-13.0.1-build1",
+  "downloads": {
+    "linux64": {
+      "ALL": {
+        "binary": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-linux-x86_64-13.0.1.tar.xz",
+        "sig": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-linux-x86_64-13.0.1.tar.xz.asc"
+      }
+    },
+    "win64": {
+      "ALL": {
+        "binary": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-windows-x86_64-portable-13.0.1.exe",
+        "sig": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-windows-x86_64-portable-13.0.1.exe.asc"
+      },
+      "de": {
+        "binary": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-windows-x86_64-portable-13.0.1_de.exe",
+        "sig": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-windows-x86_64-portable-13.0.1_de.exe.asc"
+      }
+    },
+    "android-aarch64": {
+      "fr": {
+        "binary": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-13.0.1-android-aarch64-multi.apk",
+        "sig": "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-13.0.1-android-aarch64-multi.apk.asc"
+      }
+    }
+  }
+}"#;
+
+    #[test]
+    fn releases_are_found_by_platform() {
+        let release = parse_release(DOWNLOADS.as_bytes(), "linux64", ALL_LOCALES).unwrap();
+        assert_eq!(release.version, "13.0.1");
+        assert_eq!(release.platform, "linux64");
+        assert_eq!(release.file_name, "tor-browser-linux-x86_64-13.0.1.tar.xz");
+        assert_eq!(
+            release.url,
+            "https://dist.torproject.org/torbrowser/13.0.1/tor-browser-linux-x86_64-13.0.1.tar.xz"
+        );
+        assert_eq!(release.signature_url, format!("{}.asc", release.url));
+        assert_eq!(
+            release.checksums_url,
+            "https://dist.torproject.org/torbrowser/13.0.1/sha256sums-signed-build.txt"
+        );
+    }
+
+    #[test]
+    fn missing_locales_fall_back_to_all() {
+        let release = parse_release(DOWNLOADS.as_bytes(), "win64", "de").unwrap();
+        assert_eq!(
+            release.file_name,
+            "tor-browser-windows-x86_64-portable-13.0.1_de.exe"
+        );
+        let release = parse_release(DOWNLOADS.as_bytes(), "win64", "ja").unwrap();
+        assert_eq!(
+            release.file_name,
+            "tor-browser-windows-x86_64-portable-13.0.1.exe"
+        );
+        // Without a build for every locale, there is nothing to fall back to
+        let error = parse_release(DOWNLOADS.as_bytes(), "android-aarch64", "ja").unwrap_err();
+        assert!(error.to_string().contains("locale ja"), "{}", error);
+    }
+
+    #[test]
+    fn missing_platforms_are_reported() {
+        let error = parse_release(DOWNLOADS.as_bytes(), "macos", ALL_LOCALES).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("platform macos"), "{}", message);
+        assert!(
+            message.contains("android-aarch64, linux64, win64"),
+            "{}",
+            message
+        );
+        assert!(parse_release(b"{\"version\": ", "linux64", ALL_LOCALES).is_err());
+    }
+
+    #[test]
+    fn channels_have_their_own_downloads() {
+        assert_eq!(Channel::default(), Channel::Stable);
+        assert_eq!("stable".parse::<Channel>().unwrap(), Channel::Stable);
+        assert_eq!("release".parse::<Channel>().unwrap(), Channel::Stable);
+        assert_eq!("alpha".parse::<Channel>().unwrap(), Channel::Alpha);
+        assert!("nightly".parse::<Channel>().is_err());
+        assert_eq!(
+            Channel::Stable.downloads_url(),
+            "https://aus1.torproject.org/torbrowser/update_3/release/downloads.json"
+        );
+        assert_eq!(
+            Channel::Alpha.downloads_url(),
+            "https://aus1.torproject.org/torbrowser/update_3/alpha/downloads.json"
+        );
+        for channel in [Channel::Stable, Channel::Alpha] {
+            assert_eq!(channel.to_string().parse::<Channel>().unwrap(), channel);
+        }
+    }
+}
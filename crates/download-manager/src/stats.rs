@@ -0,0 +1,234 @@
+//! Transfer statistics for each connection of a download
+//!
+//! Every connection goes over its own isolated circuit, so a slow download can
+//! often be blamed on a few bad circuits. [Statistics] keeps track of how
+//! each connection did, along with the relays its circuit went through, so
+//! they can be shown as a table with [Statistics::table()] or saved as JSON
+//! with [Statistics::write_json()].
+use arti_client::DataStream;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What happened on a single connection
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnectionStats {
+    /// Number of body bytes received
+    pub bytes: u64,
+    /// Number of chunks downloaded
+    pub chunks: u64,
+    /// Number of failed attempts, including the timed out ones
+    pub retries: u64,
+    /// Number of attempts which failed because they timed out
+    pub timeouts: u64,
+    /// Time spent on successful requests, in seconds
+    pub busy_secs: f64,
+    /// Throughput of the fastest chunk, in bytes per second
+    pub peak_throughput: f64,
+    /// Relays making up the circuit of the connection, from the guard to the exit
+    pub circuit: Vec<String>,
+}
+
+impl ConnectionStats {
+    /// Throughput while requests were in flight, in bytes per second
+    pub fn average_throughput(&self) -> f64 {
+        if self.busy_secs > 0.0 {
+            self.bytes as f64 / self.busy_secs
+        } else {
+            0.0
+        }
+    }
+
+    /// The exit relay of the circuit, if we know it
+    pub fn exit(&self) -> Option<&str> {
+        self.circuit.last().map(|relay| relay.as_str())
+    }
+}
+
+/// Statistics of every connection of a download, indexed in the same way as
+/// the connections of a [Downloader](crate::Downloader)
+pub struct Statistics {
+    /// One entry per connection
+    connections: Mutex<Vec<ConnectionStats>>,
+}
+
+impl Statistics {
+    /// Create empty statistics for `connections` number of connections
+    pub fn new(connections: usize) -> Self {
+        Self {
+            connections: Mutex::new(vec![ConnectionStats::default(); connections]),
+        }
+    }
+
+    /// Number of connections the statistics were created for
+    pub fn connections(&self) -> usize {
+        self.connections.lock().expect("poisoned lock").len()
+    }
+
+    /// Run `f` on the entry of `connection`, if there is one
+    fn update(&self, connection: usize, f: impl FnOnce(&mut ConnectionStats)) {
+        let mut connections = self.connections.lock().expect("poisoned lock");
+        if let Some(stats) = connections.get_mut(connection) {
+            f(stats);
+        }
+    }
+
+    /// Account for a chunk of `bytes` bytes which took `elapsed` to download
+    pub(crate) fn record_chunk(&self, connection: usize, bytes: usize, elapsed: Duration) {
+        self.update(connection, |stats| {
+            stats.bytes += bytes as u64;
+            stats.chunks += 1;
+            stats.busy_secs += elapsed.as_secs_f64();
+            if !elapsed.is_zero() {
+                let throughput = bytes as f64 / elapsed.as_secs_f64();
+                stats.peak_throughput = stats.peak_throughput.max(throughput);
+            }
+        });
+    }
+
+    /// Account for a failed attempt on `connection`
+    pub(crate) fn record_failure(&self, connection: usize, timed_out: bool) {
+        self.update(connection, |stats| {
+            stats.retries += 1;
+            if timed_out {
+                stats.timeouts += 1;
+            }
+        });
+    }
+
+    /// Remember the relays making up the circuit of `connection`
+    pub(crate) fn set_circuit(&self, connection: usize, circuit: Vec<String>) {
+        self.update(connection, |stats| stats.circuit = circuit);
+    }
+
+    /// Get a copy of the statistics of every connection
+    pub fn snapshot(&self) -> Vec<ConnectionStats> {
+        self.connections.lock().expect("poisoned lock").clone()
+    }
+
+    /// Format the statistics as a table, with one row per connection
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "{:>4} {:>12} {:>7} {:>12} {:>12} {:>8} {:>9}  exit\n",
+            "conn", "bytes", "chunks", "avg B/s", "peak B/s", "retries", "timeouts"
+        );
+        for (connection, stats) in self.snapshot().iter().enumerate() {
+            let _ = writeln!(
+                table,
+                "{:>4} {:>12} {:>7} {:>12.0} {:>12.0} {:>8} {:>9}  {}",
+                connection,
+                stats.bytes,
+                stats.chunks,
+                stats.average_throughput(),
+                stats.peak_throughput,
+                stats.retries,
+                stats.timeouts,
+                stats.exit().unwrap_or("unknown")
+            );
+        }
+        table
+    }
+
+    /// Save the statistics of every connection as a JSON array
+    pub fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(&self.snapshot())?)?;
+        Ok(())
+    }
+}
+
+/// Whether `error` was caused by something timing out
+pub(crate) fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<hyper::Error>() {
+            e.is_timeout()
+        } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            e.kind() == std::io::ErrorKind::TimedOut
+        } else {
+            false
+        }
+    })
+}
+
+/// Find the relays of the circuit `stream` was opened on
+///
+/// Relays are described with their identities and addresses, from the guard
+/// to the exit.
+pub(crate) fn circuit_path(stream: &DataStream) -> Vec<String> {
+    let path = stream.circuit().path_ref();
+    path.iter().map(|hop| hop.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_are_counted_per_connection() {
+        let stats = Statistics::new(2);
+        assert_eq!(stats.connections(), 2);
+        stats.record_chunk(0, 1000, Duration::from_secs(2));
+        stats.record_chunk(0, 3000, Duration::from_secs(1));
+        stats.record_chunk(1, 500, Duration::ZERO);
+        stats.record_failure(1, true);
+        stats.record_failure(1, false);
+        // Connections we don't know about are ignored
+        stats.record_chunk(2, 1000, Duration::from_secs(1));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        let first = &snapshot[0];
+        assert_eq!((first.bytes, first.chunks), (4000, 2));
+        assert_eq!((first.retries, first.timeouts), (0, 0));
+        assert_eq!(first.busy_secs, 3.0);
+        assert_eq!(first.peak_throughput, 3000.0);
+        assert_eq!(first.average_throughput(), 4000.0 / 3.0);
+        let second = &snapshot[1];
+        assert_eq!((second.bytes, second.chunks), (500, 1));
+        assert_eq!((second.retries, second.timeouts), (2, 1));
+        // Instant chunks don't make up a throughput
+        assert_eq!(second.peak_throughput, 0.0);
+        assert_eq!(second.average_throughput(), 0.0);
+    }
+
+    #[test]
+    fn circuits_are_recorded_per_connection() {
+        let stats = Statistics::new(2);
+        assert_eq!(stats.snapshot()[0].exit(), None);
+        let circuit = vec![
+            "guard".to_string(),
+            "middle".to_string(),
+            "exit".to_string(),
+        ];
+        stats.set_circuit(1, circuit.clone());
+        let snapshot = stats.snapshot();
+        assert!(snapshot[0].circuit.is_empty());
+        assert_eq!(snapshot[1].circuit, circuit);
+        assert_eq!(snapshot[1].exit(), Some("exit"));
+        // A new stream on the same connection replaces its circuit
+        stats.set_circuit(1, vec!["other".to_string()]);
+        assert_eq!(stats.snapshot()[1].exit(), Some("other"));
+        let table = stats.table();
+        let rows: Vec<_> = table.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[1].ends_with("unknown"), "{}", rows[1]);
+        assert!(rows[2].ends_with("other"), "{}", rows[2]);
+    }
+
+    #[test]
+    fn statistics_are_saved_as_json() {
+        let stats = Statistics::new(1);
+        stats.record_chunk(0, 10, Duration::from_secs(1));
+        stats.set_circuit(0, vec!["exit".to_string()]);
+        let path = std::env::temp_dir().join(format!(
+            "download-manager-stats-{}.json",
+            std::process::id()
+        ));
+        stats.write_json(&path).unwrap();
+        let saved: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved[0]["bytes"], 10);
+        assert_eq!(saved[0]["circuit"][0], "exit");
+    }
+}
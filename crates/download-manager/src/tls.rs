@@ -0,0 +1,142 @@
+//! TLS settings for the connections made over Tor
+//!
+//! By default the TLS library of the system is used through `native-tls`,
+//! along with the roots it trusts. Building with
+//! `--no-default-features --features rustls` switches to rustls and the
+//! Mozilla roots from `webpki-roots` instead, so OpenSSL isn't needed.
+//!
+//! On top of the default roots, more CA certificates can be trusted, for
+//! example to test against a local server with a self-signed certificate.
+//! The public key of a host can also be pinned, in which case its
+//! certificate is only accepted if it is valid *and* its key matches one of
+//! the pins. Pinning needs the rustls backend, as native-tls gives us no way
+//! to look at the certificate of the server. The pins themselves are checked
+//! by the [tls_pinning] crate, which `dns-resolver` uses too.
+//!
+//! HTTP/2 needs the rustls backend too: whether native-tls offers and reports
+//! ALPN depends on the system library it was built against.
+use crate::connector::{ALPN_H2, ALPN_HTTP1};
+use anyhow::bail;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tls_api::{TlsConnector as TlsConnectorTrait, TlsConnectorBuilder};
+use tls_pinning::Pins;
+use tracing::debug;
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the `native-tls` or the `rustls` feature has to be enabled");
+
+/// The TLS backend picked at build time
+#[cfg(feature = "rustls")]
+pub type TlsConnector = tls_api_rustls::TlsConnector;
+/// The TLS backend picked at build time
+#[cfg(not(feature = "rustls"))]
+pub type TlsConnector = tls_api_native_tls::TlsConnector;
+
+/// Extra CA certificates, pinned keys and protocols used when connecting to
+/// servers
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// DER encoded certificates trusted on top of the default roots
+    ca_certificates: Vec<Vec<u8>>,
+    /// Accepted public keys of the pinned hosts
+    pins: Pins,
+    /// Whether to offer HTTP/2 through ALPN
+    http2: bool,
+}
+
+impl TlsConfig {
+    /// Create a config which only uses the default roots
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust every certificate found in the PEM file at `path`
+    pub fn add_ca_certificates(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let certificates = rustls_pemfile::certs(&mut reader)?;
+        if certificates.is_empty() {
+            bail!("No certificate found in {}", path.display());
+        }
+        debug!(
+            "Trusting {} certificates from {}",
+            certificates.len(),
+            path.display()
+        );
+        self.ca_certificates.extend(certificates);
+        Ok(())
+    }
+
+    /// Only accept certificates of `host` whose public key matches `pin`
+    ///
+    /// See [Pins::add()] for what `pin` looks like and how to compute it
+    pub fn pin(&mut self, host: &str, pin: &str) -> anyhow::Result<()> {
+        self.pins.add(host, pin)
+    }
+
+    /// Offer HTTP/2 to servers through ALPN, falling back to HTTP/1.1 for
+    /// servers which don't pick it
+    ///
+    /// Fails when enabling it without the rustls backend
+    pub fn set_http2(&mut self, enabled: bool) -> anyhow::Result<()> {
+        if enabled && !cfg!(feature = "rustls") {
+            bail!("HTTP/2 needs the rustls backend, build with `--features rustls`");
+        }
+        self.http2 = enabled;
+        Ok(())
+    }
+
+    /// Whether HTTP/2 is offered to servers
+    pub fn http2(&self) -> bool {
+        self.http2
+    }
+
+    /// Set the ALPN protocols we offer on `builder`
+    fn set_alpn<B: TlsConnectorBuilder>(&self, builder: &mut B) -> anyhow::Result<()> {
+        if self.http2 {
+            builder.set_alpn_protocols(&[ALPN_H2, ALPN_HTTP1])?;
+        }
+        Ok(())
+    }
+
+    /// Create a connector of the configured backend using these settings
+    #[cfg(not(feature = "rustls"))]
+    pub fn build_connector(&self) -> anyhow::Result<TlsConnector> {
+        if !self.pins.is_empty() {
+            bail!("Certificate pinning needs the rustls backend, build with `--features rustls`");
+        }
+        let mut builder = TlsConnector::builder()?;
+        for certificate in self.ca_certificates.iter() {
+            builder.add_root_certificate(certificate)?;
+        }
+        self.set_alpn(&mut builder)?;
+        builder.build()
+    }
+
+    /// Create a connector of the configured backend using these settings
+    #[cfg(feature = "rustls")]
+    pub fn build_connector(&self) -> anyhow::Result<TlsConnector> {
+        let verifier = tls_pinning::PinningVerifier::new(&self.ca_certificates, self.pins.clone())?;
+        let mut builder = TlsConnector::builder()?;
+        builder
+            .underlying_mut()
+            .dangerous()
+            .set_certificate_verifier(std::sync::Arc::new(verifier));
+        self.set_alpn(&mut builder)?;
+        builder.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http2_needs_the_rustls_backend() {
+        let mut tls = TlsConfig::new();
+        assert!(tls.set_http2(false).is_ok());
+        assert_eq!(tls.set_http2(true).is_ok(), cfg!(feature = "rustls"));
+        assert_eq!(tls.http2(), cfg!(feature = "rustls"));
+    }
+}
@@ -0,0 +1,363 @@
+#![warn(clippy::missing_docs_in_private_items)]
+//! # tls-pinning
+//! Public key pinning for the TLS connections of the `download-manager` and
+//! `dns-resolver` programs
+//!
+//! A pin is the SHA256 hash of the SubjectPublicKeyInfo of a certificate.
+//! [Pins] holds the pins of each server, and checks certificates against
+//! them. Only the key is looked at, so a pin still holds after a certificate
+//! is renewed with the same key.
+//!
+//! With the `rustls` feature, [PinningVerifier] checks certificates the way
+//! rustls normally does, and then against the pins. Pins don't replace the
+//! usual checks: a certificate has to be valid *and* match a pin.
+use anyhow::{anyhow, bail};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// SHA256 hash of a DER encoded SubjectPublicKeyInfo
+pub type SpkiHash = [u8; 32];
+
+/// Accepted public keys, by lowercase name of the server
+#[derive(Clone, Debug, Default)]
+pub struct Pins {
+    /// Hashes of the accepted keys of each server
+    pins: HashMap<String, Vec<SpkiHash>>,
+}
+
+impl Pins {
+    /// Create an empty set of pins, which accepts any certificate
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accept certificates of the server `name` whose public key
+    /// matches `pin`
+    ///
+    /// `pin` is the base64 encoded SHA256 hash of the SubjectPublicKeyInfo,
+    /// optionally prefixed with `sha256/` like in curl's `--pinnedpubkey`.
+    /// It can be computed from a certificate with:
+    ///
+    /// `openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    ///
+    /// Several pins can be added for the same server, e.g. to roll over to a
+    /// new key, any of them is then accepted
+    pub fn add(&mut self, name: &str, pin: &str) -> anyhow::Result<()> {
+        let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+        let hash: SpkiHash = base64::engine::general_purpose::STANDARD
+            .decode(encoded)?
+            .try_into()
+            .map_err(|_| anyhow!("Pin for {} isn't a SHA256 hash", name))?;
+        self.pins
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(hash);
+        Ok(())
+    }
+
+    /// Whether no server is pinned
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    /// Check the DER encoded `certificate` of the server `name` against its
+    /// pins
+    ///
+    /// Servers without any pin accept every certificate
+    pub fn check(&self, name: &str, certificate: &[u8]) -> anyhow::Result<()> {
+        let name = name.to_ascii_lowercase();
+        let Some(pins) = self.pins.get(&name) else {
+            return Ok(());
+        };
+        let spki = spki_from_certificate(certificate)
+            .ok_or_else(|| anyhow!("Unable to find the public key of the certificate"))?;
+        let hash: SpkiHash = Sha256::digest(spki).into();
+        if !pins.contains(&hash) {
+            warn!("Certificate of {} doesn't match any of its pins", name);
+            bail!("Certificate of {} doesn't match any of its pins", name);
+        }
+        Ok(())
+    }
+}
+
+/// A single DER element, made up of its tag, the whole encoded element,
+/// its contents and whatever follows it
+type DerElement<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+/// Split the first DER element off `der`
+///
+/// Only single byte tags are supported, which is all a certificate uses
+fn der_element(der: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = der.len() - rest.len();
+    Some((tag, &der[..header + len], &rest[..len], &rest[len..]))
+}
+
+/// Find the encoded SubjectPublicKeyInfo in a DER encoded certificate
+pub fn spki_from_certificate(der: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, _, certificate, _) = der_element(der)?;
+    let (_, _, mut tbs, _) = der_element(certificate)?;
+    // The version is optional, and tagged [0]
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.3;
+    }
+    // Skip the serial number, signature, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.3;
+    }
+    let (tag, spki, _, _) = der_element(tbs)?;
+    (tag == 0x30).then_some(spki)
+}
+
+#[cfg(feature = "rustls")]
+pub use verifier::PinningVerifier;
+
+/// Certificate verification for rustls, with support for pins
+#[cfg(feature = "rustls")]
+mod verifier {
+    use super::Pins;
+    use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+    use rustls::{Certificate, OwnedTrustAnchor, RootCertStore, ServerName};
+    use std::time::SystemTime;
+
+    /// Verifies certificates against the roots like rustls normally does,
+    /// then checks the key of pinned servers
+    pub struct PinningVerifier {
+        /// The usual rustls verifier
+        inner: WebPkiVerifier,
+        /// Accepted public keys of the pinned servers
+        pins: Pins,
+    }
+
+    impl PinningVerifier {
+        /// Trust the `webpki-roots` and the given DER encoded certificates
+        pub fn new(ca_certificates: &[Vec<u8>], pins: Pins) -> anyhow::Result<Self> {
+            let mut roots = RootCertStore::empty();
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            for certificate in ca_certificates {
+                roots
+                    .add(&Certificate(certificate.clone()))
+                    .map_err(|e| anyhow::anyhow!("Invalid CA certificate: {:?}", e))?;
+            }
+            Ok(Self {
+                inner: WebPkiVerifier::new(roots, None),
+                pins,
+            })
+        }
+    }
+
+    impl ServerCertVerifier for PinningVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            intermediates: &[Certificate],
+            server_name: &ServerName,
+            scts: &mut dyn Iterator<Item = &[u8]>,
+            ocsp_response: &[u8],
+            now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let verified = self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+            let name = match server_name {
+                ServerName::DnsName(name) => name.as_ref().to_string(),
+                ServerName::IpAddress(ip) => ip.to_string(),
+                _ => return Ok(verified),
+            };
+            self.pins
+                .check(&name, &end_entity.0)
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            Ok(verified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// X.509 v1 certificate for `v1.test`, made with
+    /// `openssl req -x509 -x509v1 -new -key key.pem -subj /CN=v1.test`.
+    /// v1 certificates have no version field, so the SubjectPublicKeyInfo
+    /// comes one element earlier than in newer certificates
+    const V1_CERTIFICATE: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIICrTCCAZUCFGesJSGbf92Hk1mkMV72G17T1pn2MA0GCSqGSIb3DQEBCwUAMBIx\n\
+EDAOBgNVBAMMB3YxLnRlc3QwIBcNMjYxMDE4MTM1NjA5WhgPMjEyNjA5MjQxMzU2\n\
+MDlaMBIxEDAOBgNVBAMMB3YxLnRlc3QwggEiMA0GCSqGSIb3DQEBAQUAA4IBDwAw\n\
+ggEKAoIBAQCb0jYx67biGAjbNRf+rYS2XVaoKY0CUMAdhe6Ix2FqUFcEDml2w3MO\n\
+H2zcA0Aw0Gpt9ipNEBAn13xV1IrZccYfOIXdOpbTBwULXFnQqRzjut+ISNMgE80V\n\
+vAP0TL5f1FwUAwFzATNEo0eS14oAKlT9Oa1TZf4TtQ4hF1TnP8pY9ump1H8/ja9F\n\
+bW9OwyuDY9xIxtHlXX7EfuOqHPEirDT++ctJfBAm7KSnRA3yGMdT5v73xFS6RXza\n\
+6hyH5XipQlKLQRbzphzaN+FVUkEQgXEoq1lzn4AYMfv8xlLh9RM95HvaCRbF4TZW\n\
+zGZam305KZXgSHJ70ca81vD4CBAtgZVTAgMBAAEwDQYJKoZIhvcNAQELBQADggEB\n\
+AH9GAmuXaumDdZSsab7dGPafgrdQ+1qPS/20JWORL4dXy7Uj0tDousH7KgXOBrBb\n\
+P4hjvnfkBzQU/6F0GHp9Z0Ob/aUIzz+jLZkDK1kaBgMBoi4QSQGZ0JqGpzOUcjL+\n\
+NVRs3781NG+bHla+Xujlj9X7VcjKJcNbKvRyP3JBxaWz6QqqXX7cnXEYo8TCgtaA\n\
+2g69lUd6LLtQ68pBS09vQ3ypT9vBgdw+29HvGr4TQ3IQ/ZZ+uuNOo91SWPhf9FZP\n\
+pVqMIZ2CKlSQFwi6wJSOy5DGILgJlTb7eZ4Kmr50lpvKTXBOuUDyyJKxtUiVvLJP\n\
+1qE8LPykBTVtG3PgShOQqm8=\n\
+-----END CERTIFICATE-----";
+
+    /// Pin of [V1_CERTIFICATE], from the openssl command documented in
+    /// [Pins::add()]
+    const V1_PIN: &str = "+ZIDConLTOBU/40X6CeYtkK9vd9FJD3xOHYjxqDAPIE=";
+
+    /// Hash of the SubjectPublicKeyInfo found in `certificate`
+    fn hash_of(certificate: &[u8]) -> Option<SpkiHash> {
+        spki_from_certificate(certificate).map(|spki| Sha256::digest(spki).into())
+    }
+
+    /// A self-signed certificate for `v3.test`, DER encoded, along with its pin
+    fn v3_certificate() -> (Vec<u8>, String) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["v3.test".into()]).unwrap();
+        let spki = certificate.get_key_pair().public_key_der();
+        (
+            certificate.serialize_der().unwrap(),
+            base64::engine::general_purpose::STANDARD.encode(Sha256::digest(spki)),
+        )
+    }
+
+    #[test]
+    fn public_keys_of_v1_certificates_are_found() {
+        let certificate = rustls_pemfile::certs(&mut V1_CERTIFICATE.as_bytes())
+            .unwrap()
+            .remove(0);
+        let mut pins = Pins::new();
+        pins.add("v1.test", V1_PIN).unwrap();
+        assert_eq!(hash_of(&certificate), Some(pins.pins["v1.test"][0]));
+    }
+
+    #[test]
+    fn public_keys_of_v3_certificates_are_found() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["v3.test".into()]).unwrap();
+        let der = certificate.serialize_der().unwrap();
+        let expected: SpkiHash = Sha256::digest(certificate.get_key_pair().public_key_der()).into();
+        assert_eq!(hash_of(&der), Some(expected));
+    }
+
+    #[test]
+    fn truncated_certificates_are_refused() {
+        let (certificate, _) = v3_certificate();
+        for len in 0..certificate.len() {
+            assert_eq!(spki_from_certificate(&certificate[..len]), None);
+        }
+    }
+
+    /// DER encode an element with the given tag and short `contents`
+    fn element(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag, contents.len() as u8];
+        der.extend(contents);
+        der
+    }
+
+    #[test]
+    fn public_keys_must_be_sequences() {
+        let certificate = |key: Vec<u8>| {
+            let mut tbs = element(0xa0, &element(0x02, &[2]));
+            tbs.extend(element(0x02, &[1]));
+            for _ in 0..4 {
+                tbs.extend(element(0x30, &[]));
+            }
+            tbs.extend(key);
+            element(0x30, &element(0x30, &tbs))
+        };
+        let key = element(0x30, &element(0x03, &[0, 1, 2]));
+        assert_eq!(
+            spki_from_certificate(&certificate(key.clone())),
+            Some(&key[..])
+        );
+        let not_a_key = element(0x04, &[0, 1, 2]);
+        assert_eq!(spki_from_certificate(&certificate(not_a_key)), None);
+    }
+
+    #[test]
+    fn long_form_lengths_are_decoded() {
+        // Short form
+        assert_eq!(
+            der_element(&[0x04, 0x01, 0xaa, 0xbb]),
+            Some((0x04, &[0x04, 0x01, 0xaa][..], &[0xaa][..], &[0xbb][..]))
+        );
+        // Long form with one and two length bytes
+        assert_eq!(
+            der_element(&[0x04, 0x81, 0x01, 0xaa]),
+            Some((0x04, &[0x04, 0x81, 0x01, 0xaa][..], &[0xaa][..], &[][..]))
+        );
+        let mut long = vec![0x30, 0x82, 0x01, 0x00];
+        long.extend([0x55; 0x100]);
+        let (tag, element, contents, rest) = der_element(&long).unwrap();
+        assert_eq!(
+            (tag, element.len(), contents.len(), rest.len()),
+            (0x30, 0x104, 0x100, 0)
+        );
+        // Lengths past the end of the input
+        assert_eq!(der_element(&long[..long.len() - 1]), None);
+        assert_eq!(der_element(&[0x04, 0x81]), None);
+        assert_eq!(der_element(&[0x04, 0x82, 0x01]), None);
+        // Indefinite lengths aren't DER, and lengths can't overflow
+        assert_eq!(der_element(&[0x30, 0x80, 0x00, 0x00]), None);
+        let mut huge = vec![0x04, 0x80 | (std::mem::size_of::<usize>() as u8 + 1)];
+        huge.extend(vec![0xff; std::mem::size_of::<usize>() + 1]);
+        assert_eq!(der_element(&huge), None);
+        assert_eq!(
+            der_element(&[0x04, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+    }
+
+    #[test]
+    fn pins_have_to_be_sha256_hashes() {
+        let mut pins = Pins::new();
+        assert!(pins.add("v1.test", &format!("sha256/{}", V1_PIN)).is_ok());
+        assert!(pins.add("v1.test", "not base64!").is_err());
+        assert!(pins.add("v1.test", "AAAA").is_err());
+        assert_eq!(pins.pins["v1.test"].len(), 1);
+    }
+
+    #[test]
+    fn certificates_are_checked_against_the_pins_of_their_server() {
+        let (certificate, pin) = v3_certificate();
+        let (_, other_pin) = v3_certificate();
+        let mut pins = Pins::new();
+        assert!(pins.is_empty());
+        pins.add("V3.test", &pin).unwrap();
+        pins.add("other.test", &other_pin).unwrap();
+        // Names are matched whatever their case
+        assert!(pins.check("v3.TEST", &certificate).is_ok());
+        assert!(pins.check("other.test", &certificate).is_err());
+        // Servers without pins take any certificate, even a broken one
+        assert!(pins.check("unpinned.test", &certificate).is_ok());
+        assert!(pins.check("unpinned.test", &certificate[..10]).is_ok());
+        assert!(pins.check("v3.test", &certificate[..10]).is_err());
+    }
+}